@@ -1,5 +1,5 @@
 pub fn navbar() -> String {
-    let navbar = String::from("
+    String::from("
         <script type=\"text/javascript\" src=\"/static/js/navbar.js\"></script>
         <script src=\"https://kit.fontawesome.com/7178fd9426.js\" crossorigin=\"anonymous\"></script>
         <link type=\"text/css\" rel=\"stylesheet\" href=\"/static/css/navbar.css\">
@@ -8,7 +8,5 @@ pub fn navbar() -> String {
         <script type=\"text/javascript\">
             navbar_handler()
         </script>
-    ");
-
-    navbar
+    ")
 }
@@ -1,9 +1,12 @@
-use actix_web::{App, HttpServer};
+use actix_web::{web, App, HttpServer};
 use actix_files::Files;
 
 mod components;
+mod middleware;
 mod pages;
 
+use middleware::rate_limit::RateLimiter;
+
 use pages::home;
 use pages::competitive_programming_classes;
 use pages::steganography;
@@ -15,8 +18,21 @@ use pages::lucasodon;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
+    let lucasodon_db = web::Data::new(lucasodon::init_db().expect("failed to open the lucasodon database"));
+    who_chat::init_secret().expect("failed to set up the Who Chat secret");
+
+    let limiter = web::Data::new(RateLimiter::new());
+    let chat_events = web::Data::new(who_chat::ChatEvents::new());
+
+    actix_web::rt::spawn(who_chat::run_sweeper(chat_events.clone()));
+    actix_web::rt::spawn(lucasodon::run_backups(lucasodon_db.clone()));
+    actix_web::rt::spawn(lucasodon::run_purge(lucasodon_db.clone()));
+
+    HttpServer::new(move || {
         App::new()
+            .app_data(limiter.clone())
+            .app_data(chat_events.clone())
+            .app_data(lucasodon_db.clone())
             .service(Files::new("/static", "static"))
             .service(home::render)
             .service(competitive_programming_classes::render)
@@ -25,6 +41,21 @@ async fn main() -> std::io::Result<()> {
             .service(who_chat::get_chat)
             .service(who_chat::post_chat)
             .service(who_chat::delete_chat)
+            .service(who_chat::create_chat)
+            .service(who_chat::rotate_chat)
+            .service(who_chat::attach_chat)
+            .service(who_chat::get_attachment)
+            .service(who_chat::export_chat)
+            .service(who_chat::import_chat)
+            .service(who_chat::stream_chat)
+            .service(who_chat::admin_stats)
+            .service(who_chat::salt_e2e_chat)
+            .service(who_chat::create_e2e_chat)
+            .service(who_chat::get_e2e_chat)
+            .service(who_chat::post_e2e_chat)
+            .service(who_chat::delete_e2e_chat)
+            .service(who_chat::export_e2e_chat)
+            .service(who_chat::stream_e2e_chat)
             .service(who_chat::render)
             .service(predict_codeforces_rating::render)
             .service(predict_codeforces_rating::predict_rating)
@@ -49,6 +80,25 @@ async fn main() -> std::io::Result<()> {
             .service(lucasodon::despesa_create)
             .service(lucasodon::despesa_update)
             .service(lucasodon::despesa_delete)
+            .service(lucasodon::importar_preview)
+            .service(lucasodon::importar_confirmar)
+            .service(lucasodon::carne_leao_get)
+            .service(lucasodon::irpf_tabelas)
+            .service(lucasodon::irpf_tabela_save)
+            .service(lucasodon::exportar_plantoes)
+            .service(lucasodon::exportar_despesas)
+            .service(lucasodon::lixeira_list)
+            .service(lucasodon::lixeira_restaurar)
+            .service(lucasodon::auditoria_list)
+            .service(lucasodon::auditoria_restaurar)
+            .service(lucasodon::backup_download)
+            .service(lucasodon::backup_restore)
+            .service(lucasodon::resumo_get)
+            .service(lucasodon::projecao_get)
+            .service(lucasodon::conta)
+            .service(lucasodon::conta_senha)
+            .service(lucasodon::conta_encerrar_sessoes)
+            .service(lucasodon::usuario_create)
     })
     .bind(("127.0.0.1", 8080))?
     .run()
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+
+// Failures allowed before any delay kicks in; each one after that doubles
+// the lockout, up to MAX_DELAY. Keys with no failure for FORGET_AFTER are
+// dropped.
+const FREE_ATTEMPTS: u32 = 5;
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+const PRUNE_THRESHOLD: usize = 4096;
+// Comma-separated IPs of the reverse proxies allowed to set X-Forwarded-For.
+const TRUSTED_PROXIES_VAR: &str = "TRUSTED_PROXIES";
+const TRUSTED_PROXIES_DEFAULT: &str = "127.0.0.1,::1";
+
+
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+}
+
+
+struct Window {
+    count: u32,
+    resets_at: Instant,
+}
+
+
+// Counts failed attempts per key and locks a key out with exponential
+// backoff. Keys are free-form: the middleware uses one per scope and client
+// IP, and handlers may add their own per target resource (a chat, an account).
+// Quotas on actions that are not failures, like creating rooms, are counted
+// apart in fixed windows.
+#[derive(Default)]
+pub struct RateLimiter {
+    entries: Mutex<HashMap<String, Failures>>,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Ok when the key may try again, otherwise how long it has to wait.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    pub fn record_failure(&self, key: &str) {
+        self.record_failure_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        match entries.get(key).and_then(|f| f.blocked_until) {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    fn record_failure_at(&self, key: &str, now: Instant) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        if entries.len() > PRUNE_THRESHOLD {
+            entries.retain(|_k, f| now.duration_since(f.last_failure) < FORGET_AFTER);
+        }
+
+        let failures = entries.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+            blocked_until: None,
+        });
+        if now.duration_since(failures.last_failure) >= FORGET_AFTER {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last_failure = now;
+
+        if failures.count > FREE_ATTEMPTS {
+            let exponent = (failures.count - FREE_ATTEMPTS - 1).min(31);
+            let delay = BASE_DELAY.saturating_mul(1 << exponent).min(MAX_DELAY);
+            failures.blocked_until = Some(now + delay);
+        }
+    }
+
+    pub fn record_success(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.remove(key);
+    }
+
+    // Uses one of the `limit` actions `key` may take per `window`. Once they
+    // are spent, how long until the window starts over; refused actions are
+    // not counted.
+    pub fn take(&self, key: &str, limit: u32, window: Duration) -> Result<(), Duration> {
+        self.take_at(key, limit, window, Instant::now())
+    }
+
+    fn take_at(&self, key: &str, limit: u32, window: Duration, now: Instant) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_k, w| w.resets_at > now);
+        }
+
+        let current = windows.entry(key.to_string()).or_insert(Window { count: 0, resets_at: now + window });
+        if current.resets_at <= now {
+            *current = Window { count: 0, resets_at: now + window };
+        }
+        if current.count >= limit {
+            return Err(current.resets_at - now)
+        }
+        current.count += 1;
+        Ok(())
+    }
+}
+
+
+// Lets a handler flag a failed attempt that does not answer 401/403, such as
+// a login form that redirects back on a wrong password.
+#[derive(Clone, Copy)]
+pub struct AuthFailure;
+
+
+pub fn mark_failure(mut response: HttpResponse) -> HttpResponse {
+    response.extensions_mut().insert(AuthFailure);
+    response
+}
+
+
+fn trusted_proxies() -> &'static [IpAddr] {
+    static PROXIES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+    PROXIES.get_or_init(|| {
+        env::var(TRUSTED_PROXIES_VAR)
+            .unwrap_or_else(|_| TRUSTED_PROXIES_DEFAULT.to_string())
+            .split(',')
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect()
+    })
+}
+
+
+// The address of the client behind any trusted proxies. X-Forwarded-For is
+// only believed when the connection itself comes from a trusted proxy, and
+// then it is read from the right: entries left of the last untrusted hop
+// are whatever the client chose to send.
+fn forwarded_client(peer: Option<IpAddr>, forwarded_for: Option<&str>, trusted: &[IpAddr]) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted.contains(&peer) {
+        return Some(peer)
+    }
+
+    let mut client = peer;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+        if !trusted.contains(&client) {
+            break
+        }
+    }
+    Some(client)
+}
+
+
+pub fn client_ip(req: &HttpRequest) -> String {
+    let forwarded_for = req.headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    match forwarded_client(peer, Some(&forwarded_for), trusted_proxies()) {
+        Some(ip) => ip.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    let seconds = retry_after.as_secs().max(1);
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", seconds.to_string()))
+        .content_type("text/plain")
+        .body(format!("Error: too many attempts, retry in {} seconds", seconds))
+}
+
+
+// Middleware that refuses requests from a client IP locked out of `scope` and
+// counts every 401, 403 or marked response as a failure for it. The limiter
+// is taken from the app data; without one the middleware lets everything
+// through.
+//
+// The IP is the one client_ip finds, so a client cannot pick its own key by
+// sending a made-up X-Forwarded-For.
+pub struct RateLimit {
+    scope: &'static str,
+}
+
+
+impl RateLimit {
+    pub fn new(scope: &'static str) -> Self {
+        RateLimit { scope }
+    }
+}
+
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service: Rc::new(service), scope: self.scope }))
+    }
+}
+
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    scope: &'static str,
+}
+
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+        let key = format!("{}:ip:{}", self.scope, client_ip(req.request()));
+
+        Box::pin(async move {
+            let limiter = match limiter {
+                Some(l) => l,
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            if let Err(retry_after) = limiter.check(&key) {
+                return Ok(req.into_response(too_many_requests(retry_after)).map_into_right_body());
+            }
+
+            let res = service.call(req).await?;
+            let status = res.status().as_u16();
+            if status == 401 || status == 403 || res.response().extensions().contains::<AuthFailure>() {
+                limiter.record_failure(&key);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(limiter: &RateLimiter, times: u32, now: Instant) {
+        for _ in 0..times {
+            limiter.record_failure_at("key", now);
+        }
+    }
+
+    #[test]
+    fn backoff_starts_after_the_free_attempts_and_doubles() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        fail(&limiter, FREE_ATTEMPTS, now);
+        assert!(limiter.check_at("key", now).is_ok());
+
+        fail(&limiter, 1, now);
+        assert_eq!(limiter.check_at("key", now), Err(BASE_DELAY));
+        assert!(limiter.check_at("key", now + BASE_DELAY).is_ok());
+        assert!(limiter.check_at("other", now).is_ok());
+
+        fail(&limiter, 1, now);
+        assert_eq!(limiter.check_at("key", now), Err(BASE_DELAY * 2));
+
+        limiter.record_success("key");
+        assert!(limiter.check_at("key", now).is_ok());
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        fail(&limiter, FREE_ATTEMPTS + 100, now);
+        assert_eq!(limiter.check_at("key", now), Err(MAX_DELAY));
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_a_quiet_day() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        fail(&limiter, FREE_ATTEMPTS + 3, now);
+        let later = now + FORGET_AFTER;
+        assert!(limiter.check_at("key", later).is_ok());
+
+        fail(&limiter, FREE_ATTEMPTS, later);
+        assert!(limiter.check_at("key", later).is_ok());
+        fail(&limiter, 1, later);
+        assert_eq!(limiter.check_at("key", later), Err(BASE_DELAY));
+    }
+
+    #[test]
+    fn quotas_reset_with_their_window() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        let hour = Duration::from_secs(60 * 60);
+
+        for _ in 0..3 {
+            assert!(limiter.take_at("key", 3, hour, now).is_ok());
+        }
+        assert_eq!(limiter.take_at("key", 3, hour, now + hour / 2), Err(hour / 2));
+        assert!(limiter.take_at("other", 3, hour, now).is_ok());
+        // Quotas and failures are counted apart.
+        assert!(limiter.check_at("key", now).is_ok());
+
+        assert!(limiter.take_at("key", 3, hour, now + hour).is_ok());
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_a_proxy() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let trusted = [ip("127.0.0.1")];
+        let client = |peer: &str, header: Option<&str>| forwarded_client(Some(ip(peer)), header, &trusted);
+
+        assert_eq!(client("203.0.113.7", Some("10.0.0.1")), Some(ip("203.0.113.7")));
+        assert_eq!(client("127.0.0.1", None), Some(ip("127.0.0.1")));
+        assert_eq!(client("127.0.0.1", Some("203.0.113.7")), Some(ip("203.0.113.7")));
+        // A spoofed leftmost entry is ignored in favour of the one the proxy added.
+        assert_eq!(client("127.0.0.1", Some("10.0.0.1, 203.0.113.7")), Some(ip("203.0.113.7")));
+        assert_eq!(client("127.0.0.1", Some("203.0.113.7, 127.0.0.1")), Some(ip("203.0.113.7")));
+        assert_eq!(client("127.0.0.1", Some("garbage, 203.0.113.7")), Some(ip("203.0.113.7")));
+        assert_eq!(forwarded_client(None, Some("203.0.113.7"), &trusted), None);
+    }
+}
@@ -3,8 +3,8 @@ use actix_web::{get, HttpResponse, Result};
 
 use crate::components::navbar::navbar;
 
-fn get_skill_color(skill: &String) -> String {
-    match skill.as_str() {
+fn get_skill_color(skill: &str) -> String {
+    match skill {
         "Computer Vision" => String::from("\"color: #2525e5;\""),
         "AWS" => String::from("\"color: #FF9900;\""),
         "C++" => String::from("\"color: #659ad1;\""),
@@ -79,7 +79,7 @@ fn get_posts() -> Vec<Post> {
 }
 
 
-fn format_skills(skills: &Vec<String>) -> String {
+fn format_skills(skills: &[String]) -> String {
     String::from("skills: ") +
     &skills
         .iter()
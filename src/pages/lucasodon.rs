@@ -1,16 +1,42 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Result};
-use actix_web::cookie::Cookie;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::cookie::time::Duration as CookieDuration;
+use futures_util::StreamExt;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension};
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, XlsxError};
 
 use crate::components::navbar::navbar;
+use crate::middleware::rate_limit::{self, RateLimit, RateLimiter};
 
 const DB_PATH: &str = "bucket/lucasodon.db";
 const PASSWORD_PATH: &str = "bucket/lucasodon_password.txt";
+// Holidays recurring rules can skip: "MM-DD" (every year) or "YYYY-MM-DD"
+// per line, optionally followed by a name; '#' starts a comment.
+const FERIADOS_PATH: &str = "bucket/lucasodon_feriados.txt";
 const DEFAULT_PASSWORD: &str = "lucasodon";
+const DEFAULT_USERNAME: &str = "lucasodon";
 const COOKIE_NAME: &str = "lucasodon_auth";
+const SESSION_TOKEN_SIZE: usize = 48;
+const SESSION_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+const CSRF_TOKEN_SIZE: usize = 32;
+const CSRF_HEADER: &str = "X-CSRF-Token";
+// Failed logins are also counted per account, not only per client IP.
+const LOGIN_LIMIT_KEY: &str = "lucasodon:login";
 
+// Schema as of migration 1. Later columns and tables come from MIGRATIONS.
 const CREATE_TABLE_SQL: &str = "
     CREATE TABLE IF NOT EXISTS plantoes (
         id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -66,12 +92,196 @@ const CREATE_DESPESAS_SQL: &str = "
         ON despesas(recorrente_id, competencia) WHERE recorrente_id IS NOT NULL;
 ";
 
+// Accounts. Every plantão, despesa, categoria and recorrente belongs to one
+// through its user_id.
+const CREATE_USUARIOS_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS usuarios (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        nome TEXT NOT NULL UNIQUE,
+        senha_hash TEXT NOT NULL,
+        admin INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS sessoes (
+        token_hash TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        criada_em INTEGER NOT NULL,
+        expira_em INTEGER NOT NULL,
+        csrf_token TEXT NOT NULL DEFAULT ''
+    );
+    CREATE INDEX IF NOT EXISTS idx_sessoes_user ON sessoes(user_id);
+";
+
+// Databases from before accounts lack the user_id columns and have category
+// names unique across everyone, so categorias is rebuilt.
+const MIGRATE_USUARIOS_SQL: &str = "
+    ALTER TABLE plantoes ADD COLUMN user_id INTEGER;
+    ALTER TABLE despesas ADD COLUMN user_id INTEGER;
+    ALTER TABLE despesas_recorrentes ADD COLUMN user_id INTEGER;
+    CREATE TABLE categorias_nova (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        nome TEXT NOT NULL,
+        padrao INTEGER NOT NULL DEFAULT 0,
+        user_id INTEGER,
+        UNIQUE (user_id, nome)
+    );
+    INSERT INTO categorias_nova (id, nome, padrao) SELECT id, nome, padrao FROM categorias;
+    DROP TABLE categorias;
+    ALTER TABLE categorias_nova RENAME TO categorias;
+";
+
+const CREATE_USER_INDEXES_SQL: &str = "
+    CREATE INDEX IF NOT EXISTS idx_plantoes_user ON plantoes(user_id, data);
+    CREATE INDEX IF NOT EXISTS idx_despesas_user ON despesas(user_id, data);
+    CREATE INDEX IF NOT EXISTS idx_recorrentes_user ON despesas_recorrentes(user_id);
+";
+
+// One row per change to a plantão, despesa, categoria or recorrente, with
+// the row as JSON before and after (NULL for a creation or deletion).
+const CREATE_AUDITORIA_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS auditoria (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        tabela TEXT NOT NULL,
+        registro_id INTEGER NOT NULL,
+        acao TEXT NOT NULL,
+        antes TEXT,
+        depois TEXT,
+        criado_em INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_auditoria_registro ON auditoria(user_id, tabela, registro_id);
+";
+
+// Deletes only stamp deleted_at; rows stay in the trash (lixeira) until
+// purged after LIXEIRA_RETENCAO_DIAS. Rules come before despesas so the purge
+// finds the occurrences of a purged rule already orphaned.
+const TABELAS_LIXEIRA: [&str; 4] = ["despesas_recorrentes", "categorias", "plantoes", "despesas"];
+
+// Monthly IRPF brackets, by the month they take effect. Amounts in centavos,
+// limite NULL for the top bracket, aliquota in percent. Seeded with the
+// tables of Lei 14.848/2024 and MP 1.294/2025; later ones go in through
+// /lucasodon/api/irpf/tabela.
+const CREATE_IRPF_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS irpf_faixas (
+        vigencia TEXT NOT NULL,
+        limite INTEGER,
+        aliquota REAL NOT NULL,
+        deducao INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_irpf_vigencia ON irpf_faixas(vigencia);
+    INSERT INTO irpf_faixas (vigencia, limite, aliquota, deducao) VALUES
+        ('2024-02', 225920, 0, 0),
+        ('2024-02', 282665, 7.5, 16944),
+        ('2024-02', 375105, 15, 38144),
+        ('2024-02', 466468, 22.5, 66277),
+        ('2024-02', NULL, 27.5, 89600),
+        ('2025-05', 242880, 0, 0),
+        ('2025-05', 282665, 7.5, 18216),
+        ('2025-05', 375105, 15, 39416),
+        ('2025-05', 466468, 22.5, 67549),
+        ('2025-05', NULL, 27.5, 90873);
+";
+
 const CATEGORIAS_PADRAO: [&str; 8] = [
     "Moradia", "Alimentação", "Transporte", "Impostos",
     "Educação", "Lazer", "Saúde", "Outros",
 ];
 
 
+// ----------------------------- Money -----------------------------
+
+// Amounts are integer centavos everywhere on the server: in the database, in
+// sums and in comparisons. JSON still carries a number of reais, written from
+// the centavos so it is exact (1234.56, never 1234.5599999).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Dinheiro(pub i64);
+
+impl Dinheiro {
+    fn reais(self) -> f64 {
+        self.0 as f64 / 100.0
+    }
+
+    fn from_reais(reais: f64) -> Option<Dinheiro> {
+        let centavos = (reais * 100.0).round();
+        (centavos.is_finite() && centavos.abs() < i64::MAX as f64).then_some(Dinheiro(centavos as i64))
+    }
+
+    // "1234.56" or "1234,56"; used for form fields and imported files.
+    fn parse(texto: &str) -> Option<Dinheiro> {
+        let texto = texto.trim();
+        let (negativo, texto) = match texto.strip_prefix('-') {
+            Some(t) => (true, t),
+            None => (false, texto),
+        };
+        let (inteiro, fracao) = match texto.find(['.', ',']) {
+            Some(i) => (&texto[..i], &texto[i + 1..]),
+            None => (texto, ""),
+        };
+        if (inteiro.is_empty() && fracao.is_empty()) || fracao.len() > 2
+            || !inteiro.chars().chain(fracao.chars()).all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let inteiro: i64 = if inteiro.is_empty() { 0 } else { inteiro.parse().ok()? };
+        let fracao: i64 = format!("{:0<2}", fracao).parse().ok()?;
+        let centavos = inteiro.checked_mul(100)?.checked_add(fracao)?;
+        Some(Dinheiro(if negativo { -centavos } else { centavos }))
+    }
+}
+
+impl Serialize for Dinheiro {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.0 % 100 == 0 {
+            serializer.serialize_i64(self.0 / 100)
+        } else {
+            serializer.serialize_f64(self.reais())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Dinheiro {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct DinheiroVisitor;
+
+        impl serde::de::Visitor<'_> for DinheiroVisitor {
+            type Value = Dinheiro;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "um valor em reais")
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> std::result::Result<Dinheiro, E> {
+                v.checked_mul(100).map(Dinheiro).ok_or_else(|| E::custom("valor muito grande"))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> std::result::Result<Dinheiro, E> {
+                i64::try_from(v).map_err(|_| E::custom("valor muito grande")).and_then(|v| self.visit_i64(v))
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> std::result::Result<Dinheiro, E> {
+                Dinheiro::from_reais(v).ok_or_else(|| E::custom("valor invalido"))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> std::result::Result<Dinheiro, E> {
+                Dinheiro::parse(v).ok_or_else(|| E::custom("valor invalido"))
+            }
+        }
+
+        deserializer.deserialize_any(DinheiroVisitor)
+    }
+}
+
+impl ToSql for Dinheiro {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+impl FromSql for Dinheiro {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        i64::column_result(value).map(Dinheiro)
+    }
+}
+
+
 // ----------------------------- Data models -----------------------------
 
 #[derive(Deserialize)]
@@ -81,7 +291,7 @@ struct PlantaoInput {
     mfc_pa: String,
     duracao_h: f64,
     periodo: String,
-    valor: f64,
+    valor: Dinheiro,
     previsao_pagamento: Option<String>,
     recebido: bool,
     mes_ano_pagamento: Option<String>,
@@ -108,7 +318,7 @@ struct Plantao {
     mfc_pa: String,
     duracao_h: f64,
     periodo: String,
-    valor: f64,
+    valor: Dinheiro,
     valor_hora: f64,
     previsao_pagamento: Option<String>,
     recebido: bool,
@@ -118,14 +328,24 @@ struct Plantao {
 
 #[derive(Deserialize)]
 struct LoginForm {
+    usuario: String,
     password: String,
 }
 
+#[derive(Serialize)]
+struct Conta { nome: String, admin: bool }
+
+#[derive(Deserialize)]
+struct SenhaUpdate { atual: String, nova: String }
+
+#[derive(Deserialize)]
+struct UsuarioInput { nome: String, senha: String }
+
 
 // ----------------------------- Auth -----------------------------
 
-// The access password is kept in a local file so it never lives in the
-// source tree. On first run we create it with a default value.
+// The password of the first account, created from this file when the
+// database has none. On first run we create it with a default value.
 fn get_password() -> String {
     match fs::read_to_string(PASSWORD_PATH) {
         Ok(p) => p.trim().to_string(),
@@ -136,46 +356,347 @@ fn get_password() -> String {
     }
 }
 
-fn is_authed(req: &HttpRequest) -> bool {
-    match req.cookie(COOKIE_NAME) {
-        Some(c) => c.value() == get_password(),
-        None => false,
+fn hash_senha(senha: &str) -> String {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    Argon2::default()
+        .hash_password(senha.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .expect("argon2 hashes any password with default params")
+}
+
+fn verify_senha(senha: &str, senha_hash: &str) -> bool {
+    match PasswordHash::new(senha_hash) {
+        Ok(h) => Argon2::default().verify_password(senha.as_bytes(), &h).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+// Only a hash of each session token is stored, so a leaked database can't be
+// used to log in.
+fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn session_token(req: &HttpRequest) -> Option<String> {
+    req.cookie(COOKIE_NAME).map(|c| c.value().to_string())
+}
+
+fn random_token(size: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(size)
+        .map(char::from)
+        .collect()
+}
+
+// Each session gets its own CSRF token, which the app page hands to the
+// script and mutating API calls must echo back in CSRF_HEADER.
+fn create_sessao(conn: &Connection, user_id: i64) -> rusqlite::Result<String> {
+    conn.execute("DELETE FROM sessoes WHERE expira_em <= ?1", params![now_secs()])?;
+    let token = random_token(SESSION_TOKEN_SIZE);
+    conn.execute(
+        "INSERT INTO sessoes (token_hash, user_id, criada_em, expira_em, csrf_token) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![token_hash(&token), user_id, now_secs(), now_secs() + SESSION_TTL_SECS, random_token(CSRF_TOKEN_SIZE)],
+    )?;
+    Ok(token)
+}
+
+fn delete_sessao(conn: &Connection, token: &str) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM sessoes WHERE token_hash = ?1", params![token_hash(token)])
+}
+
+// Ends every session of the user except `keep`, if given.
+fn delete_sessoes(conn: &Connection, user_id: i64, keep: Option<&str>) -> rusqlite::Result<usize> {
+    let keep = keep.map(token_hash).unwrap_or_default();
+    conn.execute("DELETE FROM sessoes WHERE user_id = ?1 AND token_hash != ?2", params![user_id, keep])
+}
+
+fn session_cookie(token: String) -> Cookie<'static> {
+    Cookie::build(COOKIE_NAME, token)
+        .path("/lucasodon")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::seconds(SESSION_TTL_SECS))
+        .finish()
+}
+
+// (user_id, csrf_token) of the request's session, if it is still valid.
+async fn current_session(req: &HttpRequest, pool: &web::Data<DbPool>) -> Option<(i64, String)> {
+    let token = token_hash(&session_token(req)?);
+    with_db(pool, move |conn| conn.query_row(
+        "SELECT user_id, csrf_token FROM sessoes WHERE token_hash = ?1 AND expira_em > ?2",
+        params![token, now_secs()],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    ).optional()).await.ok().flatten()
+}
+
+async fn current_user(req: &HttpRequest, pool: &web::Data<DbPool>) -> Option<i64> {
+    current_session(req, pool).await.map(|(user_id, _)| user_id)
+}
+
+// For routes that change data: the session cookie alone is not enough, the
+// request must also carry the session's CSRF token.
+async fn mutating_user(req: &HttpRequest, pool: &web::Data<DbPool>) -> Result<i64, HttpResponse> {
+    let Some((user_id, csrf_token)) = current_session(req, pool).await else {
+        return Err(HttpResponse::Unauthorized().body("nao autorizado"));
+    };
+    let sent = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+    if csrf_token.is_empty() || sent != Some(csrf_token.as_str()) {
+        return Err(HttpResponse::Forbidden().body("token csrf invalido"));
     }
+    Ok(user_id)
 }
 
 
 // ----------------------------- Database -----------------------------
 
-fn open_db() -> rusqlite::Result<Connection> {
-    let conn = Connection::open(DB_PATH)?;
+pub type DbPool = Pool<SqliteConnectionManager>;
+
+#[derive(Debug)]
+pub enum DbError {
+    Pool(r2d2::Error),
+    Sql(rusqlite::Error),
+    Io(std::io::Error),
+    // A restore file that isn't a usable Lucasodon database.
+    Invalido(&'static str),
+    Blocking,
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Pool(e) => write!(f, "{}", e),
+            DbError::Sql(e) => write!(f, "{}", e),
+            DbError::Io(e) => write!(f, "{}", e),
+            DbError::Invalido(motivo) => write!(f, "{}", motivo),
+            DbError::Blocking => write!(f, "tarefa do banco interrompida"),
+        }
+    }
+}
+
+impl From<r2d2::Error> for DbError {
+    fn from(e: r2d2::Error) -> Self {
+        DbError::Pool(e)
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sql(e)
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+
+// SQLite calls block, so they run on actix's blocking thread pool with a
+// pooled connection instead of on the async executor.
+async fn with_db<T, F>(pool: &web::Data<DbPool>, f: F) -> Result<T, DbError>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || -> Result<T, DbError> {
+        let conn = pool.get()?;
+        Ok(f(&conn)?)
+    })
+    .await
+    .map_err(|_| DbError::Blocking)?
+}
+
+// Each migration takes the schema from version i (PRAGMA user_version) to
+// i + 1. Shipped migrations are never edited; changes go in a new one.
+type Migration = fn(&Connection) -> rusqlite::Result<()>;
+
+const MIGRATIONS: &[Migration] = &[
+    migration_base_schema,
+    migration_usuarios,
+    migration_centavos,
+    migration_auditoria,
+    migration_lixeira,
+    migration_irpf,
+    migration_recorrencias,
+];
+
+// Runs the pending migrations in a single transaction, so a failure leaves
+// the database as it was.
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+    let tx = conn.transaction()?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version.max(0) as usize) {
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", i as i64 + 1)?;
+    }
+    tx.commit()
+}
+
+// Called once from main, before the server starts. WAL lets the pooled
+// connections read while another one writes.
+pub fn init_db() -> Result<DbPool, DbError> {
+    let manager = SqliteConnectionManager::file(DB_PATH)
+        .with_init(|c| c.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;"));
+    let pool = Pool::new(manager)?;
+    let mut conn = pool.get()?;
+    migrate(&mut conn)?;
+    ensure_first_usuario(&conn, &get_password())?;
+    Ok(pool)
+}
+
+// Databases from before migrations exist at version 0 in any of the shapes
+// the old per-request setup left behind, hence IF NOT EXISTS here and the column
+// checks in the next one.
+fn migration_base_schema(conn: &Connection) -> rusqlite::Result<()> {
     conn.execute(CREATE_TABLE_SQL, [])?;
-    conn.execute_batch(CREATE_DESPESAS_SQL)?;
-    seed_categorias(&conn)?;
-    Ok(conn)
+    conn.execute_batch(CREATE_DESPESAS_SQL)
+}
+
+fn migration_usuarios(conn: &Connection) -> rusqlite::Result<()> {
+    if !has_column(conn, "plantoes", "user_id")? {
+        conn.execute_batch(MIGRATE_USUARIOS_SQL)?;
+    }
+    conn.execute_batch(CREATE_USUARIOS_SQL)?;
+    if !has_column(conn, "sessoes", "csrf_token")? {
+        // Sessions from before CSRF tokens can't make changes, so drop them.
+        conn.execute_batch("DELETE FROM sessoes; ALTER TABLE sessoes ADD COLUMN csrf_token TEXT NOT NULL DEFAULT '';")?;
+    }
+    conn.execute_batch(CREATE_USER_INDEXES_SQL)
+}
+
+// REAL reais become INTEGER centavos. The column is rebuilt rather than
+// updated in place because REAL affinity would turn the integers back into
+// floats.
+fn migration_centavos(conn: &Connection) -> rusqlite::Result<()> {
+    for table in ["plantoes", "despesas", "despesas_recorrentes"] {
+        conn.execute_batch(&format!("
+            ALTER TABLE {t} ADD COLUMN valor_centavos INTEGER NOT NULL DEFAULT 0;
+            UPDATE {t} SET valor_centavos = CAST(ROUND(valor * 100) AS INTEGER);
+            ALTER TABLE {t} DROP COLUMN valor;
+            ALTER TABLE {t} RENAME COLUMN valor_centavos TO valor;
+        ", t = table))?;
+    }
+    Ok(())
+}
+
+fn migration_auditoria(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(CREATE_AUDITORIA_SQL)
+}
+
+// Despesas cancelled before the trash existed stay 'cancelado' tombstones.
+fn migration_lixeira(conn: &Connection) -> rusqlite::Result<()> {
+    for table in TABELAS_LIXEIRA {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN deleted_at INTEGER;", table))?;
+    }
+    Ok(())
+}
+
+fn migration_irpf(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(CREATE_IRPF_SQL)
+}
+
+// Rules without these behave as before: no installment count and due dates
+// left on whatever day they fall.
+fn migration_recorrencias(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE despesas_recorrentes ADD COLUMN parcelas INTEGER;
+         ALTER TABLE despesas_recorrentes ADD COLUMN dia_util TEXT NOT NULL DEFAULT 'nenhum';",
+    )
+}
+
+fn table_columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map([], |r| r.get::<_, String>(1))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(names)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    Ok(table_columns(conn, table)?.iter().any(|n| n == column))
+}
+
+// With no accounts yet, the first one is created from the password file, as
+// admin, and adopts every row that has no owner.
+fn ensure_first_usuario(conn: &Connection, senha: &str) -> rusqlite::Result<()> {
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM usuarios", [], |r| r.get(0))?;
+    if count == 0 {
+        let user_id = insert_usuario(conn, DEFAULT_USERNAME, senha, true)?;
+        for table in ["plantoes", "despesas", "despesas_recorrentes", "categorias"] {
+            conn.execute(&format!("UPDATE {} SET user_id = ?1 WHERE user_id IS NULL", table), params![user_id])?;
+        }
+        seed_categorias(conn, user_id)?;
+    }
+    Ok(())
+}
+
+fn insert_usuario(conn: &Connection, nome: &str, senha: &str, admin: bool) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT INTO usuarios (nome, senha_hash, admin) VALUES (?1, ?2, ?3)",
+        params![nome.trim(), hash_senha(senha), admin as i64],
+    )?;
+    Ok(conn.last_insert_rowid())
 }
 
-fn seed_categorias(conn: &Connection) -> rusqlite::Result<()> {
-    let count: i64 = conn.query_row("SELECT COUNT(*) FROM categorias", [], |r| r.get(0))?;
+fn seed_categorias(conn: &Connection, user_id: i64) -> rusqlite::Result<()> {
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM categorias WHERE user_id = ?1", params![user_id], |r| r.get(0))?;
     if count == 0 {
         for nome in CATEGORIAS_PADRAO {
-            conn.execute("INSERT INTO categorias (nome, padrao) VALUES (?1, 1)", params![nome])?;
+            conn.execute("INSERT INTO categorias (nome, padrao, user_id) VALUES (?1, 1, ?2)", params![nome, user_id])?;
         }
     }
     Ok(())
 }
 
-fn list_plantoes() -> rusqlite::Result<Vec<Plantao>> {
-    let conn = open_db()?;
+fn create_usuario(conn: &Connection, u: &UsuarioInput) -> rusqlite::Result<()> {
+    let user_id = insert_usuario(conn, &u.nome, &u.senha, false)?;
+    seed_categorias(conn, user_id)
+}
+
+// (id, senha_hash) of the account with this name.
+fn find_usuario(conn: &Connection, nome: &str) -> rusqlite::Result<Option<(i64, String)>> {
+    conn.query_row(
+        "SELECT id, senha_hash FROM usuarios WHERE nome = ?1",
+        params![nome.trim()],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    ).optional()
+}
+
+fn get_conta(conn: &Connection, user_id: i64) -> rusqlite::Result<Conta> {
+    conn.query_row("SELECT nome, admin FROM usuarios WHERE id = ?1", params![user_id], |r| Ok(Conta {
+        nome: r.get(0)?, admin: r.get::<_, i64>(1)? != 0,
+    }))
+}
+
+// Returns false when the current password is wrong. Other sessions of the
+// user are ended, the one making the change stays logged in.
+fn change_senha(conn: &Connection, user_id: i64, sessao: &str, u: &SenhaUpdate) -> rusqlite::Result<bool> {
+    let atual: String = conn.query_row("SELECT senha_hash FROM usuarios WHERE id = ?1", params![user_id], |r| r.get(0))?;
+    if !verify_senha(&u.atual, &atual) {
+        return Ok(false);
+    }
+    conn.execute("UPDATE usuarios SET senha_hash = ?1 WHERE id = ?2", params![hash_senha(&u.nova), user_id])?;
+    delete_sessoes(conn, user_id, Some(sessao))?;
+    Ok(true)
+}
+
+fn list_plantoes(conn: &Connection, user_id: i64) -> rusqlite::Result<Vec<Plantao>> {
     let mut stmt = conn.prepare(
         "SELECT id, data, local, mfc_pa, duracao_h, periodo, valor,
                 previsao_pagamento, recebido, mes_ano_pagamento, observacoes
          FROM plantoes
+         WHERE user_id = ?1 AND deleted_at IS NULL
          ORDER BY data ASC, id ASC",
     )?;
 
-    let rows = stmt.query_map([], |row| {
+    let rows = stmt.query_map(params![user_id], |row| {
         let duracao_h: f64 = row.get(4)?;
-        let valor: f64 = row.get(6)?;
+        let valor: Dinheiro = row.get(6)?;
         Ok(Plantao {
             id: row.get(0)?,
             data: row.get(1)?,
@@ -184,7 +705,7 @@ fn list_plantoes() -> rusqlite::Result<Vec<Plantao>> {
             duracao_h,
             periodo: row.get(5)?,
             valor,
-            valor_hora: if duracao_h > 0.0 { valor / duracao_h } else { 0.0 },
+            valor_hora: if duracao_h > 0.0 { valor.reais() / duracao_h } else { 0.0 },
             previsao_pagamento: row.get(7)?,
             recebido: row.get::<_, i64>(8)? != 0,
             mes_ano_pagamento: row.get(9)?,
@@ -195,41 +716,43 @@ fn list_plantoes() -> rusqlite::Result<Vec<Plantao>> {
     rows.collect()
 }
 
-fn insert_plantao(p: &PlantaoInput) -> rusqlite::Result<()> {
-    let conn = open_db()?;
-    conn.execute(
-        "INSERT INTO plantoes
-            (data, local, mfc_pa, duracao_h, periodo, valor,
-             previsao_pagamento, recebido, mes_ano_pagamento, observacoes)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-        params![
-            p.data, p.local, p.mfc_pa, p.duracao_h, p.periodo, p.valor,
-            p.previsao_pagamento, p.recebido as i64, p.mes_ano_pagamento, p.observacoes,
-        ],
-    )?;
-    Ok(())
+fn insert_plantao(conn: &Connection, user_id: i64, p: &PlantaoInput) -> rusqlite::Result<()> {
+    em_savepoint(conn, || {
+        conn.execute(
+            "INSERT INTO plantoes
+                (data, local, mfc_pa, duracao_h, periodo, valor,
+                 previsao_pagamento, recebido, mes_ano_pagamento, observacoes, user_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                p.data, p.local, p.mfc_pa, p.duracao_h, p.periodo, p.valor,
+                p.previsao_pagamento, p.recebido as i64, p.mes_ano_pagamento, p.observacoes, user_id,
+            ],
+        )?;
+        auditar_criacao(conn, user_id, "plantoes")
+    })
 }
 
-fn update_plantao(u: &PlantaoUpdate) -> rusqlite::Result<usize> {
-    let conn = open_db()?;
+fn update_plantao(conn: &Connection, user_id: i64, u: &PlantaoUpdate) -> rusqlite::Result<usize> {
     let p = &u.fields;
-    conn.execute(
+    auditado(conn, user_id, "plantoes", "alterar", &[u.id], || conn.execute(
         "UPDATE plantoes SET
             data = ?1, local = ?2, mfc_pa = ?3, duracao_h = ?4, periodo = ?5,
             valor = ?6, previsao_pagamento = ?7, recebido = ?8,
             mes_ano_pagamento = ?9, observacoes = ?10
-         WHERE id = ?11",
+         WHERE id = ?11 AND user_id = ?12 AND deleted_at IS NULL",
         params![
             p.data, p.local, p.mfc_pa, p.duracao_h, p.periodo, p.valor,
             p.previsao_pagamento, p.recebido as i64, p.mes_ano_pagamento,
-            p.observacoes, u.id,
+            p.observacoes, u.id, user_id,
         ],
-    )
+    ))
 }
 
-fn delete_plantao(id: i64) -> rusqlite::Result<usize> {
-    let conn = open_db()?;
-    conn.execute("DELETE FROM plantoes WHERE id = ?1", params![id])
+fn delete_plantao(conn: &Connection, user_id: i64, id: i64) -> rusqlite::Result<usize> {
+    auditado(conn, user_id, "plantoes", "excluir", &[id], || conn.execute(
+        "UPDATE plantoes SET deleted_at = ?1 WHERE id = ?2 AND user_id = ?3 AND deleted_at IS NULL",
+        params![now_secs(), id, user_id],
+    ))
 }
 
 
@@ -247,7 +770,7 @@ struct CategoriaUpdate { id: i64, nome: String }
 #[derive(Serialize)]
 struct Despesa {
     id: i64,
-    valor: f64,
+    valor: Dinheiro,
     data: String,
     descricao: Option<String>,
     categoria_id: Option<i64>,
@@ -260,7 +783,7 @@ struct Despesa {
 
 #[derive(Deserialize)]
 struct DespesaInput {
-    valor: f64,
+    valor: Dinheiro,
     data: String,
     descricao: Option<String>,
     categoria_id: Option<i64>,
@@ -276,7 +799,7 @@ struct DespesaUpdate { id: i64, #[serde(flatten)] fields: DespesaInput }
 struct Recorrente {
     id: i64,
     descricao: String,
-    valor: f64,
+    valor: Dinheiro,
     categoria_id: Option<i64>,
     tipo: String,
     periodicidade: String,
@@ -285,12 +808,18 @@ struct Recorrente {
     data_inicio: String,
     data_fim: Option<String>,
     ativo: bool,
+    parcelas: Option<i64>,
+    dia_util: String,
 }
 
+// periodicidade is one of PERIODICIDADES; semanal and quinzenal count from
+// data_inicio and ignore dia_vencimento. `parcelas` ends the rule after that
+// many occurrences ("parcelado em N vezes"); `dia_util` moves due dates off
+// weekends and holidays: "nenhum", "proximo" or "anterior".
 #[derive(Deserialize)]
 struct RecorrenteInput {
     descricao: String,
-    valor: f64,
+    valor: Dinheiro,
     categoria_id: Option<i64>,
     tipo: String,
     periodicidade: String,
@@ -299,198 +828,673 @@ struct RecorrenteInput {
     data_inicio: String,
     data_fim: Option<String>,
     ativo: Option<bool>,
+    parcelas: Option<i64>,
+    dia_util: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct RecorrenteUpdate { id: i64, #[serde(flatten)] fields: RecorrenteInput }
+struct ImportarPreview {
+    // "csv" or "ofx"; guessed from the content when missing.
+    formato: Option<String>,
+    conteudo: String,
+}
 
-#[derive(Deserialize)]
-struct DespesaFilter {
-    inicio: Option<String>,
-    fim: Option<String>,
+// One debit from a statement, as proposed to the user.
+#[derive(Serialize)]
+struct LinhaExtrato {
+    linha: usize,
+    data: String,
+    descricao: String,
+    valor: Dinheiro,
     categoria_id: Option<i64>,
-    tipo: Option<String>,
+    tipo: String,
+    // "importar", "duplicada" (already recorded) or "pagar_previsto" (settles
+    // the recurring occurrence alvo_id).
+    acao: String,
+    alvo_id: Option<i64>,
 }
 
-
-// ----------------------- Despesas: date helpers -----------------------
-
-fn is_leap(y: i32) -> bool { (y % 4 == 0 && y % 100 != 0) || y % 400 == 0 }
-
-fn days_in_month(y: i32, m: u32) -> u32 {
-    match m {
-        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
-        4 | 6 | 9 | 11 => 30,
-        2 => if is_leap(y) { 29 } else { 28 },
-        _ => 30,
-    }
+#[derive(Serialize)]
+struct PreviewExtrato {
+    formato: String,
+    linhas: Vec<LinhaExtrato>,
+    // Credits and lines that could not be read.
+    ignoradas: usize,
 }
 
-// Parse the (year, month) out of a "YYYY-MM" or "YYYY-MM-DD" string.
-fn parse_ym(s: &str) -> (i32, u32) {
-    let y = s.get(0..4).and_then(|v| v.parse().ok()).unwrap_or(0);
-    let m = s.get(5..7).and_then(|v| v.parse().ok()).unwrap_or(1);
-    (y, m)
+#[derive(Deserialize)]
+struct LinhaConfirmada {
+    data: String,
+    descricao: String,
+    valor: Dinheiro,
+    categoria_id: Option<i64>,
+    tipo: String,
+    acao: String,
+    alvo_id: Option<i64>,
 }
 
+#[derive(Deserialize)]
+struct ImportarConfirmar {
+    linhas: Vec<LinhaConfirmada>,
+}
 
-// ----------------------- Despesas: categorias -----------------------
+#[derive(Serialize)]
+struct ResultadoImportacao {
+    importadas: usize,
+    pagas: usize,
+}
 
-fn list_categorias() -> rusqlite::Result<Vec<Categoria>> {
-    let conn = open_db()?;
-    let mut stmt = conn.prepare("SELECT id, nome, padrao FROM categorias ORDER BY nome ASC")?;
-    let rows = stmt.query_map([], |r| Ok(Categoria {
-        id: r.get(0)?, nome: r.get(1)?, padrao: r.get::<_, i64>(2)? != 0,
-    }))?;
-    rows.collect()
+#[derive(Deserialize)]
+struct Periodo {
+    inicio: Option<String>,
+    fim: Option<String>,
 }
 
-fn insert_categoria(c: &CategoriaInput) -> rusqlite::Result<()> {
-    let conn = open_db()?;
-    conn.execute("INSERT INTO categorias (nome, padrao) VALUES (?1, 0)", params![c.nome.trim()])?;
-    Ok(())
+#[derive(Serialize)]
+struct ItemLixeira {
+    tabela: String,
+    id: i64,
+    descricao: Option<String>,
+    data: Option<String>,
+    valor: Option<Dinheiro>,
+    excluido_em: i64,
+    // When the purge removes it for good.
+    expira_em: i64,
 }
 
-fn update_categoria(u: &CategoriaUpdate) -> rusqlite::Result<usize> {
-    let conn = open_db()?;
-    conn.execute("UPDATE categorias SET nome = ?1 WHERE id = ?2", params![u.nome.trim(), u.id])
+#[derive(Deserialize)]
+struct ItemRef {
+    tabela: String,
+    id: i64,
 }
 
-// Deleting a category reassigns its expenses/rules to "Outros" (or NULL if
-// "Outros" itself is being deleted), so nothing is left dangling.
-fn delete_categoria(id: i64) -> rusqlite::Result<usize> {
-    let conn = open_db()?;
-    let outros: Option<i64> = conn
-        .query_row("SELECT id FROM categorias WHERE nome = 'Outros'", [], |r| r.get(0))
-        .optional()?;
-    let target = if outros == Some(id) { None } else { outros };
-    conn.execute("UPDATE despesas SET categoria_id = ?1 WHERE categoria_id = ?2", params![target, id])?;
-    conn.execute("UPDATE despesas_recorrentes SET categoria_id = ?1 WHERE categoria_id = ?2", params![target, id])?;
-    conn.execute("DELETE FROM categorias WHERE id = ?1", params![id])
+#[derive(Deserialize)]
+struct AuditoriaQuery {
+    tabela: Option<String>,
+    registro_id: Option<i64>,
+    // Paging: only entries older than this id.
+    antes_de: Option<i64>,
+    limite: Option<i64>,
 }
 
+#[derive(Serialize)]
+struct EntradaAuditoria {
+    id: i64,
+    tabela: String,
+    registro_id: i64,
+    // "criar", "alterar", "excluir", "restaurar" or "purgar"
+    acao: String,
+    usuario: String,
+    criado_em: i64,
+    antes: Option<Value>,
+    depois: Option<Value>,
+}
 
-// ----------------------- Despesas: recurring rules -----------------------
+#[derive(Deserialize)]
+struct AuditoriaRestaurar {
+    id: i64,
+    // "antes" (default, undoes the change) or "depois"
+    versao: Option<String>,
+}
 
-fn list_recorrentes() -> rusqlite::Result<Vec<Recorrente>> {
-    let conn = open_db()?;
-    let mut stmt = conn.prepare(
-        "SELECT id, descricao, valor, categoria_id, tipo, periodicidade,
-                dia_vencimento, mes_vencimento, data_inicio, data_fim, ativo
-         FROM despesas_recorrentes ORDER BY descricao ASC")?;
-    let rows = stmt.query_map([], |r| Ok(Recorrente {
-        id: r.get(0)?, descricao: r.get(1)?, valor: r.get(2)?, categoria_id: r.get(3)?,
-        tipo: r.get(4)?, periodicidade: r.get(5)?, dia_vencimento: r.get(6)?,
-        mes_vencimento: r.get(7)?, data_inicio: r.get(8)?, data_fim: r.get(9)?,
-        ativo: r.get::<_, i64>(10)? != 0,
-    }))?;
-    rows.collect()
+#[derive(Deserialize)]
+struct ExportarQuery {
+    inicio: Option<String>,
+    fim: Option<String>,
+    // "csv" (default) or "xlsx".
+    formato: Option<String>,
 }
 
-fn insert_recorrente(p: &RecorrenteInput) -> rusqlite::Result<()> {
-    let conn = open_db()?;
-    conn.execute(
-        "INSERT INTO despesas_recorrentes
-            (descricao, valor, categoria_id, tipo, periodicidade,
-             dia_vencimento, mes_vencimento, data_inicio, data_fim, ativo)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-        params![
-            p.descricao, p.valor, p.categoria_id, p.tipo, p.periodicidade,
-            p.dia_vencimento, p.mes_vencimento, p.data_inicio, p.data_fim,
-            p.ativo.unwrap_or(true) as i64,
-        ],
-    )?;
-    Ok(())
+#[derive(Serialize, Default)]
+struct ResumoReceitas {
+    total: Dinheiro,
+    recebido: Dinheiro,
+    pendente: Dinheiro,
+    plantoes: i64,
 }
 
-fn update_recorrente(u: &RecorrenteUpdate) -> rusqlite::Result<usize> {
-    let conn = open_db()?;
-    let p = &u.fields;
-    conn.execute(
-        "UPDATE despesas_recorrentes SET
-            descricao = ?1, valor = ?2, categoria_id = ?3, tipo = ?4, periodicidade = ?5,
-            dia_vencimento = ?6, mes_vencimento = ?7, data_inicio = ?8, data_fim = ?9, ativo = ?10
-         WHERE id = ?11",
-        params![
-            p.descricao, p.valor, p.categoria_id, p.tipo, p.periodicidade,
-            p.dia_vencimento, p.mes_vencimento, p.data_inicio, p.data_fim,
-            p.ativo.unwrap_or(true) as i64, u.id,
-        ],
-    )
+#[derive(Serialize)]
+struct ResumoMes {
+    mes: String,
+    total: Dinheiro,
+    recebido: Dinheiro,
+    pendente: Dinheiro,
+    plantoes: i64,
+    percentual_recebido: f64,
 }
 
-// Deleting a rule drops its still-pending ('previsto') occurrences but keeps
-// already-paid ones as historical record.
-fn delete_recorrente(id: i64) -> rusqlite::Result<usize> {
-    let conn = open_db()?;
-    conn.execute("DELETE FROM despesas WHERE recorrente_id = ?1 AND status = 'previsto'", params![id])?;
-    conn.execute("DELETE FROM despesas_recorrentes WHERE id = ?1", params![id])
+#[derive(Serialize, Default)]
+struct ResumoDespesas {
+    total: Dinheiro,
+    pago: Dinheiro,
+    previsto: Dinheiro,
+}
+
+#[derive(Serialize)]
+struct ResumoCategoria {
+    categoria_id: Option<i64>,
+    nome: String,
+    total: Dinheiro,
+    percentual: f64,
+}
+
+#[derive(Serialize)]
+struct ResumoTipo {
+    tipo: String,
+    total: Dinheiro,
+    percentual: f64,
+}
+
+#[derive(Deserialize)]
+struct ProjecaoQuery {
+    meses: Option<u32>,
+    // "YYYY-MM"; defaults to the current month.
+    inicio: Option<String>,
+    saldo_inicial: Option<Dinheiro>,
+}
+
+#[derive(Serialize)]
+struct ProjecaoMes {
+    mes: String,
+    receitas: Dinheiro,
+    despesas: Dinheiro,
+    saldo_mes: Dinheiro,
+    saldo_previsto: Dinheiro,
+    deficit: bool,
+}
+
+#[derive(Serialize)]
+struct Projecao {
+    inicio: String,
+    saldo_inicial: Dinheiro,
+    // Receivables whose payment date is already before `inicio`.
+    atrasado: Dinheiro,
+    meses: Vec<ProjecaoMes>,
+    meses_em_deficit: usize,
+}
+
+#[derive(Deserialize)]
+struct CarneLeaoQuery {
+    // Defaults to the current year.
+    ano: Option<i32>,
+}
+
+// One bracket of the monthly IRPF table: up to `limite` (None for the top
+// bracket) the tax is base * aliquota% - deducao.
+#[derive(Serialize, Deserialize, Clone)]
+struct FaixaIrpf {
+    limite: Option<Dinheiro>,
+    aliquota: f64,
+    deducao: Dinheiro,
+}
+
+// The brackets in force from `vigencia` ("YYYY-MM") until the next table.
+#[derive(Serialize, Deserialize)]
+struct TabelaIrpf {
+    vigencia: String,
+    faixas: Vec<FaixaIrpf>,
+}
+
+#[derive(Serialize)]
+struct CarneLeaoMes {
+    mes: String,
+    rendimentos: Dinheiro,
+    despesas_profissionais: Dinheiro,
+    // Deductions left over from earlier months of the year.
+    excesso_anterior: Dinheiro,
+    base_calculo: Dinheiro,
+    aliquota: f64,
+    parcela_deduzir: Dinheiro,
+    imposto: Dinheiro,
+}
+
+#[derive(Serialize)]
+struct CarneLeaoAnual {
+    rendimentos: Dinheiro,
+    despesas_profissionais: Dinheiro,
+    base_calculo: Dinheiro,
+    imposto: Dinheiro,
+    aliquota_efetiva: f64,
+    // Deductions no month could absorb; they don't carry into the next year.
+    excesso_nao_usado: Dinheiro,
+}
+
+#[derive(Serialize)]
+struct CarneLeao {
+    ano: i32,
+    meses: Vec<CarneLeaoMes>,
+    anual: CarneLeaoAnual,
+}
+
+#[derive(Serialize)]
+struct Resumo {
+    inicio: Option<String>,
+    fim: Option<String>,
+    receitas: ResumoReceitas,
+    receitas_por_mes: Vec<ResumoMes>,
+    despesas: ResumoDespesas,
+    despesas_por_categoria: Vec<ResumoCategoria>,
+    despesas_por_tipo: Vec<ResumoTipo>,
+    // Everything in the period, and only what was actually received and paid.
+    saldo: Dinheiro,
+    saldo_realizado: Dinheiro,
+}
+
+#[derive(Deserialize)]
+struct RecorrenteUpdate { id: i64, #[serde(flatten)] fields: RecorrenteInput }
+
+#[derive(Deserialize)]
+struct DespesaFilter {
+    inicio: Option<String>,
+    fim: Option<String>,
+    categoria_id: Option<i64>,
+    tipo: Option<String>,
+}
+
+
+// ----------------------- Despesas: date helpers -----------------------
+
+fn is_leap(y: i32) -> bool { (y % 4 == 0 && y % 100 != 0) || y % 400 == 0 }
+
+fn days_in_month(y: i32, m: u32) -> u32 {
+    match m {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 => if is_leap(y) { 29 } else { 28 },
+        _ => 30,
+    }
+}
+
+// Parse the (year, month) out of a "YYYY-MM" or "YYYY-MM-DD" string.
+fn parse_ym(s: &str) -> (i32, u32) {
+    let y = s.get(0..4).and_then(|v| v.parse().ok()).unwrap_or(0);
+    let m = s.get(5..7).and_then(|v| v.parse().ok()).unwrap_or(1);
+    (y, m)
+}
+
+fn add_months(y: i32, m: u32, n: i32) -> (i32, u32) {
+    let total = y * 12 + (m as i32 - 1) + n;
+    (total.div_euclid(12), total.rem_euclid(12) as u32 + 1)
+}
+
+// Today's (year, month, day) in UTC.
+fn hoje() -> (i32, u32, u32) {
+    civil_from_days(now_secs().div_euclid(86_400))
+}
+
+// (year, month, day) of a count of days since 1970-01-01, and back (Howard
+// Hinnant's civil_from_days / days_from_civil).
+fn civil_from_days(dias: i64) -> (i32, u32, u32) {
+    let z = dias + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = (yoe + era * 400) as i32 + i32::from(m <= 2);
+    (y, m, d)
+}
+
+fn days_from_civil(y: i32, m: u32, d: u32) -> i64 {
+    let y = i64::from(y) - i64::from(m <= 2);
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (i64::from(m) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(d) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn parse_ymd(s: &str) -> (i32, u32, u32) {
+    let (y, m) = parse_ym(s);
+    let d = s.get(8..10).and_then(|v| v.parse().ok()).unwrap_or(1);
+    (y, m, d)
+}
+
+// Whether s is exactly a real "YYYY-MM-DD" date.
+fn data_valida(s: &str) -> bool {
+    let formato = s.len() == 10 && s.bytes().enumerate().all(|(i, b)| if i == 4 || i == 7 { b == b'-' } else { b.is_ascii_digit() });
+    let (y, m, d) = parse_ymd(s);
+    formato && (1..=12).contains(&m) && (1..=days_in_month(y, m)).contains(&d)
+}
+
+fn fmt_dias(dias: i64) -> String {
+    let (y, m, d) = civil_from_days(dias);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+
+// ----------------------- Despesas: categorias -----------------------
+
+fn list_categorias(conn: &Connection, user_id: i64) -> rusqlite::Result<Vec<Categoria>> {
+    let mut stmt = conn.prepare("SELECT id, nome, padrao FROM categorias WHERE user_id = ?1 AND deleted_at IS NULL ORDER BY nome ASC")?;
+    let rows = stmt.query_map(params![user_id], |r| Ok(Categoria {
+        id: r.get(0)?, nome: r.get(1)?, padrao: r.get::<_, i64>(2)? != 0,
+    }))?;
+    rows.collect()
+}
+
+// A trashed category with the same name is purged to make room.
+fn insert_categoria(conn: &Connection, user_id: i64, c: &CategoriaInput) -> rusqlite::Result<()> {
+    em_savepoint(conn, || {
+        purge_categorias_na_lixeira(conn, user_id, c.nome.trim())?;
+        conn.execute("INSERT INTO categorias (nome, padrao, user_id) VALUES (?1, 0, ?2)", params![c.nome.trim(), user_id])?;
+        auditar_criacao(conn, user_id, "categorias")
+    })
+}
+
+// Renaming to the name of a trashed category purges it, as on insert.
+fn update_categoria(conn: &Connection, user_id: i64, u: &CategoriaUpdate) -> rusqlite::Result<usize> {
+    let owned: Option<i64> = conn
+        .query_row("SELECT id FROM categorias WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NULL", params![u.id, user_id], |r| r.get(0))
+        .optional()?;
+    if owned.is_none() {
+        return Ok(0);
+    }
+    em_savepoint(conn, || {
+        purge_categorias_na_lixeira(conn, user_id, u.nome.trim())?;
+        auditado(conn, user_id, "categorias", "alterar", &[u.id], || {
+            conn.execute(
+                "UPDATE categorias SET nome = ?1 WHERE id = ?2 AND user_id = ?3 AND deleted_at IS NULL",
+                params![u.nome.trim(), u.id, user_id],
+            )
+        })
+    })
+}
+
+fn purge_categorias_na_lixeira(conn: &Connection, user_id: i64, nome: &str) -> rusqlite::Result<()> {
+    let lixo = ids_where(conn, "categorias", "user_id = ?1 AND nome = ?2 AND deleted_at IS NOT NULL", params![user_id, nome])?;
+    for id in lixo {
+        purge_item(conn, user_id, "categorias", id)?;
+    }
+    Ok(())
+}
+
+// UNIQUE (user_id, nome): a name another category already uses is the
+// client's mistake, not a server error.
+fn nome_em_uso(e: &DbError) -> bool {
+    matches!(e, DbError::Sql(rusqlite::Error::SqliteFailure(f, _)) if f.code == rusqlite::ErrorCode::ConstraintViolation)
+}
+
+// Deleting a category reassigns its live expenses/rules to "Outros" (or NULL
+// if "Outros" itself is being deleted), so nothing is left dangling. Trashed
+// rows keep it, and restoring the category moves the others back.
+fn delete_categoria(conn: &Connection, user_id: i64, id: i64) -> rusqlite::Result<usize> {
+    let owned: Option<i64> = conn
+        .query_row("SELECT id FROM categorias WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NULL", params![id, user_id], |r| r.get(0))
+        .optional()?;
+    if owned.is_none() {
+        return Ok(0);
+    }
+    let outros: Option<i64> = conn
+        .query_row("SELECT id FROM categorias WHERE nome = 'Outros' AND user_id = ?1 AND deleted_at IS NULL", params![user_id], |r| r.get(0))
+        .optional()?;
+    let target = if outros == Some(id) { None } else { outros };
+    em_savepoint(conn, || {
+        for table in ["despesas", "despesas_recorrentes"] {
+            let ids = ids_where(conn, table, "categoria_id = ?1 AND deleted_at IS NULL", params![id])?;
+            auditado(conn, user_id, table, "alterar", &ids, || conn.execute(
+                &format!("UPDATE {} SET categoria_id = ?1 WHERE categoria_id = ?2 AND deleted_at IS NULL", table),
+                params![target, id],
+            ))?;
+        }
+        auditado(conn, user_id, "categorias", "excluir", &[id], || {
+            conn.execute("UPDATE categorias SET deleted_at = ?1 WHERE id = ?2", params![now_secs(), id])
+        })
+    })
+}
+
+// A categoria_id is kept only if it is one of the user's own categories.
+const OWN_CATEGORIA_SQL: &str = "(SELECT id FROM categorias WHERE id = ?{cat} AND user_id = ?{user} AND deleted_at IS NULL)";
+
+fn own_categoria(cat: usize, user: usize) -> String {
+    OWN_CATEGORIA_SQL.replace("{cat}", &cat.to_string()).replace("{user}", &user.to_string())
+}
+
+
+// ----------------------- Despesas: recurring rules -----------------------
+
+fn list_recorrentes(conn: &Connection, user_id: i64) -> rusqlite::Result<Vec<Recorrente>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, descricao, valor, categoria_id, tipo, periodicidade,
+                dia_vencimento, mes_vencimento, data_inicio, data_fim, ativo, parcelas, dia_util
+         FROM despesas_recorrentes WHERE user_id = ?1 AND deleted_at IS NULL ORDER BY descricao ASC")?;
+    let rows = stmt.query_map(params![user_id], |r| Ok(Recorrente {
+        id: r.get(0)?, descricao: r.get(1)?, valor: r.get(2)?, categoria_id: r.get(3)?,
+        tipo: r.get(4)?, periodicidade: r.get(5)?, dia_vencimento: r.get(6)?,
+        mes_vencimento: r.get(7)?, data_inicio: r.get(8)?, data_fim: r.get(9)?,
+        ativo: r.get::<_, i64>(10)? != 0, parcelas: r.get(11)?, dia_util: r.get(12)?,
+    }))?;
+    rows.collect()
+}
+
+const PERIODICIDADES: [&str; 6] = ["semanal", "quinzenal", "mensal", "trimestral", "semestral", "anual"];
+const AJUSTES_DIA_UTIL: [&str; 3] = ["nenhum", "proximo", "anterior"];
+
+fn recorrente_valida(p: &RecorrenteInput) -> bool {
+    PERIODICIDADES.contains(&p.periodicidade.as_str())
+        && AJUSTES_DIA_UTIL.contains(&p.dia_util.as_deref().unwrap_or("nenhum"))
+        && p.parcelas.is_none_or(|n| (1..=600).contains(&n))
+        && (1..=31).contains(&p.dia_vencimento)
+        && (p.periodicidade != "anual" || p.mes_vencimento.is_some_and(|m| (1..=12).contains(&m)))
+}
+
+fn insert_recorrente(conn: &Connection, user_id: i64, p: &RecorrenteInput) -> rusqlite::Result<()> {
+    em_savepoint(conn, || {
+        conn.execute(
+            &format!("INSERT INTO despesas_recorrentes
+                (descricao, valor, categoria_id, tipo, periodicidade,
+                 dia_vencimento, mes_vencimento, data_inicio, data_fim, ativo, user_id, parcelas, dia_util)
+             VALUES (?1, ?2, {}, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)", own_categoria(3, 11)),
+            params![
+                p.descricao, p.valor, p.categoria_id, p.tipo, p.periodicidade,
+                p.dia_vencimento, p.mes_vencimento, p.data_inicio, p.data_fim,
+                p.ativo.unwrap_or(true) as i64, user_id, p.parcelas, p.dia_util.as_deref().unwrap_or("nenhum"),
+            ],
+        )?;
+        auditar_criacao(conn, user_id, "despesas_recorrentes")
+    })
+}
+
+fn update_recorrente(conn: &Connection, user_id: i64, u: &RecorrenteUpdate) -> rusqlite::Result<usize> {
+    let p = &u.fields;
+    auditado(conn, user_id, "despesas_recorrentes", "alterar", &[u.id], || conn.execute(
+        &format!("UPDATE despesas_recorrentes SET
+            descricao = ?1, valor = ?2, categoria_id = {}, tipo = ?4, periodicidade = ?5,
+            dia_vencimento = ?6, mes_vencimento = ?7, data_inicio = ?8, data_fim = ?9, ativo = ?10,
+            parcelas = ?13, dia_util = ?14
+         WHERE id = ?11 AND user_id = ?12 AND deleted_at IS NULL", own_categoria(3, 12)),
+        params![
+            p.descricao, p.valor, p.categoria_id, p.tipo, p.periodicidade,
+            p.dia_vencimento, p.mes_vencimento, p.data_inicio, p.data_fim,
+            p.ativo.unwrap_or(true) as i64, u.id, user_id, p.parcelas, p.dia_util.as_deref().unwrap_or("nenhum"),
+        ],
+    ))
+}
+
+// Deleting a rule sends its still-pending ('previsto') occurrences to the
+// trash with it, stamped with the same time so they come back together, but
+// keeps already-paid ones as historical record.
+fn delete_recorrente(conn: &Connection, user_id: i64, id: i64) -> rusqlite::Result<usize> {
+    let agora = now_secs();
+    em_savepoint(conn, || {
+        let n = auditado(conn, user_id, "despesas_recorrentes", "excluir", &[id], || conn.execute(
+            "UPDATE despesas_recorrentes SET deleted_at = ?1 WHERE id = ?2 AND user_id = ?3 AND deleted_at IS NULL",
+            params![agora, id, user_id],
+        ))?;
+        if n > 0 {
+            let filtro = "recorrente_id = ?1 AND status = 'previsto' AND deleted_at IS NULL";
+            let previstas = ids_where(conn, "despesas", filtro, params![id])?;
+            auditado(conn, user_id, "despesas", "excluir", &previstas, || conn.execute(
+                &format!("UPDATE despesas SET deleted_at = ?2 WHERE {}", filtro),
+                params![id, agora],
+            ))?;
+        }
+        Ok(n)
+    })
 }
 
 
 // ----------------------- Despesas: lançamentos -----------------------
 
+// An active recurring rule, as materialize_recorrentes reads it.
+struct RegraRecorrente {
+    id: i64,
+    valor: Dinheiro,
+    categoria_id: Option<i64>,
+    tipo: String,
+    periodicidade: String,
+    dia_vencimento: i64,
+    mes_vencimento: Option<i64>,
+    data_inicio: String,
+    data_fim: Option<String>,
+    parcelas: Option<i64>,
+    dia_util: String,
+}
+
+// Upper bound on the occurrences of one rule materialized at a time, ~11
+// years of weekly ones.
+const MAX_OCORRENCIAS: usize = 600;
+
+// Dates in FERIADOS_PATH, as written there ("MM-DD" or "YYYY-MM-DD"). A
+// missing file just means only weekends are skipped.
+fn load_feriados() -> HashSet<String> {
+    let Ok(texto) = fs::read_to_string(FERIADOS_PATH) else { return HashSet::new() };
+    texto.lines()
+        .filter_map(|l| l.split('#').next()?.split_whitespace().next())
+        .map(str::to_string)
+        .collect()
+}
+
+fn dia_util(dias: i64, feriados: &HashSet<String>) -> bool {
+    // 1970-01-01 was a Thursday: 0 = Sunday, 6 = Saturday.
+    let semana = (dias + 4).rem_euclid(7);
+    let data = fmt_dias(dias);
+    semana != 0 && semana != 6 && !feriados.contains(&data) && !feriados.contains(&data[5..])
+}
+
+fn ajustar_dia_util(dias: i64, ajuste: &str, feriados: &HashSet<String>) -> i64 {
+    let passo = match ajuste { "proximo" => 1, "anterior" => -1, _ => return dias };
+    let mut d = dias;
+    // Bounded so a file listing every day can't hang the request.
+    for _ in 0..31 {
+        if dia_util(d, feriados) { return d; }
+        d += passo;
+    }
+    dias
+}
+
+// Nominal due dates of a rule from the start of month `desde` to the end of
+// month `ate`, each with its number counted from data_inicio (0 for the
+// first) and the competência it is stored under: "YYYY-MM" for month-based
+// rules (one per month, as always), the nominal date for semanal/quinzenal.
+// Stops at data_fim and after `parcelas` occurrences. Occurrences before
+// `desde` are skipped arithmetically, so old rules still reach the window.
+// An anual rule with no mes_vencimento has no occurrences.
+fn vencimentos(regra: &RegraRecorrente, desde: (i32, u32), ate: (i32, u32)) -> Vec<(i64, String, i64)> {
+    // Occurrence numbers in the window: from the first one not before it to
+    // the last one in it, at most MAX_OCORRENCIAS of them, the latest ones.
+    let janela = |primeiro: i64, ultimo: i64| {
+        let ultimo = regra.parcelas.map_or(ultimo, |n| ultimo.min(n - 1));
+        primeiro.max(ultimo + 1 - MAX_OCORRENCIAS as i64)..=ultimo
+    };
+    let (sy, sm, sd) = parse_ymd(&regra.data_inicio);
+    match regra.periodicidade.as_str() {
+        "semanal" | "quinzenal" => {
+            let passo = if regra.periodicidade == "semanal" { 7 } else { 14 };
+            let inicio = days_from_civil(sy, sm, sd);
+            let mut ultimo_dia = days_from_civil(ate.0, ate.1, days_in_month(ate.0, ate.1));
+            if let Some(df) = regra.data_fim.as_deref() {
+                let (y, m, d) = parse_ymd(df);
+                ultimo_dia = ultimo_dia.min(days_from_civil(y, m, d));
+            }
+            if ultimo_dia < inicio {
+                return Vec::new();
+            }
+            let antes = (days_from_civil(desde.0, desde.1, 1) - inicio).max(0);
+            janela((antes + passo - 1) / passo, (ultimo_dia - inicio) / passo)
+                .map(|k| { let dia = inicio + k * passo; (k, fmt_dias(dia), dia) })
+                .collect()
+        }
+        periodicidade => {
+            let passo = match periodicidade { "trimestral" => 3, "semestral" => 6, "anual" => 12, _ => 1 };
+            let (mut y, mut m) = (sy, sm);
+            if periodicidade == "anual" {
+                let Some(mv) = regra.mes_vencimento else { return Vec::new() };
+                let mv = mv.clamp(1, 12) as u32;
+                if mv < sm { y += 1; }
+                m = mv;
+            }
+            let mes = |(y, m): (i32, u32)| y as i64 * 12 + m as i64 - 1;
+            let inicio = mes((y, m));
+            let ultimo_mes = regra.data_fim.as_deref().map(parse_ym).map_or(mes(ate), |f| mes(ate).min(mes(f)));
+            if ultimo_mes < inicio {
+                return Vec::new();
+            }
+            let antes = (mes(desde) - inicio).max(0);
+            janela((antes + passo - 1) / passo, (ultimo_mes - inicio) / passo)
+                .map(|k| {
+                    let (y, m) = add_months(y, m, (k * passo) as i32);
+                    let dom = (regra.dia_vencimento as u32).clamp(1, days_in_month(y, m));
+                    (k, format!("{:04}-{:02}", y, m), days_from_civil(y, m, dom))
+                })
+                .collect()
+        }
+    }
+}
+
 // Lazily materialize occurrences of every active rule across the [inicio, fim]
 // window. Idempotent via INSERT OR IGNORE on (recorrente_id, competencia).
-fn materialize_recorrentes(conn: &Connection, inicio: &str, fim: &str) -> rusqlite::Result<()> {
+// The window never reaches past the projection horizon, so a far-off fim
+// sent by a client does not fill the table with centuries of previstos.
+fn materialize_recorrentes(conn: &Connection, user_id: i64, inicio: &str, fim: &str) -> rusqlite::Result<()> {
     let (y0, m0) = parse_ym(inicio);
-    let (y1, m1) = parse_ym(fim);
+    let (ano, mes, _) = hoje();
+    let (y1, m1) = parse_ym(fim).min(add_months(ano, mes, PROJECAO_MESES_MAX as i32));
+    if (y0, m0) > (y1, m1) {
+        return Ok(());
+    }
 
     let mut stmt = conn.prepare(
-        "SELECT id, valor, categoria_id, tipo, periodicidade,
-                dia_vencimento, mes_vencimento, data_inicio, data_fim
-         FROM despesas_recorrentes WHERE ativo = 1")?;
-    let rules: Vec<(i64, f64, Option<i64>, String, String, i64, Option<i64>, String, Option<String>)> =
-        stmt.query_map([], |r| Ok((
-            r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?,
-            r.get(5)?, r.get(6)?, r.get(7)?, r.get(8)?,
-        )))?.collect::<rusqlite::Result<Vec<_>>>()?;
-
-    for (id, valor, cat, tipo, periodicidade, dia_venc, mes_venc, data_inicio, data_fim) in rules {
-        let (sy, sm) = parse_ym(&data_inicio);
-        let (mut y, mut m) = (y0, m0);
-        let mut guard = 0;
-        while (y, m) <= (y1, m1) {
-            guard += 1;
-            if guard > 600 { break; }
-
-            let within_start = (y, m) >= (sy, sm);
-            let within_end = match &data_fim {
-                Some(df) => { let (ey, em) = parse_ym(df); (y, m) <= (ey, em) }
-                None => true,
-            };
-            let applies = if periodicidade == "anual" {
-                mes_venc.map_or(false, |mv| mv as u32 == m)
-            } else {
-                true
-            };
-
-            if within_start && within_end && applies {
-                let dom = (dia_venc as u32).clamp(1, days_in_month(y, m));
-                let data = format!("{:04}-{:02}-{:02}", y, m, dom);
-                let comp = format!("{:04}-{:02}", y, m);
-                conn.execute(
-                    "INSERT OR IGNORE INTO despesas
-                        (valor, data, descricao, categoria_id, tipo, status, recorrente_id, competencia, observacoes)
-                     SELECT ?1, ?2, descricao, ?3, ?4, 'previsto', ?5, ?6, NULL
-                     FROM despesas_recorrentes WHERE id = ?5",
-                    params![valor, data, cat, tipo, id, comp],
-                )?;
-            }
+        "SELECT id, valor, categoria_id, tipo, periodicidade, dia_vencimento,
+                mes_vencimento, data_inicio, data_fim, parcelas, dia_util
+         FROM despesas_recorrentes WHERE ativo = 1 AND user_id = ?1 AND deleted_at IS NULL")?;
+    let rules: Vec<RegraRecorrente> =
+        stmt.query_map(params![user_id], |r| Ok(RegraRecorrente {
+            id: r.get(0)?, valor: r.get(1)?, categoria_id: r.get(2)?, tipo: r.get(3)?,
+            periodicidade: r.get(4)?, dia_vencimento: r.get(5)?, mes_vencimento: r.get(6)?,
+            data_inicio: r.get(7)?, data_fim: r.get(8)?, parcelas: r.get(9)?, dia_util: r.get(10)?,
+        }))?.collect::<rusqlite::Result<Vec<_>>>()?;
 
-            if m == 12 { y += 1; m = 1; } else { m += 1; }
+    let feriados = if rules.iter().any(|r| r.dia_util != "nenhum") { load_feriados() } else { HashSet::new() };
+    for regra in &rules {
+        // Moving dates back can pull next month's first occurrence into the window.
+        let ate = if regra.dia_util == "anterior" { add_months(y1, m1, 1) } else { (y1, m1) };
+        for (k, comp, dia) in vencimentos(regra, (y0, m0), ate) {
+            let data = fmt_dias(ajustar_dia_util(dia, &regra.dia_util, &feriados));
+            let parcela = regra.parcelas.map_or(String::new(), |n| format!(" ({}/{})", k + 1, n));
+            conn.execute(
+                "INSERT OR IGNORE INTO despesas
+                    (valor, data, descricao, categoria_id, tipo, status, recorrente_id, competencia, observacoes, user_id)
+                 SELECT ?1, ?2, descricao || ?7, ?3, ?4, 'previsto', ?5, ?6, NULL, user_id
+                 FROM despesas_recorrentes WHERE id = ?5",
+                params![regra.valor, data, regra.categoria_id, regra.tipo, regra.id, comp, parcela],
+            )?;
         }
     }
     Ok(())
 }
 
 fn list_despesas(
+    conn: &Connection,
+    user_id: i64,
     inicio: Option<&str>, fim: Option<&str>,
     categoria_id: Option<i64>, tipo: Option<&str>,
 ) -> rusqlite::Result<Vec<Despesa>> {
-    let conn = open_db()?;
     if let (Some(i), Some(f)) = (inicio, fim) {
-        materialize_recorrentes(&conn, i, f)?;
+        materialize_recorrentes(conn, user_id, i, f)?;
     }
     let lo = inicio.unwrap_or("0001-01-01");
     let hi = fim.unwrap_or("9999-12-31");
@@ -499,9 +1503,9 @@ fn list_despesas(
         "SELECT id, valor, data, descricao, categoria_id, tipo, status,
                 recorrente_id, competencia, observacoes
          FROM despesas
-         WHERE status != 'cancelado' AND data >= ?1 AND data <= ?2
+         WHERE status != 'cancelado' AND deleted_at IS NULL AND data >= ?1 AND data <= ?2 AND user_id = ?3
          ORDER BY data ASC, id ASC")?;
-    let rows = stmt.query_map(params![lo, hi], |r| Ok(Despesa {
+    let rows = stmt.query_map(params![lo, hi, user_id], |r| Ok(Despesa {
         id: r.get(0)?, valor: r.get(1)?, data: r.get(2)?, descricao: r.get(3)?,
         categoria_id: r.get(4)?, tipo: r.get(5)?, status: r.get(6)?,
         recorrente_id: r.get(7)?, competencia: r.get(8)?, observacoes: r.get(9)?,
@@ -512,258 +1516,1648 @@ fn list_despesas(
     Ok(v)
 }
 
-fn insert_despesa(d: &DespesaInput) -> rusqlite::Result<()> {
-    let conn = open_db()?;
+fn insert_despesa(conn: &Connection, user_id: i64, d: &DespesaInput) -> rusqlite::Result<()> {
     let status = d.status.clone().unwrap_or_else(|| "pago".to_string());
-    conn.execute(
-        "INSERT INTO despesas
-            (valor, data, descricao, categoria_id, tipo, status, recorrente_id, competencia, observacoes)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, NULL, NULL, ?7)",
-        params![d.valor, d.data, d.descricao, d.categoria_id, d.tipo, status, d.observacoes],
-    )?;
-    Ok(())
+    em_savepoint(conn, || {
+        conn.execute(
+            &format!("INSERT INTO despesas
+                (valor, data, descricao, categoria_id, tipo, status, recorrente_id, competencia, observacoes, user_id)
+             VALUES (?1, ?2, ?3, {}, ?5, ?6, NULL, NULL, ?7, ?8)", own_categoria(4, 8)),
+            params![d.valor, d.data, d.descricao, d.categoria_id, d.tipo, status, d.observacoes, user_id],
+        )?;
+        auditar_criacao(conn, user_id, "despesas")
+    })
 }
 
-fn update_despesa(u: &DespesaUpdate) -> rusqlite::Result<usize> {
-    let conn = open_db()?;
+fn update_despesa(conn: &Connection, user_id: i64, u: &DespesaUpdate) -> rusqlite::Result<usize> {
     let d = &u.fields;
     let status = d.status.clone().unwrap_or_else(|| "pago".to_string());
-    conn.execute(
-        "UPDATE despesas SET
-            valor = ?1, data = ?2, descricao = ?3, categoria_id = ?4,
+    auditado(conn, user_id, "despesas", "alterar", &[u.id], || conn.execute(
+        &format!("UPDATE despesas SET
+            valor = ?1, data = ?2, descricao = ?3, categoria_id = {},
             tipo = ?5, status = ?6, observacoes = ?7
-         WHERE id = ?8",
-        params![d.valor, d.data, d.descricao, d.categoria_id, d.tipo, status, d.observacoes, u.id],
-    )
+         WHERE id = ?8 AND user_id = ?9 AND deleted_at IS NULL", own_categoria(4, 9)),
+        params![d.valor, d.data, d.descricao, d.categoria_id, d.tipo, status, d.observacoes, u.id, user_id],
+    ))
+}
+
+// Goes to the trash. A trashed occurrence of a recurring rule still holds its
+// (recorrente_id, competencia) slot, so it isn't regenerated.
+fn delete_despesa(conn: &Connection, user_id: i64, id: i64) -> rusqlite::Result<usize> {
+    auditado(conn, user_id, "despesas", "excluir", &[id], || conn.execute(
+        "UPDATE despesas SET deleted_at = ?1 WHERE id = ?2 AND user_id = ?3 AND deleted_at IS NULL",
+        params![now_secs(), id, user_id],
+    ))
 }
 
-// An occurrence of a recurring rule is tombstoned ('cancelado') so it won't be
-// regenerated; a one-off expense is hard-deleted.
-fn delete_despesa(id: i64) -> rusqlite::Result<usize> {
-    let conn = open_db()?;
-    let recorrente_id: Option<i64> = conn
-        .query_row("SELECT recorrente_id FROM despesas WHERE id = ?1", params![id], |r| r.get(0))
-        .optional()?
-        .flatten();
-    if recorrente_id.is_some() {
-        conn.execute("UPDATE despesas SET status = 'cancelado' WHERE id = ?1", params![id])
+
+// ----------------------- Despesas: importação -----------------------
+
+// (date, description, signed amount) read from a statement line.
+type Transacao = (String, String, Dinheiro);
+
+// Statement amounts come as "-1.234,56", "1234.56", "R$ 50,00"... The last
+// '.' or ',' is the decimal separator when it has one or two digits after it.
+fn parse_valor_extrato(texto: &str) -> Option<Dinheiro> {
+    let limpo: String = texto.chars().filter(|c| c.is_ascii_digit() || matches!(c, '-' | '.' | ',')).collect();
+    let negativo = limpo.starts_with('-') || texto.trim().ends_with('D');
+    let digitos = limpo.trim_start_matches('-');
+    let valor = match digitos.rfind(['.', ',']) {
+        Some(i) if digitos.len() - i - 1 <= 2 => {
+            let inteiro: String = digitos[..i].chars().filter(|c| c.is_ascii_digit()).collect();
+            Dinheiro::parse(&format!("{}.{}", inteiro, &digitos[i + 1..]))?
+        },
+        _ => Dinheiro::parse(&digitos.replace(['.', ','], ""))?,
+    };
+    Some(if negativo { Dinheiro(-valor.0.abs()) } else { valor })
+}
+
+// "dd/mm/yyyy", "yyyy-mm-dd" or the OFX "yyyymmdd[hhmmss...]" as "yyyy-mm-dd".
+fn parse_data_extrato(texto: &str) -> Option<String> {
+    let t = texto.trim();
+    let (y, m, d) = if let [d, m, y] = t.split('/').collect::<Vec<_>>()[..] {
+        (y.parse::<i32>().ok()?, m.parse::<u32>().ok()?, d.parse::<u32>().ok()?)
+    } else if t.get(4..5) == Some("-") {
+        (t.get(0..4)?.parse().ok()?, t.get(5..7)?.parse().ok()?, t.get(8..10)?.parse().ok()?)
+    } else if t.get(..8).is_some_and(|p| p.chars().all(|c| c.is_ascii_digit())) {
+        (t[0..4].parse().ok()?, t[4..6].parse().ok()?, t[6..8].parse().ok()?)
     } else {
-        conn.execute("DELETE FROM despesas WHERE id = ?1", params![id])
+        return None;
+    };
+    let y = if y < 100 { 2000 + y } else { y };
+    ((1..=12).contains(&m) && d >= 1 && d <= days_in_month(y, m)).then(|| format!("{:04}-{:02}-{:02}", y, m, d))
+}
+
+fn split_csv_line(line: &str, sep: char) -> Vec<String> {
+    let mut campos = Vec::new();
+    let mut atual = String::new();
+    let mut aspas = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if aspas && chars.peek() == Some(&'"') => { atual.push('"'); chars.next(); },
+            '"' => aspas = !aspas,
+            c if c == sep && !aspas => campos.push(std::mem::take(&mut atual)),
+            c => atual.push(c),
+        }
     }
+    campos.push(atual);
+    campos.into_iter().map(|c| c.trim().to_string()).collect()
 }
 
+fn normaliza(texto: &str) -> String {
+    texto.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphabetic() || c == ' ' { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-// ----------------------------- Routes -----------------------------
+// Bank CSVs differ in separator and headers, so columns are found by name:
+// the date, the description, and either a signed amount or separate
+// debit/credit columns. Returns the transactions and how many lines were
+// skipped.
+fn parse_csv_extrato(conteudo: &str) -> (Vec<(usize, Transacao)>, usize) {
+    let linhas: Vec<(usize, &str)> = conteudo.lines().enumerate()
+        .map(|(i, l)| (i + 1, l.trim_start_matches('\u{feff}')))
+        .filter(|(_, l)| !l.trim().is_empty())
+        .collect();
+    let Some(&(_, cabecalho)) = linhas.first() else { return (Vec::new(), 0) };
+    let sep = [';', '\t', ','].into_iter().max_by_key(|s| cabecalho.matches(*s).count()).unwrap_or(';');
+    let nomes: Vec<String> = split_csv_line(cabecalho, sep).iter().map(|n| normaliza(n)).collect();
+    let coluna = |chaves: &[&str]| nomes.iter().position(|n| chaves.iter().any(|k| n.contains(k)));
+
+    let data = coluna(&["data", "date"]);
+    let descricao = coluna(&["descri", "hist", "lan", "memo", "estabelecimento", "description"]);
+    let valor = coluna(&["valor", "amount", "value", "quantia"]);
+    let debito = coluna(&["debito", "débito", "saida", "saída"]);
+    let credito = coluna(&["credito", "crédito", "entrada"]);
+    let Some(data) = data else { return (Vec::new(), linhas.len()) };
+
+    let mut transacoes = Vec::new();
+    let mut ignoradas = 0;
+    for &(numero, linha) in &linhas[1..] {
+        let campos = split_csv_line(linha, sep);
+        let campo = |i: Option<usize>| i.and_then(|i| campos.get(i)).map(String::as_str).unwrap_or("");
+        let valor = match (valor, debito) {
+            (Some(_), _) => parse_valor_extrato(campo(valor)),
+            (None, Some(_)) if !campo(debito).is_empty() => parse_valor_extrato(campo(debito)).map(|v| Dinheiro(-v.0.abs())),
+            (None, Some(_)) => parse_valor_extrato(campo(credito)).map(|v| Dinheiro(v.0.abs())),
+            (None, None) => None,
+        };
+        match (parse_data_extrato(campo(Some(data))), valor) {
+            (Some(d), Some(v)) => transacoes.push((numero, (d, campo(descricao).to_string(), v))),
+            _ => ignoradas += 1,
+        }
+    }
+    (transacoes, ignoradas)
+}
+
+// OFX is SGML: leaf tags are usually left unclosed, so each value runs until
+// the next '<'.
+fn parse_ofx_extrato(conteudo: &str) -> (Vec<(usize, Transacao)>, usize) {
+    let tag = |bloco: &str, nome: &str| -> Option<String> {
+        let inicio = bloco.find(&format!("<{}>", nome))? + nome.len() + 2;
+        let resto = &bloco[inicio..];
+        Some(resto[..resto.find('<').unwrap_or(resto.len())].trim().to_string())
+    };
+    let mut transacoes = Vec::new();
+    let mut ignoradas = 0;
+    for (i, bloco) in conteudo.split("<STMTTRN>").skip(1).enumerate() {
+        let bloco = bloco.split("</STMTTRN>").next().unwrap_or(bloco);
+        let data = tag(bloco, "DTPOSTED").and_then(|d| parse_data_extrato(&d));
+        let valor = tag(bloco, "TRNAMT").and_then(|v| parse_valor_extrato(&v));
+        let descricao = tag(bloco, "MEMO").filter(|m| !m.is_empty()).or_else(|| tag(bloco, "NAME")).unwrap_or_default();
+        match (data, valor) {
+            (Some(d), Some(v)) => transacoes.push((i + 1, (d, descricao, v))),
+            _ => ignoradas += 1,
+        }
+    }
+    (transacoes, ignoradas)
+}
+
+// Most used (categoria_id, tipo) for past despesas with the same normalized
+// description, or failing that the same first word.
+fn sugestoes_categoria(conn: &Connection, user_id: i64) -> rusqlite::Result<HashMap<String, (Option<i64>, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT descricao, categoria_id, tipo, COUNT(*) AS n FROM despesas
+         WHERE user_id = ?1 AND descricao IS NOT NULL AND status != 'cancelado' AND deleted_at IS NULL
+         GROUP BY descricao, categoria_id, tipo ORDER BY n DESC")?;
+    let mut sugestoes = HashMap::new();
+    let rows = stmt.query_map(params![user_id], |r| Ok((r.get::<_, String>(0)?, r.get::<_, Option<i64>>(1)?, r.get::<_, String>(2)?)))?;
+    for row in rows {
+        let (descricao, categoria, tipo) = row?;
+        let chave = normaliza(&descricao);
+        if let Some(palavra) = chave.split(' ').next().filter(|p| p.len() >= 3) {
+            sugestoes.entry(format!("~{}", palavra)).or_insert((categoria, tipo.clone()));
+        }
+        sugestoes.entry(chave).or_insert((categoria, tipo));
+    }
+    Ok(sugestoes)
+}
 
-#[post("/lucasodon/login")]
-async fn login(form: web::Form<LoginForm>) -> impl Responder {
-    if form.password.trim() == get_password() {
-        let cookie = Cookie::build(COOKIE_NAME, get_password())
-            .path("/lucasodon")
-            .http_only(true)
-            .finish();
-        HttpResponse::SeeOther()
-            .append_header(("Location", "/lucasodon"))
-            .cookie(cookie)
-            .finish()
+// How close (in days) a statement date may be to a recurring occurrence's
+// due date for the payment to settle it.
+const JANELA_PREVISTO_DIAS: i64 = 5;
+
+fn preview_extrato(conn: &Connection, user_id: i64, p: &ImportarPreview) -> rusqlite::Result<PreviewExtrato> {
+    let formato = match p.formato.as_deref() {
+        Some(f) if !f.is_empty() => f.to_lowercase(),
+        _ if p.conteudo.contains("<OFX>") || p.conteudo.contains("<STMTTRN>") => "ofx".to_string(),
+        _ => "csv".to_string(),
+    };
+    let (transacoes, mut ignoradas) = if formato == "ofx" {
+        parse_ofx_extrato(&p.conteudo)
     } else {
-        HttpResponse::SeeOther()
-            .append_header(("Location", "/lucasodon?erro=1"))
-            .finish()
+        parse_csv_extrato(&p.conteudo)
+    };
+
+    // Statements list both directions; only debits are despesas. A file with
+    // no negative amount at all is taken to list debits as positives.
+    let so_debitos = transacoes.iter().any(|(_, (_, _, v))| v.0 < 0);
+    let sugestoes = sugestoes_categoria(conn, user_id)?;
+    let mut duplicada = conn.prepare(
+        "SELECT id FROM despesas
+         WHERE user_id = ?1 AND status = 'pago' AND deleted_at IS NULL AND valor = ?2 AND data = ?3 LIMIT 1")?;
+    let mut prevista = conn.prepare(
+        "SELECT id FROM despesas
+         WHERE user_id = ?1 AND status = 'previsto' AND deleted_at IS NULL AND recorrente_id IS NOT NULL AND valor = ?2
+           AND abs(julianday(data) - julianday(?3)) <= ?4
+         ORDER BY abs(julianday(data) - julianday(?3)) LIMIT 1")?;
+
+    let mut linhas = Vec::new();
+    for (linha, (data, descricao, valor)) in transacoes {
+        if so_debitos && valor.0 >= 0 {
+            ignoradas += 1;
+            continue;
+        }
+        let valor = Dinheiro(valor.0.abs());
+        let chave = normaliza(&descricao);
+        let palavra = format!("~{}", chave.split(' ').next().unwrap_or(""));
+        let (categoria_id, tipo) = sugestoes.get(&chave).or_else(|| sugestoes.get(&palavra))
+            .cloned()
+            .unwrap_or((None, "pessoal".to_string()));
+
+        let (acao, alvo_id) = if let Some(id) = duplicada.query_row(params![user_id, valor, data], |r| r.get(0)).optional()? {
+            ("duplicada", Some(id))
+        } else if let Some(id) = prevista.query_row(params![user_id, valor, data, JANELA_PREVISTO_DIAS], |r| r.get(0)).optional()? {
+            ("pagar_previsto", Some(id))
+        } else {
+            ("importar", None)
+        };
+        linhas.push(LinhaExtrato {
+            linha, data, descricao, valor, categoria_id, tipo,
+            acao: acao.to_string(), alvo_id,
+        });
     }
+    Ok(PreviewExtrato { formato, linhas, ignoradas })
 }
 
-#[get("/lucasodon/logout")]
-async fn logout() -> impl Responder {
-    let mut cookie = Cookie::build(COOKIE_NAME, "")
-        .path("/lucasodon")
-        .http_only(true)
-        .finish();
-    cookie.make_removal();
-    HttpResponse::SeeOther()
-        .append_header(("Location", "/lucasodon"))
-        .cookie(cookie)
-        .finish()
+// Applies the lines the user kept after the preview, all or nothing.
+fn confirmar_extrato(conn: &Connection, user_id: i64, c: &ImportarConfirmar) -> rusqlite::Result<ResultadoImportacao> {
+    let tx = conn.unchecked_transaction()?;
+    let mut resultado = ResultadoImportacao { importadas: 0, pagas: 0 };
+    for l in &c.linhas {
+        match (l.acao.as_str(), l.alvo_id) {
+            ("pagar_previsto", Some(alvo)) => {
+                resultado.pagas += auditado(&tx, user_id, "despesas", "alterar", &[alvo], || tx.execute(
+                    "UPDATE despesas SET status = 'pago', data = ?1, valor = ?2
+                     WHERE id = ?3 AND user_id = ?4 AND status = 'previsto' AND deleted_at IS NULL",
+                    params![l.data, l.valor, alvo, user_id],
+                ))?;
+            },
+            ("importar", _) => {
+                insert_despesa(&tx, user_id, &DespesaInput {
+                    valor: l.valor,
+                    data: l.data.clone(),
+                    descricao: Some(l.descricao.clone()),
+                    categoria_id: l.categoria_id,
+                    tipo: l.tipo.clone(),
+                    status: Some("pago".to_string()),
+                    observacoes: Some("importado do extrato".to_string()),
+                })?;
+                resultado.importadas += 1;
+            },
+            _ => (),
+        }
+    }
+    tx.commit()?;
+    Ok(resultado)
 }
 
-#[get("/lucasodon/api/list")]
-async fn list(req: HttpRequest) -> impl Responder {
-    if !is_authed(&req) {
-        return HttpResponse::Unauthorized().body("nao autorizado");
+
+// ----------------------------- Auditoria -----------------------------
+
+// Every mutator above goes through auditado (or auditar_criacao). The
+// occurrences materialize_recorrentes generates aren't logged: they follow
+// from the rule, whose changes are.
+
+// Tables whose rows are audited, and so may be restored from the log.
+const TABELAS_AUDITADAS: [&str; 4] = ["plantoes", "despesas", "categorias", "despesas_recorrentes"];
+const AUDITORIA_LIMITE_PADRAO: i64 = 50;
+const AUDITORIA_LIMITE_MAX: i64 = 500;
+
+// Savepoints nest, unlike BEGIN, so this works both on its own and inside a
+// caller's transaction (as in confirmar_extrato).
+fn em_savepoint<T>(conn: &Connection, f: impl FnOnce() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    conn.execute_batch("SAVEPOINT auditoria")?;
+    match f() {
+        Ok(v) => {
+            conn.execute_batch("RELEASE auditoria")?;
+            Ok(v)
+        },
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK TO auditoria; RELEASE auditoria");
+            Err(e)
+        },
     }
-    match list_plantoes() {
-        Ok(plantoes) => HttpResponse::Ok().json(plantoes),
+}
+
+fn ids_where(conn: &Connection, table: &str, filtro: &str, p: impl rusqlite::Params) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt = conn.prepare(&format!("SELECT id FROM {} WHERE {}", table, filtro))?;
+    let ids = stmt.query_map(p, |r| r.get(0))?.collect();
+    ids
+}
+
+// The row as a JSON object of its columns, if it exists and is the user's.
+fn linha_json(conn: &Connection, user_id: i64, table: &str, id: i64) -> rusqlite::Result<Option<Value>> {
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {} WHERE id = ?1 AND user_id = ?2", table))?;
+    let nomes: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    stmt.query_row(params![id, user_id], |r| {
+        let mut campos = serde_json::Map::new();
+        for (i, nome) in nomes.iter().enumerate() {
+            let valor = match r.get_ref(i)? {
+                ValueRef::Integer(n) => Value::from(n),
+                ValueRef::Real(f) => Value::from(f),
+                ValueRef::Text(t) => Value::from(String::from_utf8_lossy(t)),
+                ValueRef::Null | ValueRef::Blob(_) => Value::Null,
+            };
+            campos.insert(nome.clone(), valor);
+        }
+        Ok(Value::Object(campos))
+    }).optional()
+}
+
+fn auditar(
+    conn: &Connection, user_id: i64, table: &str, id: i64, acao: &str,
+    antes: Option<Value>, depois: Option<Value>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO auditoria (user_id, tabela, registro_id, acao, antes, depois, criado_em)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![user_id, table, id, acao, antes.map(|v| v.to_string()), depois.map(|v| v.to_string()), now_secs()],
+    )?;
+    Ok(())
+}
+
+// Logs the row the last INSERT on this connection created.
+fn auditar_criacao(conn: &Connection, user_id: i64, table: &str) -> rusqlite::Result<()> {
+    let id = conn.last_insert_rowid();
+    let depois = linha_json(conn, user_id, table, id)?;
+    auditar(conn, user_id, table, id, "criar", None, depois)
+}
+
+// Runs `f`, which changes the rows `ids` of `table`, and logs each row that
+// actually changed. Rows of other users read as absent, so they never are.
+fn auditado<T>(
+    conn: &Connection, user_id: i64, table: &str, acao: &str, ids: &[i64],
+    f: impl FnOnce() -> rusqlite::Result<T>,
+) -> rusqlite::Result<T> {
+    em_savepoint(conn, || {
+        let antes = ids.iter().map(|&id| linha_json(conn, user_id, table, id)).collect::<rusqlite::Result<Vec<_>>>()?;
+        let resultado = f()?;
+        for (&id, antes) in ids.iter().zip(antes) {
+            let depois = linha_json(conn, user_id, table, id)?;
+            if antes != depois {
+                auditar(conn, user_id, table, id, acao, antes, depois)?;
+            }
+        }
+        Ok(resultado)
+    })
+}
+
+fn list_auditoria(conn: &Connection, user_id: i64, q: &AuditoriaQuery) -> rusqlite::Result<Vec<EntradaAuditoria>> {
+    let limite = q.limite.unwrap_or(AUDITORIA_LIMITE_PADRAO).clamp(1, AUDITORIA_LIMITE_MAX);
+    let mut stmt = conn.prepare(
+        "SELECT a.id, a.tabela, a.registro_id, a.acao, u.nome, a.criado_em, a.antes, a.depois
+         FROM auditoria a JOIN usuarios u ON u.id = a.user_id
+         WHERE a.user_id = ?1
+           AND (?2 IS NULL OR a.tabela = ?2)
+           AND (?3 IS NULL OR a.registro_id = ?3)
+           AND (?4 IS NULL OR a.id < ?4)
+         ORDER BY a.id DESC LIMIT ?5")?;
+    let json = |s: Option<String>| s.and_then(|s| serde_json::from_str(&s).ok());
+    let rows = stmt.query_map(params![user_id, q.tabela, q.registro_id, q.antes_de, limite], |r| Ok(EntradaAuditoria {
+        id: r.get(0)?, tabela: r.get(1)?, registro_id: r.get(2)?, acao: r.get(3)?,
+        usuario: r.get(4)?, criado_em: r.get(5)?,
+        antes: json(r.get(6)?), depois: json(r.get(7)?),
+    }))?;
+    rows.collect()
+}
+
+fn json_para_sql(v: &Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value as Sql;
+    match v {
+        Value::Number(n) => n.as_i64().map(Sql::Integer).or_else(|| n.as_f64().map(Sql::Real)).unwrap_or(Sql::Null),
+        Value::String(s) => Sql::Text(s.clone()),
+        Value::Bool(b) => Sql::Integer(*b as i64),
+        _ => Sql::Null,
+    }
+}
+
+// Puts the row back as it was before (or after) the logged change,
+// recreating it if it was deleted since. Columns the table no longer has are
+// skipped; returns false when that version of the row doesn't exist.
+fn restaurar_versao(conn: &Connection, user_id: i64, r: &AuditoriaRestaurar) -> rusqlite::Result<bool> {
+    let entrada: Option<(String, i64, Option<String>, Option<String>)> = conn.query_row(
+        "SELECT tabela, registro_id, antes, depois FROM auditoria WHERE id = ?1 AND user_id = ?2",
+        params![r.id, user_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    ).optional()?;
+    let Some((table, registro_id, antes, depois)) = entrada else { return Ok(false) };
+    let Some(table) = TABELAS_AUDITADAS.into_iter().find(|t| *t == table) else { return Ok(false) };
+    let versao = if r.versao.as_deref() == Some("depois") { depois } else { antes };
+    let Some(Value::Object(campos)) = versao.and_then(|v| serde_json::from_str(&v).ok()) else { return Ok(false) };
+
+    let colunas: Vec<String> = table_columns(conn, table)?
+        .into_iter()
+        .filter(|c| c != "id" && c != "user_id" && campos.contains_key(c))
+        .collect();
+    let sql = format!(
+        "INSERT INTO {t} (id, user_id{cols}) VALUES (?1, ?2{vals})
+         ON CONFLICT(id) DO UPDATE SET {sets} WHERE user_id = ?2",
+        t = table,
+        cols = colunas.iter().map(|c| format!(", {}", c)).collect::<String>(),
+        vals = (0..colunas.len()).map(|i| format!(", ?{}", i + 3)).collect::<String>(),
+        sets = if colunas.is_empty() { "id = id".to_string() } else { colunas.iter().map(|c| format!("{c} = excluded.{c}")).collect::<Vec<_>>().join(", ") },
+    );
+    let valores: Vec<rusqlite::types::Value> = [rusqlite::types::Value::Integer(registro_id), rusqlite::types::Value::Integer(user_id)]
+        .into_iter()
+        .chain(colunas.iter().map(|c| json_para_sql(&campos[c])))
+        .collect();
+    let n = auditado(conn, user_id, table, "restaurar", &[registro_id], || {
+        conn.execute(&sql, rusqlite::params_from_iter(valores))
+    })?;
+    Ok(n > 0)
+}
+
+
+// ----------------------------- Lixeira -----------------------------
+
+const LIXEIRA_RETENCAO_DIAS: i64 = 30;
+const LIXEIRA_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+fn list_lixeira(conn: &Connection, user_id: i64) -> rusqlite::Result<Vec<ItemLixeira>> {
+    let retencao = LIXEIRA_RETENCAO_DIAS * 86_400;
+    let mut stmt = conn.prepare(
+        "SELECT 'plantoes', id, local, data, valor, deleted_at FROM plantoes
+         WHERE user_id = ?1 AND deleted_at IS NOT NULL
+         UNION ALL
+         SELECT 'despesas', id, descricao, data, valor, deleted_at FROM despesas
+         WHERE user_id = ?1 AND deleted_at IS NOT NULL
+           AND NOT (recorrente_id IS NOT NULL AND recorrente_id IN
+                    (SELECT id FROM despesas_recorrentes WHERE deleted_at = despesas.deleted_at))
+         UNION ALL
+         SELECT 'categorias', id, nome, NULL, NULL, deleted_at FROM categorias
+         WHERE user_id = ?1 AND deleted_at IS NOT NULL
+         UNION ALL
+         SELECT 'despesas_recorrentes', id, descricao, data_inicio, valor, deleted_at FROM despesas_recorrentes
+         WHERE user_id = ?1 AND deleted_at IS NOT NULL
+         ORDER BY deleted_at DESC")?;
+    let rows = stmt.query_map(params![user_id], |r| {
+        let excluido_em: i64 = r.get(5)?;
+        Ok(ItemLixeira {
+            tabela: r.get(0)?, id: r.get(1)?, descricao: r.get(2)?, data: r.get(3)?, valor: r.get(4)?,
+            excluido_em, expira_em: excluido_em + retencao,
+        })
+    })?;
+    rows.collect()
+}
+
+// Takes a row out of the trash. A recurring rule brings back the pending
+// occurrences that were trashed along with it.
+fn restaurar_lixeira(conn: &Connection, user_id: i64, item: &ItemRef) -> rusqlite::Result<usize> {
+    let Some(table) = TABELAS_LIXEIRA.into_iter().find(|t| *t == item.tabela) else { return Ok(0) };
+    em_savepoint(conn, || {
+        let excluido_em: Option<i64> = conn.query_row(
+            &format!("SELECT deleted_at FROM {} WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NOT NULL", table),
+            params![item.id, user_id],
+            |r| r.get(0),
+        ).optional()?;
+        let Some(excluido_em) = excluido_em else { return Ok(0) };
+        auditado(conn, user_id, table, "restaurar", &[item.id], || conn.execute(
+            &format!("UPDATE {} SET deleted_at = NULL WHERE id = ?1", table),
+            params![item.id],
+        ))?;
+        if table == "despesas_recorrentes" {
+            let filtro = "recorrente_id = ?1 AND deleted_at = ?2";
+            let previstas = ids_where(conn, "despesas", filtro, params![item.id, excluido_em])?;
+            auditado(conn, user_id, "despesas", "restaurar", &previstas, || conn.execute(
+                &format!("UPDATE despesas SET deleted_at = NULL WHERE {}", filtro),
+                params![item.id, excluido_em],
+            ))?;
+        }
+        if table == "categorias" {
+            devolver_categoria(conn, user_id, item.id)?;
+        }
+        Ok(1)
+    })
+}
+
+// Moves back what delete_categoria reassigned: the 'alterar' entries its
+// savepoint logged right before the category's 'excluir'. Rows whose category
+// changed again since then are left where they are.
+fn devolver_categoria(conn: &Connection, user_id: i64, id: i64) -> rusqlite::Result<()> {
+    let excluir: Option<i64> = conn.query_row(
+        "SELECT MAX(id) FROM auditoria WHERE user_id = ?1 AND tabela = 'categorias' AND registro_id = ?2 AND acao = 'excluir'",
+        params![user_id, id],
+        |r| r.get(0),
+    )?;
+    let Some(excluir) = excluir else { return Ok(()) };
+    let categoria = |json: Option<String>| -> Option<Value> {
+        serde_json::from_str::<Value>(&json?).ok().map(|v| v["categoria_id"].clone())
+    };
+    let mut movidas = Vec::new();
+    let mut stmt = conn.prepare("SELECT tabela, registro_id, acao, antes, depois FROM auditoria WHERE id < ?1 ORDER BY id DESC")?;
+    let mut rows = stmt.query(params![excluir])?;
+    while let Some(r) = rows.next()? {
+        let tabela: String = r.get(0)?;
+        let Some(table) = ["despesas", "despesas_recorrentes"].into_iter().find(|t| *t == tabela) else { break };
+        if r.get::<_, String>(2)? != "alterar" || categoria(r.get(3)?) != Some(Value::from(id)) {
+            break;
+        }
+        movidas.push((table, r.get::<_, i64>(1)?, categoria(r.get(4)?).and_then(|v| v.as_i64())));
+    }
+    for (table, registro, destino) in movidas {
+        auditado(conn, user_id, table, "alterar", &[registro], || conn.execute(
+            &format!("UPDATE {} SET categoria_id = ?1 WHERE id = ?2 AND categoria_id IS ?3", table),
+            params![id, registro, destino],
+        ))?;
+    }
+    Ok(())
+}
+
+// Removes a trashed row for good. A purged occurrence of a rule that still
+// exists is left as a 'cancelado' tombstone so it isn't generated again.
+fn purge_item(conn: &Connection, user_id: i64, table: &str, id: i64) -> rusqlite::Result<usize> {
+    let tombstone = table == "despesas" && conn.query_row(
+        "SELECT recorrente_id IN (SELECT id FROM despesas_recorrentes) FROM despesas WHERE id = ?1",
+        params![id],
+        |r| r.get::<_, Option<bool>>(0),
+    ).optional()?.flatten().unwrap_or(false);
+    auditado(conn, user_id, table, "purgar", &[id], || {
+        if tombstone {
+            conn.execute("UPDATE despesas SET status = 'cancelado', deleted_at = NULL WHERE id = ?1", params![id])
+        } else {
+            conn.execute(&format!("DELETE FROM {} WHERE id = ?1 AND user_id = ?2", table), params![id, user_id])
+        }
+    })
+}
+
+// Purges everything trashed before `limite` (unix seconds), for all users.
+fn purge_lixeira(conn: &Connection, limite: i64) -> rusqlite::Result<usize> {
+    let mut total = 0;
+    for table in TABELAS_LIXEIRA {
+        let mut stmt = conn.prepare(&format!("SELECT id, user_id FROM {} WHERE deleted_at < ?1", table))?;
+        let vencidos = stmt.query_map(params![limite], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (id, user_id) in vencidos {
+            total += purge_item(conn, user_id, table, id)?;
+        }
+    }
+    Ok(total)
+}
+
+pub async fn run_purge(pool: web::Data<DbPool>) {
+    let mut interval = actix_web::rt::time::interval(LIXEIRA_INTERVAL);
+    loop {
+        interval.tick().await;
+        let limite = now_secs() - LIXEIRA_RETENCAO_DIAS * 86_400;
+        if let Err(err) = with_db(&pool, move |conn| purge_lixeira(conn, limite)).await {
+            eprintln!("lucasodon lixeira: {}", err);
+        }
+    }
+}
+
+
+// ----------------------------- Resumo -----------------------------
+
+// A plantão counts in the month it is paid: previsao_pagamento when set,
+// otherwise the day of the shift.
+const DATA_PAGAMENTO_SQL: &str = "COALESCE(NULLIF(previsao_pagamento, ''), data)";
+
+fn percentual(parte: Dinheiro, total: Dinheiro) -> f64 {
+    if total.0 > 0 { parte.0 as f64 * 100.0 / total.0 as f64 } else { 0.0 }
+}
+
+// Totals for the dashboard over [inicio, fim], either end optional. All sums
+// are done by SQLite over integer centavos.
+fn resumo(conn: &Connection, user_id: i64, inicio: Option<&str>, fim: Option<&str>) -> rusqlite::Result<Resumo> {
+    if let (Some(i), Some(f)) = (inicio, fim) {
+        materialize_recorrentes(conn, user_id, i, f)?;
+    }
+    let lo = inicio.unwrap_or("0001-01-01");
+    let hi = fim.unwrap_or("9999-12-31");
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT substr({pg}, 1, 7) AS mes, SUM(valor),
+                SUM(CASE WHEN recebido = 1 THEN valor ELSE 0 END), COUNT(*)
+         FROM plantoes
+         WHERE user_id = ?1 AND deleted_at IS NULL AND {pg} >= ?2 AND {pg} <= ?3
+         GROUP BY mes ORDER BY mes",
+        pg = DATA_PAGAMENTO_SQL,
+    ))?;
+    let meses = stmt.query_map(params![user_id, lo, hi], |r| {
+        let total: Dinheiro = r.get(1)?;
+        let recebido: Dinheiro = r.get(2)?;
+        let pendente = Dinheiro(total.0 - recebido.0);
+        Ok(ResumoMes {
+            mes: r.get(0)?, total, recebido, pendente, plantoes: r.get(3)?,
+            percentual_recebido: percentual(recebido, total),
+        })
+    })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut receitas = ResumoReceitas::default();
+    for m in &meses {
+        receitas.total.0 += m.total.0;
+        receitas.recebido.0 += m.recebido.0;
+        receitas.pendente.0 += m.pendente.0;
+        receitas.plantoes += m.plantoes;
+    }
+
+    let despesas = conn.query_row(
+        "SELECT COALESCE(SUM(valor), 0),
+                COALESCE(SUM(CASE WHEN status = 'pago' THEN valor ELSE 0 END), 0)
+         FROM despesas
+         WHERE user_id = ?1 AND status != 'cancelado' AND deleted_at IS NULL AND data >= ?2 AND data <= ?3",
+        params![user_id, lo, hi],
+        |r| {
+            let total: Dinheiro = r.get(0)?;
+            let pago: Dinheiro = r.get(1)?;
+            Ok(ResumoDespesas { total, pago, previsto: Dinheiro(total.0 - pago.0) })
+        },
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT d.categoria_id, COALESCE(c.nome, 'Sem categoria'), SUM(d.valor) AS total
+         FROM despesas d
+         LEFT JOIN categorias c ON c.id = d.categoria_id AND c.user_id = d.user_id
+         WHERE d.user_id = ?1 AND d.status != 'cancelado' AND d.deleted_at IS NULL AND d.data >= ?2 AND d.data <= ?3
+         GROUP BY d.categoria_id ORDER BY total DESC")?;
+    let por_categoria = stmt.query_map(params![user_id, lo, hi], |r| {
+        let total: Dinheiro = r.get(2)?;
+        Ok(ResumoCategoria {
+            categoria_id: r.get(0)?, nome: r.get(1)?, total,
+            percentual: percentual(total, despesas.total),
+        })
+    })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut stmt = conn.prepare(
+        "SELECT tipo, SUM(valor) AS total
+         FROM despesas
+         WHERE user_id = ?1 AND status != 'cancelado' AND deleted_at IS NULL AND data >= ?2 AND data <= ?3
+         GROUP BY tipo ORDER BY total DESC")?;
+    let por_tipo = stmt.query_map(params![user_id, lo, hi], |r| {
+        let total: Dinheiro = r.get(1)?;
+        Ok(ResumoTipo { tipo: r.get(0)?, total, percentual: percentual(total, despesas.total) })
+    })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(Resumo {
+        inicio: inicio.map(String::from),
+        fim: fim.map(String::from),
+        saldo: Dinheiro(receitas.total.0 - despesas.total.0),
+        saldo_realizado: Dinheiro(receitas.recebido.0 - despesas.pago.0),
+        receitas,
+        receitas_por_mes: meses,
+        despesas,
+        despesas_por_categoria: por_categoria,
+        despesas_por_tipo: por_tipo,
+    })
+}
+
+
+// ----------------------------- Projeção -----------------------------
+
+const PROJECAO_MESES_PADRAO: u32 = 6;
+const PROJECAO_MESES_MAX: u32 = 36;
+
+// Month-by-month forecast: plantões not yet received, by month of payment,
+// against despesas still 'previsto' (recurring ones are materialized for the
+// window first). The running balance starts at saldo_inicial.
+fn projecao(
+    conn: &Connection, user_id: i64,
+    inicio: (i32, u32), meses: u32, saldo_inicial: Dinheiro,
+) -> rusqlite::Result<Projecao> {
+    let (y0, m0) = inicio;
+    let (y1, m1) = add_months(y0, m0, meses as i32 - 1);
+    let lo = format!("{:04}-{:02}-01", y0, m0);
+    let hi = format!("{:04}-{:02}-{:02}", y1, m1, days_in_month(y1, m1));
+    materialize_recorrentes(conn, user_id, &lo, &hi)?;
+
+    let atrasado: Dinheiro = conn.query_row(
+        &format!("SELECT COALESCE(SUM(valor), 0) FROM plantoes
+                  WHERE user_id = ?1 AND recebido = 0 AND deleted_at IS NULL AND {} < ?2", DATA_PAGAMENTO_SQL),
+        params![user_id, lo],
+        |r| r.get(0),
+    )?;
+
+    let mut receitas_stmt = conn.prepare(&format!(
+        "SELECT COALESCE(SUM(valor), 0) FROM plantoes
+         WHERE user_id = ?1 AND recebido = 0 AND deleted_at IS NULL AND substr({}, 1, 7) = ?2", DATA_PAGAMENTO_SQL))?;
+    let mut despesas_stmt = conn.prepare(
+        "SELECT COALESCE(SUM(valor), 0) FROM despesas
+         WHERE user_id = ?1 AND status = 'previsto' AND deleted_at IS NULL AND substr(data, 1, 7) = ?2")?;
+
+    let mut saldo = saldo_inicial;
+    let mut lista = Vec::new();
+    for i in 0..meses {
+        let (y, m) = add_months(y0, m0, i as i32);
+        let mes = format!("{:04}-{:02}", y, m);
+        let receitas: Dinheiro = receitas_stmt.query_row(params![user_id, mes], |r| r.get(0))?;
+        let despesas: Dinheiro = despesas_stmt.query_row(params![user_id, mes], |r| r.get(0))?;
+        let saldo_mes = Dinheiro(receitas.0 - despesas.0);
+        saldo = Dinheiro(saldo.0 + saldo_mes.0);
+        lista.push(ProjecaoMes {
+            mes, receitas, despesas, saldo_mes,
+            saldo_previsto: saldo,
+            deficit: despesas > receitas,
+        });
+    }
+
+    Ok(Projecao {
+        inicio: format!("{:04}-{:02}", y0, m0),
+        saldo_inicial,
+        atrasado,
+        meses_em_deficit: lista.iter().filter(|m| m.deficit).count(),
+        meses: lista,
+    })
+}
+
+
+// ---------------------------- Carnê-Leão ----------------------------
+
+// Month a received plantão counts for: the day it was paid, else the
+// expected one, else the shift date.
+const MES_RECEBIMENTO_SQL: &str =
+    "substr(COALESCE(NULLIF(mes_ano_pagamento, ''), NULLIF(previsao_pagamento, ''), data), 1, 7)";
+
+fn list_tabelas_irpf(conn: &Connection) -> rusqlite::Result<Vec<TabelaIrpf>> {
+    let mut stmt = conn.prepare(
+        "SELECT vigencia, limite, aliquota, deducao FROM irpf_faixas
+         ORDER BY vigencia, limite IS NULL, limite")?;
+    let mut tabelas: Vec<TabelaIrpf> = Vec::new();
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, FaixaIrpf {
+        limite: r.get(1)?, aliquota: r.get(2)?, deducao: r.get(3)?,
+    })))?;
+    for row in rows {
+        let (vigencia, faixa) = row?;
+        match tabelas.last_mut() {
+            Some(t) if t.vigencia == vigencia => t.faixas.push(faixa),
+            _ => tabelas.push(TabelaIrpf { vigencia, faixas: vec![faixa] }),
+        }
+    }
+    Ok(tabelas)
+}
+
+fn save_tabela_irpf(conn: &Connection, t: &TabelaIrpf) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM irpf_faixas WHERE vigencia = ?1", params![t.vigencia])?;
+    for f in &t.faixas {
+        tx.execute(
+            "INSERT INTO irpf_faixas (vigencia, limite, aliquota, deducao) VALUES (?1, ?2, ?3, ?4)",
+            params![t.vigencia, f.limite, f.aliquota, f.deducao],
+        )?;
+    }
+    tx.commit()
+}
+
+// Brackets must go up, with only the last one open-ended.
+fn tabela_irpf_valida(t: &TabelaIrpf) -> bool {
+    let (y, m) = parse_ym(&t.vigencia);
+    let limites: Vec<Option<Dinheiro>> = t.faixas.iter().map(|f| f.limite).collect();
+    t.vigencia.len() == 7 && t.vigencia.as_bytes()[4] == b'-' && y > 0 && (1..=12).contains(&m)
+        && limites.last().is_some_and(|l| l.is_none())
+        && limites[..limites.len() - 1].iter().all(|l| l.is_some())
+        && limites.windows(2).all(|w| w[1].is_none() || w[0] < w[1])
+        && t.faixas.iter().all(|f| (0.0..=100.0).contains(&f.aliquota) && f.deducao.0 >= 0)
+}
+
+// The table in force in `mes`, or the oldest one for months before any.
+fn tabela_do_mes<'a>(tabelas: &'a [TabelaIrpf], mes: &str) -> Option<&'a TabelaIrpf> {
+    tabelas.iter().rev().find(|t| t.vigencia.as_str() <= mes).or(tabelas.first())
+}
+
+// (aliquota, parcela a deduzir, imposto) for a monthly tax base.
+fn imposto_mensal(tabela: &TabelaIrpf, base: Dinheiro) -> (f64, Dinheiro, Dinheiro) {
+    let faixa = tabela.faixas.iter().find(|f| f.limite.is_none_or(|l| base <= l));
+    let Some(faixa) = faixa else { return (0.0, Dinheiro(0), Dinheiro(0)) };
+    let bruto = (base.0 as f64 * faixa.aliquota / 100.0).round() as i64;
+    (faixa.aliquota, faixa.deducao, Dinheiro((bruto - faixa.deducao.0).max(0)))
+}
+
+// Carnê-Leão month by month: received plantões minus paid professional
+// despesas (livro-caixa). Deductions above a month's income carry over to
+// the following months of the same year.
+fn carne_leao(conn: &Connection, user_id: i64, ano: i32) -> rusqlite::Result<CarneLeao> {
+    let tabelas = list_tabelas_irpf(conn)?;
+    let mut rendimentos_stmt = conn.prepare(&format!(
+        "SELECT COALESCE(SUM(valor), 0) FROM plantoes
+         WHERE user_id = ?1 AND recebido = 1 AND deleted_at IS NULL AND {} = ?2", MES_RECEBIMENTO_SQL))?;
+    let mut despesas_stmt = conn.prepare(
+        "SELECT COALESCE(SUM(valor), 0) FROM despesas
+         WHERE user_id = ?1 AND tipo = 'profissional' AND status = 'pago' AND deleted_at IS NULL
+           AND substr(data, 1, 7) = ?2")?;
+
+    let mut meses = Vec::new();
+    let mut excesso = Dinheiro(0);
+    for m in 1..=12 {
+        let mes = format!("{:04}-{:02}", ano, m);
+        let rendimentos: Dinheiro = rendimentos_stmt.query_row(params![user_id, mes], |r| r.get(0))?;
+        let despesas: Dinheiro = despesas_stmt.query_row(params![user_id, mes], |r| r.get(0))?;
+        let deducoes = despesas.0 + excesso.0;
+        let base = Dinheiro((rendimentos.0 - deducoes).max(0));
+        let (aliquota, parcela_deduzir, imposto) = match tabela_do_mes(&tabelas, &mes) {
+            Some(t) => imposto_mensal(t, base),
+            None => (0.0, Dinheiro(0), Dinheiro(0)),
+        };
+        meses.push(CarneLeaoMes {
+            mes, rendimentos, despesas_profissionais: despesas, excesso_anterior: excesso,
+            base_calculo: base, aliquota, parcela_deduzir, imposto,
+        });
+        excesso = Dinheiro((deducoes - rendimentos.0).max(0));
+    }
+
+    let soma = |f: fn(&CarneLeaoMes) -> Dinheiro| Dinheiro(meses.iter().map(|m| f(m).0).sum());
+    let rendimentos = soma(|m| m.rendimentos);
+    let imposto = soma(|m| m.imposto);
+    let anual = CarneLeaoAnual {
+        rendimentos,
+        despesas_profissionais: soma(|m| m.despesas_profissionais),
+        base_calculo: soma(|m| m.base_calculo),
+        imposto,
+        aliquota_efetiva: percentual(imposto, rendimentos),
+        excesso_nao_usado: excesso,
+    };
+    Ok(CarneLeao { ano, meses, anual })
+}
+
+
+// ---------------------------- Exportação ----------------------------
+
+enum Celula {
+    Texto(String),
+    Numero(f64),
+    Valor(Dinheiro),
+    // ISO yyyy-mm-dd
+    Data(String),
+    Vazia,
+}
+
+impl Celula {
+    fn texto(s: Option<&str>) -> Celula {
+        s.filter(|s| !s.is_empty()).map(|s| Celula::Texto(s.to_string())).unwrap_or(Celula::Vazia)
+    }
+
+    fn data(s: Option<&str>) -> Celula {
+        s.filter(|s| !s.is_empty()).map(|s| Celula::Data(s.to_string())).unwrap_or(Celula::Vazia)
+    }
+}
+
+// A table as the UI shows it, plus totals rows at the bottom.
+struct Planilha {
+    nome: &'static str,
+    cabecalho: &'static [&'static str],
+    linhas: Vec<Vec<Celula>>,
+    totais: Vec<Vec<Celula>>,
+}
+
+fn no_periodo(data: &str, inicio: Option<&str>, fim: Option<&str>) -> bool {
+    inicio.is_none_or(|i| data >= i) && fim.is_none_or(|f| data <= f)
+}
+
+fn planilha_plantoes(conn: &Connection, user_id: i64, inicio: Option<&str>, fim: Option<&str>) -> rusqlite::Result<Planilha> {
+    let plantoes: Vec<Plantao> = list_plantoes(conn, user_id)?
+        .into_iter()
+        .filter(|p| no_periodo(&p.data, inicio, fim))
+        .collect();
+    let horas: f64 = plantoes.iter().map(|p| p.duracao_h).sum();
+    let total = Dinheiro(plantoes.iter().map(|p| p.valor.0).sum());
+    let recebido = Dinheiro(plantoes.iter().filter(|p| p.recebido).map(|p| p.valor.0).sum());
+
+    let linhas = plantoes.iter().enumerate().map(|(i, p)| vec![
+        Celula::Numero((i + 1) as f64),
+        Celula::Data(p.data.clone()),
+        Celula::Texto(p.local.clone()),
+        Celula::Texto(p.mfc_pa.clone()),
+        Celula::Numero(p.duracao_h),
+        Celula::Texto(p.periodo.clone()),
+        Celula::Valor(p.valor),
+        Dinheiro::from_reais(p.valor_hora).map_or(Celula::Vazia, Celula::Valor),
+        Celula::data(p.previsao_pagamento.as_deref()),
+        Celula::Texto(if p.recebido { "Sim" } else { "Não" }.to_string()),
+        Celula::data(p.mes_ano_pagamento.as_deref()),
+        Celula::texto(p.observacoes.as_deref()),
+    ]).collect();
+    let total_linha = |rotulo: &str, horas: Celula, valor: Dinheiro, por_hora: Celula| vec![
+        Celula::Vazia, Celula::Texto(rotulo.to_string()), Celula::Vazia, Celula::Vazia,
+        horas, Celula::Vazia, Celula::Valor(valor), por_hora,
+    ];
+    let media = if horas > 0.0 { Dinheiro::from_reais(total.reais() / horas).map_or(Celula::Vazia, Celula::Valor) } else { Celula::Vazia };
+    Ok(Planilha {
+        nome: "Plantões",
+        cabecalho: &["#", "Data", "Local", "MFC/PA", "Duração", "Período", "Valor", "R$/h",
+                     "Previsão", "Recebido", "Dia de pagamento", "Observações"],
+        linhas,
+        totais: vec![
+            total_linha("Total", Celula::Numero(horas), total, media),
+            total_linha("Recebido", Celula::Vazia, recebido, Celula::Vazia),
+            total_linha("A receber", Celula::Vazia, Dinheiro(total.0 - recebido.0), Celula::Vazia),
+        ],
+    })
+}
+
+fn planilha_despesas(conn: &Connection, user_id: i64, inicio: Option<&str>, fim: Option<&str>) -> rusqlite::Result<Planilha> {
+    let nomes: HashMap<i64, String> = list_categorias(conn, user_id)?.into_iter().map(|c| (c.id, c.nome)).collect();
+    let despesas = list_despesas(conn, user_id, inicio, fim, None, None)?;
+    let soma = |f: &dyn Fn(&Despesa) -> bool| Dinheiro(despesas.iter().filter(|d| f(d)).map(|d| d.valor.0).sum());
+
+    let linhas = despesas.iter().map(|d| vec![
+        Celula::Data(d.data.clone()),
+        Celula::texto(d.descricao.as_deref()),
+        Celula::texto(d.categoria_id.and_then(|c| nomes.get(&c)).map(String::as_str)),
+        Celula::Texto(if d.tipo == "profissional" { "Profissional" } else { "Pessoal" }.to_string()),
+        Celula::Valor(d.valor),
+        Celula::Texto(if d.status == "pago" { "Pago" } else { "Previsto" }.to_string()),
+        Celula::texto(d.observacoes.as_deref()),
+    ]).collect();
+    let total_linha = |rotulo: &str, valor: Dinheiro| vec![
+        Celula::Vazia, Celula::Texto(rotulo.to_string()), Celula::Vazia, Celula::Vazia, Celula::Valor(valor),
+    ];
+    Ok(Planilha {
+        nome: "Despesas",
+        cabecalho: &["Data", "Descrição", "Categoria", "Tipo", "Valor", "Status", "Observações"],
+        linhas,
+        totais: vec![
+            total_linha("Total", soma(&|_| true)),
+            total_linha("Pessoal", soma(&|d| d.tipo != "profissional")),
+            total_linha("Profissional", soma(&|d| d.tipo == "profissional")),
+            total_linha("Pago", soma(&|d| d.status == "pago")),
+            total_linha("Previsto", soma(&|d| d.status == "previsto")),
+        ],
+    })
+}
+
+// Written for a Brazilian Excel: ';' separators, decimal commas, dd/mm/yyyy
+// dates and a BOM so the accents open correctly. Text that a spreadsheet
+// would take for a formula gets a leading apostrophe.
+fn planilha_csv(p: &Planilha) -> String {
+    let campo = |c: &Celula| match c {
+        Celula::Texto(t) => {
+            let t = if t.starts_with(['=', '+', '-', '@', '\t', '\r']) { format!("'{}", t) } else { t.clone() };
+            if t.contains([';', '"', '\n', '\r']) { format!("\"{}\"", t.replace('"', "\"\"")) } else { t }
+        }
+        Celula::Numero(n) => n.to_string().replace('.', ","),
+        Celula::Valor(v) => format!("{}{},{:02}", if v.0 < 0 { "-" } else { "" }, v.0.abs() / 100, v.0.abs() % 100),
+        Celula::Data(d) => d.split('-').rev().collect::<Vec<_>>().join("/"),
+        Celula::Vazia => String::new(),
+    };
+    let mut out = String::from("\u{feff}");
+    out.push_str(&p.cabecalho.join(";"));
+    out.push_str("\r\n");
+    for linha in p.linhas.iter().chain(std::iter::once(&Vec::new())).chain(&p.totais) {
+        out.push_str(&linha.iter().map(campo).collect::<Vec<_>>().join(";"));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn planilha_xlsx(p: &Planilha) -> Result<Vec<u8>, XlsxError> {
+    let negrito = Format::new().set_bold();
+    let moeda = Format::new().set_num_format("\"R$\" #,##0.00");
+    let data = Format::new().set_num_format("dd/mm/yyyy");
+    let total_moeda = moeda.clone().set_bold();
+
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name(p.nome)?;
+    for (col, titulo) in p.cabecalho.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, *titulo, &negrito)?;
+        sheet.set_column_width(col as u16, 14)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+
+    let totais_inicio = p.linhas.len() + 2;
+    let linhas = p.linhas.iter().enumerate().map(|(i, l)| (i + 1, l, false))
+        .chain(p.totais.iter().enumerate().map(|(i, l)| (totais_inicio + i, l, true)));
+    for (row, linha, total) in linhas {
+        let row = row as u32;
+        for (col, celula) in linha.iter().enumerate() {
+            let col = col as u16;
+            match celula {
+                Celula::Texto(t) if total => { sheet.write_string_with_format(row, col, t, &negrito)?; },
+                Celula::Texto(t) => { sheet.write_string(row, col, t)?; },
+                Celula::Numero(n) => { sheet.write_number(row, col, *n)?; },
+                Celula::Valor(v) => { sheet.write_number_with_format(row, col, v.reais(), if total { &total_moeda } else { &moeda })?; },
+                Celula::Data(d) => match ExcelDateTime::parse_from_str(d) {
+                    Ok(dt) => { sheet.write_datetime_with_format(row, col, &dt, &data)?; },
+                    Err(_) => { sheet.write_string(row, col, d)?; },
+                },
+                Celula::Vazia => (),
+            }
+        }
+    }
+    workbook.save_to_buffer()
+}
+
+
+// ------------------------------ Backup ------------------------------
+
+const BACKUP_DIR: &str = "bucket/lucasodon_backups";
+const BACKUP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const BACKUP_KEEP_VAR: &str = "LUCASODON_BACKUP_KEEP";
+const BACKUP_KEEP_DEFAULT: usize = 14;
+const RESTORE_LIMIT: usize = 256 * 1024 * 1024;
+
+fn backup_keep() -> usize {
+    std::env::var(BACKUP_KEEP_VAR).ok().and_then(|v| v.parse().ok()).unwrap_or(BACKUP_KEEP_DEFAULT).max(1)
+}
+
+// lucasodon-YYYYMMDD-HHMMSS.db, so sorting by name sorts by age.
+fn backup_path() -> PathBuf {
+    let (y, m, d) = hoje();
+    let t = now_secs().rem_euclid(86_400);
+    Path::new(BACKUP_DIR).join(format!(
+        "lucasodon-{:04}{:02}{:02}-{:02}{:02}{:02}.db", y, m, d, t / 3600, t / 60 % 60, t % 60,
+    ))
+}
+
+fn temp_path(prefixo: &str) -> PathBuf {
+    let sufixo: String = rand::thread_rng().sample_iter(&Alphanumeric).take(12).map(char::from).collect();
+    Path::new(BACKUP_DIR).join(format!("{}-{}.tmp", prefixo, sufixo))
+}
+
+// The online backup API copies a consistent snapshot while other connections
+// keep reading and writing.
+fn snapshot(conn: &Connection, destino: &Path) -> Result<(), DbError> {
+    fs::create_dir_all(BACKUP_DIR)?;
+    conn.backup(DatabaseName::Main, destino, None)?;
+    Ok(())
+}
+
+// Deletes the oldest scheduled snapshots beyond the newest `keep`.
+fn rotate_backups(keep: usize) -> Result<(), DbError> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(BACKUP_DIR)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("lucasodon-") && n.ends_with(".db")))
+        .collect();
+    backups.sort();
+    let excedentes = backups.len().saturating_sub(keep);
+    for antigo in &backups[..excedentes] {
+        fs::remove_file(antigo)?;
+    }
+    Ok(())
+}
+
+fn scheduled_backup(pool: &DbPool) -> Result<(), DbError> {
+    let conn = pool.get()?;
+    snapshot(&conn, &backup_path())?;
+    rotate_backups(backup_keep())
+}
+
+pub async fn run_backups(pool: web::Data<DbPool>) {
+    let mut interval = actix_web::rt::time::interval(BACKUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pool = pool.clone();
+        if let Ok(Err(err)) = web::block(move || scheduled_backup(&pool)).await {
+            eprintln!("lucasodon backup: {}", err);
+        }
+    }
+}
+
+fn backup_bytes(pool: &DbPool) -> Result<Vec<u8>, DbError> {
+    let tmp = temp_path("download");
+    let resultado = snapshot(&*pool.get()?, &tmp).and_then(|_| Ok(fs::read(&tmp)?));
+    let _ = fs::remove_file(&tmp);
+    resultado
+}
+
+// Only files that are intact SQLite databases from this or an older schema
+// version (which are migrated here) and have an administrator get swapped
+// in. The current database is snapshotted first.
+fn restore_from(pool: &DbPool, arquivo: &Path) -> Result<(), DbError> {
+    {
+        let mut candidato = Connection::open(arquivo).map_err(|_| DbError::Invalido("arquivo nao e um banco sqlite"))?;
+        let integridade: String = candidato.query_row("PRAGMA integrity_check", [], |r| r.get(0))
+            .map_err(|_| DbError::Invalido("arquivo nao e um banco sqlite"))?;
+        if integridade != "ok" {
+            return Err(DbError::Invalido("banco corrompido"));
+        }
+        let versao: i64 = candidato.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        if versao < 1 {
+            return Err(DbError::Invalido("arquivo nao e um backup do lucasodon"));
+        }
+        if versao > MIGRATIONS.len() as i64 {
+            return Err(DbError::Invalido("backup de uma versao mais nova"));
+        }
+        migrate(&mut candidato).map_err(|_| DbError::Invalido("esquema do backup incompativel"))?;
+        let admins: i64 = candidato.query_row("SELECT COUNT(*) FROM usuarios WHERE admin = 1", [], |r| r.get(0))?;
+        if admins == 0 {
+            return Err(DbError::Invalido("backup sem administrador"));
+        }
+        // Leaves everything in the main file, which is what gets copied.
+        candidato.execute_batch("PRAGMA journal_mode = DELETE;")?;
+    }
+    let mut conn = pool.get()?;
+    snapshot(&conn, &backup_path())?;
+    conn.restore(DatabaseName::Main, arquivo, None::<fn(rusqlite::backup::Progress)>)?;
+    Ok(())
+}
+
+
+// ----------------------------- Routes -----------------------------
+
+#[post("/lucasodon/login", wrap = "RateLimit::new(\"lucasodon_login\")")]
+async fn login(form: web::Form<LoginForm>, limiter: web::Data<RateLimiter>, pool: web::Data<DbPool>) -> impl Responder {
+    let limit_key = format!("{}:{}", LOGIN_LIMIT_KEY, form.usuario.trim().to_lowercase());
+    if let Err(retry_after) = limiter.check(&limit_key) {
+        return rate_limit::too_many_requests(retry_after);
+    }
+
+    // Argon2 is slow on purpose, so the check runs off the executor too.
+    let form = form.into_inner();
+    let login = with_db(&pool, move |conn| {
+        match find_usuario(conn, &form.usuario)? {
+            Some((user_id, senha_hash)) if verify_senha(form.password.trim(), &senha_hash) => {
+                create_sessao(conn, user_id).map(Some)
+            },
+            _ => Ok(None),
+        }
+    }).await;
+    match login {
+        Ok(Some(token)) => {
+            limiter.record_success(&limit_key);
+            HttpResponse::SeeOther()
+                .append_header(("Location", "/lucasodon"))
+                .cookie(session_cookie(token))
+                .finish()
+        },
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+        Ok(None) => {
+            limiter.record_failure(&limit_key);
+            rate_limit::mark_failure(HttpResponse::SeeOther()
+                .append_header(("Location", "/lucasodon?erro=1"))
+                .finish())
+        },
+    }
+}
+
+#[get("/lucasodon/logout")]
+async fn logout(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    if let Some(token) = session_token(&req) {
+        let _ = with_db(&pool, move |conn| delete_sessao(conn, &token)).await;
+    }
+    let mut cookie = session_cookie(String::new());
+    cookie.make_removal();
+    HttpResponse::SeeOther()
+        .append_header(("Location", "/lucasodon"))
+        .cookie(cookie)
+        .finish()
+}
+
+#[get("/lucasodon/api/list")]
+async fn list(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else {
+        return HttpResponse::Unauthorized().body("nao autorizado");
+    };
+    match with_db(&pool, move |conn| list_plantoes(conn, user)).await {
+        Ok(plantoes) => HttpResponse::Ok().json(plantoes),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/create")]
+async fn create(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<PlantaoInput>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| insert_plantao(conn, user, &input)).await {
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/update")]
+async fn update(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<PlantaoUpdate>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| update_plantao(conn, user, &input)).await {
+        Ok(0) => HttpResponse::NotFound().body("plantao nao encontrado"),
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/delete")]
+async fn delete(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<IdOnly>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| delete_plantao(conn, user, input.id)).await {
+        Ok(0) => HttpResponse::NotFound().body("plantao nao encontrado"),
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+// ---- Categorias ----
+
+#[get("/lucasodon/api/categorias")]
+async fn categorias_list(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    match with_db(&pool, move |conn| list_categorias(conn, user)).await {
+        Ok(v) => HttpResponse::Ok().json(v),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/categorias/create")]
+async fn categoria_create(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<CategoriaInput>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| insert_categoria(conn, user, &input)).await {
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) if nome_em_uso(&e) => HttpResponse::Conflict().body("categoria ja existe"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/categorias/update")]
+async fn categoria_update(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<CategoriaUpdate>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| update_categoria(conn, user, &input)).await {
+        Ok(0) => HttpResponse::NotFound().body("categoria nao encontrada"),
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) if nome_em_uso(&e) => HttpResponse::Conflict().body("categoria ja existe"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/categorias/delete")]
+async fn categoria_delete(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<IdOnly>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| delete_categoria(conn, user, input.id)).await {
+        Ok(0) => HttpResponse::NotFound().body("categoria nao encontrada"),
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+// ---- Recorrentes ----
+
+#[get("/lucasodon/api/recorrentes")]
+async fn recorrentes_list(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    match with_db(&pool, move |conn| list_recorrentes(conn, user)).await {
+        Ok(v) => HttpResponse::Ok().json(v),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/recorrentes/create")]
+async fn recorrente_create(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<RecorrenteInput>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    if !recorrente_valida(&input) {
+        return HttpResponse::BadRequest().body("recorrente invalida");
+    }
+    match with_db(&pool, move |conn| insert_recorrente(conn, user, &input)).await {
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/recorrentes/update")]
+async fn recorrente_update(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<RecorrenteUpdate>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    if !recorrente_valida(&input.fields) {
+        return HttpResponse::BadRequest().body("recorrente invalida");
+    }
+    match with_db(&pool, move |conn| update_recorrente(conn, user, &input)).await {
+        Ok(0) => HttpResponse::NotFound().body("recorrente nao encontrada"),
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/recorrentes/delete")]
+async fn recorrente_delete(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<IdOnly>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| delete_recorrente(conn, user, input.id)).await {
+        Ok(0) => HttpResponse::NotFound().body("recorrente nao encontrada"),
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+// ---- Despesas ----
+
+#[get("/lucasodon/api/despesas")]
+async fn despesas_list(req: HttpRequest, pool: web::Data<DbPool>, q: web::Query<DespesaFilter>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    let tipo = q.tipo.clone().filter(|t| !t.is_empty() && t != "todos");
+    match with_db(&pool, move |conn| list_despesas(conn, user, q.inicio.as_deref(), q.fim.as_deref(), q.categoria_id, tipo.as_deref())).await {
+        Ok(v) => HttpResponse::Ok().json(v),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/despesas/create")]
+async fn despesa_create(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<DespesaInput>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| insert_despesa(conn, user, &input)).await {
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/despesas/update")]
+async fn despesa_update(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<DespesaUpdate>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| update_despesa(conn, user, &input)).await {
+        Ok(0) => HttpResponse::NotFound().body("despesa nao encontrada"),
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/despesas/delete")]
+async fn despesa_delete(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<IdOnly>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| delete_despesa(conn, user, input.id)).await {
+        Ok(0) => HttpResponse::NotFound().body("despesa nao encontrada"),
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+// ---- Importação ----
+
+// Statements can be larger than the default JSON limit, so the body is read
+// as bytes (up to IMPORT_LIMIT) and parsed here.
+const IMPORT_LIMIT: usize = 2 * 1024 * 1024;
+
+async fn read_body(mut payload: web::Payload, limit: usize) -> std::result::Result<web::BytesMut, HttpResponse> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| HttpResponse::BadRequest().body(format!("Erro: {}", e)))?;
+        if body.len() + chunk.len() > limit {
+            return Err(HttpResponse::PayloadTooLarge().body("arquivo muito grande"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(payload: web::Payload) -> std::result::Result<T, HttpResponse> {
+    let body = read_body(payload, IMPORT_LIMIT).await?;
+    serde_json::from_slice(&body).map_err(|e| HttpResponse::BadRequest().body(format!("Erro: {}", e)))
+}
+
+#[post("/lucasodon/api/importar/preview")]
+async fn importar_preview(req: HttpRequest, pool: web::Data<DbPool>, payload: web::Payload) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    let input: ImportarPreview = match read_json(payload).await { Ok(i) => i, Err(resp) => return resp };
+    match with_db(&pool, move |conn| preview_extrato(conn, user, &input)).await {
+        Ok(p) => HttpResponse::Ok().json(p),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[post("/lucasodon/api/importar/confirmar")]
+async fn importar_confirmar(req: HttpRequest, pool: web::Data<DbPool>, payload: web::Payload) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    let input: ImportarConfirmar = match read_json(payload).await { Ok(i) => i, Err(resp) => return resp };
+    match with_db(&pool, move |conn| confirmar_extrato(conn, user, &input)).await {
+        Ok(r) => HttpResponse::Ok().json(r),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+// ---- Resumo ----
+
+#[get("/lucasodon/api/resumo")]
+async fn resumo_get(req: HttpRequest, pool: web::Data<DbPool>, q: web::Query<Periodo>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    let q = q.into_inner();
+    let inicio = q.inicio.filter(|s| !s.is_empty());
+    let fim = q.fim.filter(|s| !s.is_empty());
+    match with_db(&pool, move |conn| resumo(conn, user, inicio.as_deref(), fim.as_deref())).await {
+        Ok(r) => HttpResponse::Ok().json(r),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    }
+}
+
+#[get("/lucasodon/api/projecao")]
+async fn projecao_get(req: HttpRequest, pool: web::Data<DbPool>, q: web::Query<ProjecaoQuery>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    let meses = q.meses.unwrap_or(PROJECAO_MESES_PADRAO).clamp(1, PROJECAO_MESES_MAX);
+    let inicio = match q.inicio.as_deref().filter(|s| !s.is_empty()) {
+        Some(s) => match parse_ym(s) {
+            (y, m) if y > 0 && (1..=12).contains(&m) => (y, m),
+            _ => return HttpResponse::BadRequest().body("inicio invalido"),
+        },
+        None => { let (y, m, _) = hoje(); (y, m) },
+    };
+    let saldo_inicial = q.saldo_inicial.unwrap_or_default();
+    match with_db(&pool, move |conn| projecao(conn, user, inicio, meses, saldo_inicial)).await {
+        Ok(p) => HttpResponse::Ok().json(p),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/create")]
-async fn create(req: HttpRequest, input: web::Json<PlantaoInput>) -> impl Responder {
-    if !is_authed(&req) {
-        return HttpResponse::Unauthorized().body("nao autorizado");
-    }
-    match insert_plantao(&input) {
-        Ok(_) => HttpResponse::Ok().body("ok"),
+// ---- Carnê-Leão ----
+
+#[get("/lucasodon/api/carne_leao")]
+async fn carne_leao_get(req: HttpRequest, pool: web::Data<DbPool>, q: web::Query<CarneLeaoQuery>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    let ano = q.ano.unwrap_or_else(|| hoje().0);
+    match with_db(&pool, move |conn| carne_leao(conn, user, ano)).await {
+        Ok(c) => HttpResponse::Ok().json(c),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/update")]
-async fn update(req: HttpRequest, input: web::Json<PlantaoUpdate>) -> impl Responder {
-    if !is_authed(&req) {
-        return HttpResponse::Unauthorized().body("nao autorizado");
-    }
-    match update_plantao(&input) {
-        Ok(0) => HttpResponse::NotFound().body("plantao nao encontrado"),
-        Ok(_) => HttpResponse::Ok().body("ok"),
+#[get("/lucasodon/api/irpf/tabela")]
+async fn irpf_tabelas(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    if current_user(&req, &pool).await.is_none() { return HttpResponse::Unauthorized().body("nao autorizado"); }
+    match with_db(&pool, list_tabelas_irpf).await {
+        Ok(t) => HttpResponse::Ok().json(t),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/delete")]
-async fn delete(req: HttpRequest, input: web::Json<IdOnly>) -> impl Responder {
-    if !is_authed(&req) {
-        return HttpResponse::Unauthorized().body("nao autorizado");
+// Replaces the table taking effect in `vigencia`. The tables are shared by
+// every account, so only administrators may change them.
+#[post("/lucasodon/api/irpf/tabela")]
+async fn irpf_tabela_save(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<TabelaIrpf>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    if let Err(resp) = require_admin(&pool, user).await { return resp; }
+    if !tabela_irpf_valida(&input) {
+        return HttpResponse::BadRequest().body("tabela invalida");
     }
-    match delete_plantao(input.id) {
-        Ok(0) => HttpResponse::NotFound().body("plantao nao encontrado"),
+    match with_db(&pool, move |conn| save_tabela_irpf(conn, &input)).await {
         Ok(_) => HttpResponse::Ok().body("ok"),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-// ---- Categorias ----
+// ---- Exportação ----
 
-#[get("/lucasodon/api/categorias")]
-async fn categorias_list(req: HttpRequest) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match list_categorias() {
-        Ok(v) => HttpResponse::Ok().json(v),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+async fn exportar(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    q: ExportarQuery,
+    tabela: fn(&Connection, i64, Option<&str>, Option<&str>) -> rusqlite::Result<Planilha>,
+) -> HttpResponse {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    let inicio = q.inicio.filter(|s| !s.is_empty());
+    let fim = q.fim.filter(|s| !s.is_empty());
+    if !inicio.iter().chain(&fim).all(|d| data_valida(d)) {
+        return HttpResponse::BadRequest().body("periodo invalido");
     }
+    let xlsx = match q.formato.as_deref() {
+        None | Some("") | Some("csv") => false,
+        Some("xlsx") => true,
+        Some(_) => return HttpResponse::BadRequest().body("formato invalido"),
+    };
+    let (i, f) = (inicio.clone(), fim.clone());
+    let planilha = match with_db(&pool, move |conn| tabela(conn, user, i.as_deref(), f.as_deref())).await {
+        Ok(p) => p,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+    };
+
+    let periodo = match (&inicio, &fim) {
+        (None, None) => String::new(),
+        (i, f) => format!("-{}_{}", i.as_deref().unwrap_or("inicio"), f.as_deref().unwrap_or("hoje")),
+    };
+    let arquivo = format!("lucasodon-{}{}", planilha.nome.to_lowercase().replace('õ', "o"), periodo);
+    let (corpo, tipo, extensao) = if xlsx {
+        match planilha_xlsx(&planilha) {
+            Ok(b) => (b, "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx"),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+        }
+    } else {
+        (planilha_csv(&planilha).into_bytes(), "text/csv; charset=utf-8", "csv")
+    };
+    HttpResponse::Ok()
+        .content_type(tipo)
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}.{}\"", arquivo, extensao)))
+        .body(corpo)
 }
 
-#[post("/lucasodon/api/categorias/create")]
-async fn categoria_create(req: HttpRequest, input: web::Json<CategoriaInput>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match insert_categoria(&input) {
-        Ok(_) => HttpResponse::Ok().body("ok"),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
-    }
+#[get("/lucasodon/api/exportar/plantoes")]
+async fn exportar_plantoes(req: HttpRequest, pool: web::Data<DbPool>, q: web::Query<ExportarQuery>) -> impl Responder {
+    exportar(req, pool, q.into_inner(), planilha_plantoes).await
 }
 
-#[post("/lucasodon/api/categorias/update")]
-async fn categoria_update(req: HttpRequest, input: web::Json<CategoriaUpdate>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match update_categoria(&input) {
-        Ok(0) => HttpResponse::NotFound().body("categoria nao encontrada"),
-        Ok(_) => HttpResponse::Ok().body("ok"),
+#[get("/lucasodon/api/exportar/despesas")]
+async fn exportar_despesas(req: HttpRequest, pool: web::Data<DbPool>, q: web::Query<ExportarQuery>) -> impl Responder {
+    exportar(req, pool, q.into_inner(), planilha_despesas).await
+}
+
+// ---- Auditoria ----
+
+#[get("/lucasodon/api/auditoria")]
+async fn auditoria_list(req: HttpRequest, pool: web::Data<DbPool>, q: web::Query<AuditoriaQuery>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    match with_db(&pool, move |conn| list_auditoria(conn, user, &q)).await {
+        Ok(v) => HttpResponse::Ok().json(v),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/categorias/delete")]
-async fn categoria_delete(req: HttpRequest, input: web::Json<IdOnly>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match delete_categoria(input.id) {
-        Ok(0) => HttpResponse::NotFound().body("categoria nao encontrada"),
-        Ok(_) => HttpResponse::Ok().body("ok"),
+#[post("/lucasodon/api/auditoria/restaurar")]
+async fn auditoria_restaurar(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<AuditoriaRestaurar>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| restaurar_versao(conn, user, &input)).await {
+        Ok(true) => HttpResponse::Ok().body("ok"),
+        Ok(false) => HttpResponse::BadRequest().body("versao indisponivel"),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-// ---- Recorrentes ----
+// ---- Lixeira ----
 
-#[get("/lucasodon/api/recorrentes")]
-async fn recorrentes_list(req: HttpRequest) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match list_recorrentes() {
+#[get("/lucasodon/api/lixeira")]
+async fn lixeira_list(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    match with_db(&pool, move |conn| list_lixeira(conn, user)).await {
         Ok(v) => HttpResponse::Ok().json(v),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/recorrentes/create")]
-async fn recorrente_create(req: HttpRequest, input: web::Json<RecorrenteInput>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match insert_recorrente(&input) {
+// Undo for any delete: the UI calls it from the "Desfazer" after deleting and
+// from the trash view.
+#[post("/lucasodon/api/lixeira/restaurar")]
+async fn lixeira_restaurar(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<ItemRef>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| restaurar_lixeira(conn, user, &input)).await {
+        Ok(0) => HttpResponse::NotFound().body("item nao esta na lixeira"),
         Ok(_) => HttpResponse::Ok().body("ok"),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/recorrentes/update")]
-async fn recorrente_update(req: HttpRequest, input: web::Json<RecorrenteUpdate>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match update_recorrente(&input) {
-        Ok(0) => HttpResponse::NotFound().body("recorrente nao encontrada"),
-        Ok(_) => HttpResponse::Ok().body("ok"),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+// ---- Backup ----
+
+// The database holds every account, so only administrators may download or
+// replace it.
+#[get("/lucasodon/api/backup")]
+async fn backup_download(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    if let Err(resp) = require_admin(&pool, user).await { return resp; }
+    let db = pool.clone();
+    match web::block(move || -> Result<Vec<u8>, DbError> { backup_bytes(&db) }).await {
+        Ok(Ok(bytes)) => HttpResponse::Ok()
+            .content_type("application/vnd.sqlite3")
+            .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"",
+                backup_path().file_name().and_then(|n| n.to_str()).unwrap_or("lucasodon.db"))))
+            .body(bytes),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+        Err(_) => HttpResponse::InternalServerError().body(format!("Erro: {}", DbError::Blocking)),
     }
 }
 
-#[post("/lucasodon/api/recorrentes/delete")]
-async fn recorrente_delete(req: HttpRequest, input: web::Json<IdOnly>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match delete_recorrente(input.id) {
-        Ok(0) => HttpResponse::NotFound().body("recorrente nao encontrada"),
-        Ok(_) => HttpResponse::Ok().body("ok"),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+#[post("/lucasodon/api/backup/restaurar")]
+async fn backup_restore(req: HttpRequest, pool: web::Data<DbPool>, payload: web::Payload) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    if let Err(resp) = require_admin(&pool, user).await { return resp; }
+    let body = match read_body(payload, RESTORE_LIMIT).await { Ok(b) => b, Err(resp) => return resp };
+    let db = pool.clone();
+    let resultado = web::block(move || -> Result<(), DbError> {
+        fs::create_dir_all(BACKUP_DIR)?;
+        let tmp = temp_path("restore");
+        fs::write(&tmp, &body)?;
+        let resultado = restore_from(&db, &tmp);
+        let _ = fs::remove_file(&tmp);
+        resultado
+    }).await;
+    match resultado {
+        Ok(Ok(())) => HttpResponse::Ok().body("ok"),
+        Ok(Err(e @ DbError::Invalido(_))) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
+        Err(_) => HttpResponse::InternalServerError().body(format!("Erro: {}", DbError::Blocking)),
     }
 }
 
-// ---- Despesas ----
+// ---- Conta ----
 
-#[get("/lucasodon/api/despesas")]
-async fn despesas_list(req: HttpRequest, q: web::Query<DespesaFilter>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    let tipo = q.tipo.clone().filter(|t| !t.is_empty() && t != "todos");
-    match list_despesas(q.inicio.as_deref(), q.fim.as_deref(), q.categoria_id, tipo.as_deref()) {
-        Ok(v) => HttpResponse::Ok().json(v),
+#[get("/lucasodon/api/conta")]
+async fn conta(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    let Some(user) = current_user(&req, &pool).await else { return HttpResponse::Unauthorized().body("nao autorizado"); };
+    match with_db(&pool, move |conn| get_conta(conn, user)).await {
+        Ok(c) => HttpResponse::Ok().json(c),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/despesas/create")]
-async fn despesa_create(req: HttpRequest, input: web::Json<DespesaInput>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match insert_despesa(&input) {
-        Ok(_) => HttpResponse::Ok().body("ok"),
+#[post("/lucasodon/api/conta/senha")]
+async fn conta_senha(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<SenhaUpdate>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    if input.nova.trim().is_empty() {
+        return HttpResponse::BadRequest().body("senha vazia");
+    }
+    let sessao = session_token(&req).unwrap_or_default();
+    match with_db(&pool, move |conn| change_senha(conn, user, &sessao, &input)).await {
+        Ok(true) => HttpResponse::Ok().body("ok"),
+        Ok(false) => HttpResponse::Forbidden().body("senha atual incorreta"),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/despesas/update")]
-async fn despesa_update(req: HttpRequest, input: web::Json<DespesaUpdate>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match update_despesa(&input) {
-        Ok(0) => HttpResponse::NotFound().body("despesa nao encontrada"),
-        Ok(_) => HttpResponse::Ok().body("ok"),
+// Logs the user out everywhere, including this browser.
+#[post("/lucasodon/api/conta/encerrar_sessoes")]
+async fn conta_encerrar_sessoes(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    match with_db(&pool, move |conn| delete_sessoes(conn, user, None)).await {
+        Ok(_) => {
+            let mut cookie = session_cookie(String::new());
+            cookie.make_removal();
+            HttpResponse::Ok().cookie(cookie).body("ok")
+        },
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
-#[post("/lucasodon/api/despesas/delete")]
-async fn despesa_delete(req: HttpRequest, input: web::Json<IdOnly>) -> impl Responder {
-    if !is_authed(&req) { return HttpResponse::Unauthorized().body("nao autorizado"); }
-    match delete_despesa(input.id) {
-        Ok(0) => HttpResponse::NotFound().body("despesa nao encontrada"),
+async fn require_admin(pool: &web::Data<DbPool>, user: i64) -> std::result::Result<(), HttpResponse> {
+    match with_db(pool, move |conn| get_conta(conn, user)).await {
+        Ok(c) if c.admin => Ok(()),
+        Ok(_) => Err(HttpResponse::Forbidden().body("apenas administradores")),
+        Err(e) => Err(HttpResponse::InternalServerError().body(format!("Erro: {}", e))),
+    }
+}
+
+#[post("/lucasodon/api/usuarios/create")]
+async fn usuario_create(req: HttpRequest, pool: web::Data<DbPool>, input: web::Json<UsuarioInput>) -> impl Responder {
+    let user = match mutating_user(&req, &pool).await { Ok(u) => u, Err(resp) => return resp };
+    if let Err(resp) = require_admin(&pool, user).await { return resp; }
+    if input.nome.trim().is_empty() || input.senha.trim().is_empty() {
+        return HttpResponse::BadRequest().body("nome e senha sao obrigatorios");
+    }
+    match with_db(&pool, move |conn| create_usuario(conn, &input)).await {
         Ok(_) => HttpResponse::Ok().body("ok"),
         Err(e) => HttpResponse::InternalServerError().body(format!("Erro: {}", e)),
     }
 }
 
 #[get("/lucasodon")]
-pub async fn render(req: HttpRequest) -> Result<HttpResponse> {
-    if !is_authed(&req) {
+pub async fn render(req: HttpRequest, pool: web::Data<DbPool>) -> Result<HttpResponse> {
+    let Some((_, csrf_token)) = current_session(&req, &pool).await else {
         return Ok(HttpResponse::Ok()
             .content_type("text/html")
             .body(login_page()));
-    }
+    };
 
     Ok(HttpResponse::Ok()
         .content_type("text/html")
-        .body(app_page()))
+        .body(app_page(&csrf_token)))
 }
 
 
@@ -784,8 +3178,10 @@ fn login_page() -> String {
                 <div class=\"content lucasodon-login\">
                     <h1 class=\"lucasodon-title\">Controle de Plant&otilde;es</h1>
                     <form class=\"login-card\" method=\"POST\" action=\"/lucasodon/login\">
+                        <label for=\"usuario\">Usu&aacute;rio</label>
+                        <input type=\"text\" id=\"usuario\" name=\"usuario\" autocomplete=\"username\" autofocus required>
                         <label for=\"password\">Senha</label>
-                        <input type=\"password\" id=\"password\" name=\"password\" autofocus required>
+                        <input type=\"password\" id=\"password\" name=\"password\" autocomplete=\"current-password\" required>
                         <button type=\"submit\">Entrar</button>
                     </form>
                 </div>
@@ -794,11 +3190,12 @@ fn login_page() -> String {
     ", navbar())
 }
 
-fn app_page() -> String {
+fn app_page(csrf_token: &str) -> String {
     format!("
         <html lang=\"pt-br\">
             <head>
                 <meta charset=\"utf-8\" />
+                <meta name=\"csrf-token\" content=\"{csrf_token}\" />
                 <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\" />
                 <link type=\"text/css\" rel=\"stylesheet\" href=\"/static/css/index.css\">
                 <link href=\"https://fonts.googleapis.com/css2?family=Open+Sans:wght@300;400;600&family=Reenie+Beanie&display=swap\" rel=\"stylesheet\">
@@ -809,12 +3206,20 @@ fn app_page() -> String {
                 <div class=\"content lucasodon-app\">
                     <div class=\"lucasodon-header\">
                         <h1 class=\"lucasodon-title\">Controle Financeiro</h1>
-                        <a class=\"logout-link\" href=\"/lucasodon/logout\">Sair</a>
+                        <div class=\"header-actions\">
+                            <button id=\"open-lixeira-btn\" class=\"logout-link\" type=\"button\">Lixeira</button>
+                            <button id=\"open-historico-btn\" class=\"logout-link\" type=\"button\">Hist&oacute;rico</button>
+                            <button id=\"open-exportar-btn\" class=\"logout-link\" type=\"button\">Exportar</button>
+                            <button id=\"open-conta-btn\" class=\"logout-link\" type=\"button\">Conta</button>
+                            <a class=\"logout-link\" href=\"/lucasodon/logout\">Sair</a>
+                        </div>
                     </div>
 
                     <div class=\"main-tabs\">
                         <button id=\"maintab-plantoes\" class=\"main-tab active\" type=\"button\">Plant&otilde;es</button>
                         <button id=\"maintab-despesas\" class=\"main-tab\" type=\"button\">Despesas</button>
+                        <button id=\"maintab-projecao\" class=\"main-tab\" type=\"button\">Proje&ccedil;&atilde;o</button>
+                        <button id=\"maintab-carne\" class=\"main-tab\" type=\"button\">Carn&ecirc;-Le&atilde;o</button>
                     </div>
 
                     <div id=\"section-plantoes\">
@@ -898,6 +3303,7 @@ fn app_page() -> String {
                             <div class=\"toolbar-actions\">
                                 <button id=\"open-despesa-btn\" class=\"open-form-btn\">+ Nova despesa</button>
                                 <button id=\"open-categorias-btn\" class=\"edit\">Categorias</button>
+                                <button id=\"open-importar-btn\" class=\"edit\">Importar extrato</button>
                             </div>
                         </div>
 
@@ -929,6 +3335,55 @@ fn app_page() -> String {
                         </div>
                     </div>
 
+                    <div id=\"section-projecao\" style=\"display: none;\">
+                        <div id=\"projecao-totais\" class=\"totais\"></div>
+
+                        <div class=\"despesas-toolbar\">
+                            <div class=\"filtros\">
+                                <div class=\"field\"><label>In&iacute;cio</label><input type=\"month\" id=\"p-inicio\"></div>
+                                <div class=\"field\"><label>Meses</label>
+                                    <select id=\"p-meses\"><option>3</option><option selected>6</option><option>12</option><option>24</option></select>
+                                </div>
+                                <div class=\"field\"><label>Saldo inicial (R$)</label><input type=\"number\" step=\"0.01\" id=\"p-saldo\" placeholder=\"0\"></div>
+                            </div>
+                        </div>
+
+                        <div id=\"projecao-msg\" class=\"msg\"></div>
+
+                        <div class=\"table-wrap\">
+                            <table id=\"projecao-table\">
+                                <thead><tr>
+                                    <th>M&ecirc;s</th><th>A receber</th><th>Despesas previstas</th>
+                                    <th>Saldo do m&ecirc;s</th><th>Saldo previsto</th>
+                                </tr></thead>
+                                <tbody id=\"projecao-body\"></tbody>
+                            </table>
+                        </div>
+                    </div>
+
+                    <div id=\"section-carne\" style=\"display: none;\">
+                        <div id=\"carne-totais\" class=\"totais\"></div>
+
+                        <div class=\"despesas-toolbar\">
+                            <div class=\"filtros\">
+                                <div class=\"field\"><label>Ano</label><input type=\"number\" id=\"c-ano\" min=\"2000\" max=\"2100\"></div>
+                            </div>
+                        </div>
+
+                        <div id=\"carne-msg\" class=\"msg\"></div>
+
+                        <div class=\"table-wrap\">
+                            <table id=\"carne-table\">
+                                <thead><tr>
+                                    <th>M&ecirc;s</th><th>Rendimentos</th><th>Despesas dedut&iacute;veis</th>
+                                    <th>Saldo anterior</th><th>Base de c&aacute;lculo</th><th>Al&iacute;quota</th>
+                                    <th>Parcela a deduzir</th><th>Imposto</th>
+                                </tr></thead>
+                                <tbody id=\"carne-body\"></tbody>
+                            </table>
+                        </div>
+                    </div>
+
                     <div id=\"despesa-modal\" class=\"modal-overlay\">
                         <div class=\"modal\">
                             <div class=\"modal-head\">
@@ -963,8 +3418,12 @@ fn app_page() -> String {
                                 <div class=\"field\"><label>Valor (R$)</label><input type=\"number\" step=\"0.01\" id=\"rx-valor\"></div>
                                 <div class=\"field\"><label>Categoria</label><select id=\"rx-categoria\"></select></div>
                                 <div class=\"field\"><label>Tipo</label><select id=\"rx-tipo\"><option value=\"pessoal\">Pessoal</option><option value=\"profissional\">Profissional</option></select></div>
-                                <div class=\"field\"><label>Periodicidade</label><select id=\"rx-periodicidade\"><option value=\"mensal\">Mensal</option><option value=\"anual\">Anual</option></select></div>
-                                <div class=\"field\"><label>Dia de vencimento</label><input type=\"number\" min=\"1\" max=\"31\" id=\"rx-dia\" placeholder=\"5\"></div>
+                                <div class=\"field\"><label>Periodicidade</label><select id=\"rx-periodicidade\">
+                                    <option value=\"semanal\">Semanal</option><option value=\"quinzenal\">Quinzenal</option>
+                                    <option value=\"mensal\" selected>Mensal</option><option value=\"trimestral\">Trimestral</option>
+                                    <option value=\"semestral\">Semestral</option><option value=\"anual\">Anual</option>
+                                </select></div>
+                                <div class=\"field\" id=\"rx-dia-field\"><label>Dia de vencimento</label><input type=\"number\" min=\"1\" max=\"31\" id=\"rx-dia\" placeholder=\"5\"></div>
                                 <div class=\"field\" id=\"rx-mes-field\" style=\"display: none;\"><label>M&ecirc;s (anual)</label>
                                     <select id=\"rx-mes\">
                                         <option value=\"1\">Janeiro</option><option value=\"2\">Fevereiro</option>
@@ -977,6 +3436,10 @@ fn app_page() -> String {
                                 </div>
                                 <div class=\"field\"><label>In&iacute;cio</label><input type=\"date\" id=\"rx-inicio\"></div>
                                 <div class=\"field\"><label>Fim (opcional)</label><input type=\"date\" id=\"rx-fim\"></div>
+                                <div class=\"field\"><label>Parcelas (opcional)</label><input type=\"number\" min=\"1\" max=\"600\" id=\"rx-parcelas\" placeholder=\"sem fim\"></div>
+                                <div class=\"field\"><label>Fim de semana/feriado</label>
+                                    <select id=\"rx-dia-util\"><option value=\"nenhum\">Manter a data</option><option value=\"proximo\">Pr&oacute;ximo dia &uacute;til</option><option value=\"anterior\">Dia &uacute;til anterior</option></select>
+                                </div>
                                 <div class=\"field checkbox\"><label>Ativo</label><input type=\"checkbox\" id=\"rx-ativo\" checked></div>
                             </div>
                             <div class=\"modal-actions\">
@@ -986,6 +3449,135 @@ fn app_page() -> String {
                         </div>
                     </div>
 
+                    <div id=\"importar-modal\" class=\"modal-overlay\">
+                        <div class=\"modal\">
+                            <div class=\"modal-head\">
+                                <h2>Importar extrato</h2>
+                                <button id=\"close-importar-btn\" class=\"modal-close\" aria-label=\"Fechar\">&times;</button>
+                            </div>
+                            <div class=\"form-card\">
+                                <div class=\"field wide\"><label>Arquivo (CSV ou OFX)</label><input type=\"file\" id=\"ix-arquivo\" accept=\".csv,.ofx,.txt\"></div>
+                            </div>
+                            <div class=\"modal-actions\">
+                                <button id=\"preview-importar-btn\">Pr&eacute;-visualizar</button>
+                            </div>
+                            <div id=\"importar-preview\" class=\"table-wrap\" style=\"display: none;\">
+                                <table>
+                                    <thead><tr>
+                                        <th></th><th>Data</th><th>Descri&ccedil;&atilde;o</th><th>Valor</th>
+                                        <th>Categoria</th><th>Tipo</th><th>Situa&ccedil;&atilde;o</th>
+                                    </tr></thead>
+                                    <tbody id=\"importar-body\"></tbody>
+                                </table>
+                            </div>
+                            <div class=\"modal-actions\">
+                                <button id=\"confirm-importar-btn\" style=\"display: none;\">Importar selecionadas</button>
+                            </div>
+                            <div id=\"importar-msg\" class=\"msg\"></div>
+                        </div>
+                    </div>
+
+                    <div id=\"lixeira-modal\" class=\"modal-overlay\">
+                        <div class=\"modal\">
+                            <div class=\"modal-head\">
+                                <h2>Lixeira</h2>
+                                <button id=\"close-lixeira-btn\" class=\"modal-close\" aria-label=\"Fechar\">&times;</button>
+                            </div>
+                            <div class=\"table-wrap\">
+                                <table>
+                                    <thead><tr><th>Tipo</th><th>Descri&ccedil;&atilde;o</th><th>Data</th><th>Valor</th><th>Exclu&iacute;do em</th><th></th></tr></thead>
+                                    <tbody id=\"lixeira-body\"></tbody>
+                                </table>
+                            </div>
+                            <div id=\"lixeira-msg\" class=\"msg\"></div>
+                        </div>
+                    </div>
+
+                    <div id=\"historico-modal\" class=\"modal-overlay\">
+                        <div class=\"modal\">
+                            <div class=\"modal-head\">
+                                <h2>Hist&oacute;rico de altera&ccedil;&otilde;es</h2>
+                                <button id=\"close-historico-btn\" class=\"modal-close\" aria-label=\"Fechar\">&times;</button>
+                            </div>
+                            <div class=\"form-card\">
+                                <div class=\"field\"><label>Mostrar</label>
+                                    <select id=\"hx-tabela\">
+                                        <option value=\"\">Tudo</option><option value=\"plantoes\">Plant&otilde;es</option>
+                                        <option value=\"despesas\">Despesas</option><option value=\"despesas_recorrentes\">Recorrentes</option>
+                                        <option value=\"categorias\">Categorias</option>
+                                    </select>
+                                </div>
+                            </div>
+                            <div class=\"table-wrap\">
+                                <table>
+                                    <thead><tr><th>Quando</th><th>Registro</th><th>A&ccedil;&atilde;o</th><th>Altera&ccedil;&otilde;es</th><th></th></tr></thead>
+                                    <tbody id=\"historico-body\"></tbody>
+                                </table>
+                            </div>
+                            <div class=\"modal-actions\">
+                                <button id=\"mais-historico-btn\" class=\"edit\" style=\"display: none;\">Carregar mais</button>
+                            </div>
+                            <div id=\"historico-msg\" class=\"msg\"></div>
+                        </div>
+                    </div>
+
+                    <div id=\"exportar-modal\" class=\"modal-overlay\">
+                        <div class=\"modal\">
+                            <div class=\"modal-head\">
+                                <h2>Exportar</h2>
+                                <button id=\"close-exportar-btn\" class=\"modal-close\" aria-label=\"Fechar\">&times;</button>
+                            </div>
+                            <div class=\"form-card\">
+                                <div class=\"field\"><label>Dados</label>
+                                    <select id=\"ex-tabela\"><option value=\"plantoes\">Plant&otilde;es</option><option value=\"despesas\">Despesas</option></select>
+                                </div>
+                                <div class=\"field\"><label>Formato</label>
+                                    <select id=\"ex-formato\"><option value=\"xlsx\">Excel (XLSX)</option><option value=\"csv\">CSV</option></select>
+                                </div>
+                                <div class=\"field\"><label>De</label><input type=\"date\" id=\"ex-inicio\"></div>
+                                <div class=\"field\"><label>At&eacute;</label><input type=\"date\" id=\"ex-fim\"></div>
+                            </div>
+                            <div class=\"modal-actions\">
+                                <button id=\"cancel-exportar-btn\" class=\"edit\">Cancelar</button>
+                                <button id=\"download-exportar-btn\">Baixar</button>
+                            </div>
+                        </div>
+                    </div>
+
+                    <div id=\"conta-modal\" class=\"modal-overlay\">
+                        <div class=\"modal\">
+                            <div class=\"modal-head\">
+                                <h2 id=\"conta-title\">Conta</h2>
+                                <button id=\"close-conta-btn\" class=\"modal-close\" aria-label=\"Fechar\">&times;</button>
+                            </div>
+                            <div class=\"form-card\">
+                                <div class=\"field\"><label>Senha atual</label><input type=\"password\" id=\"cx-atual\" autocomplete=\"current-password\"></div>
+                                <div class=\"field\"><label>Nova senha</label><input type=\"password\" id=\"cx-nova\" autocomplete=\"new-password\"></div>
+                            </div>
+                            <div class=\"modal-actions\">
+                                <button id=\"encerrar-sessoes-btn\" class=\"edit\">Sair de todas as sess&otilde;es</button>
+                                <button id=\"save-senha-btn\">Alterar senha</button>
+                            </div>
+                            <div id=\"usuarios-admin\" style=\"display: none;\">
+                                <div class=\"form-card\">
+                                    <div class=\"field\"><label>Novo usu&aacute;rio</label><input type=\"text\" id=\"ux-nome\" autocomplete=\"off\"></div>
+                                    <div class=\"field\"><label>Senha</label><input type=\"password\" id=\"ux-senha\" autocomplete=\"new-password\"></div>
+                                </div>
+                                <div class=\"modal-actions\">
+                                    <button id=\"save-usuario-btn\">Criar usu&aacute;rio</button>
+                                </div>
+                                <div class=\"form-card\">
+                                    <div class=\"field wide\"><label>Restaurar backup</label><input type=\"file\" id=\"bx-arquivo\" accept=\".db,.sqlite,.sqlite3\"></div>
+                                </div>
+                                <div class=\"modal-actions\">
+                                    <button id=\"download-backup-btn\" class=\"edit\">Baixar backup</button>
+                                    <button id=\"restore-backup-btn\" class=\"del\">Restaurar</button>
+                                </div>
+                            </div>
+                            <div id=\"conta-msg\" class=\"msg\"></div>
+                        </div>
+                    </div>
+
                     <div id=\"categorias-modal\" class=\"modal-overlay\">
                         <div class=\"modal\">
                             <div class=\"modal-head\">
@@ -1008,3 +3600,698 @@ fn app_page() -> String {
         </html>
     ", navbar())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frozen copies of the schemas databases were left in before migrations
+    // existed. They must not follow later changes to the constants above.
+
+    const PLANTOES_ONLY_SQL: &str = "
+        CREATE TABLE plantoes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            data TEXT NOT NULL,
+            local TEXT NOT NULL,
+            mfc_pa TEXT NOT NULL,
+            duracao_h REAL NOT NULL,
+            periodo TEXT NOT NULL,
+            valor REAL NOT NULL,
+            previsao_pagamento TEXT,
+            recebido INTEGER NOT NULL DEFAULT 0,
+            mes_ano_pagamento TEXT,
+            observacoes TEXT
+        );
+    ";
+
+    const DESPESAS_SQL: &str = "
+        CREATE TABLE categorias (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            nome TEXT NOT NULL UNIQUE,
+            padrao INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE despesas_recorrentes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            descricao TEXT NOT NULL,
+            valor REAL NOT NULL,
+            categoria_id INTEGER,
+            tipo TEXT NOT NULL,
+            periodicidade TEXT NOT NULL,
+            dia_vencimento INTEGER NOT NULL,
+            mes_vencimento INTEGER,
+            data_inicio TEXT NOT NULL,
+            data_fim TEXT,
+            ativo INTEGER NOT NULL DEFAULT 1
+        );
+        CREATE TABLE despesas (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            valor REAL NOT NULL,
+            data TEXT NOT NULL,
+            descricao TEXT,
+            categoria_id INTEGER,
+            tipo TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pago',
+            recorrente_id INTEGER,
+            competencia TEXT,
+            observacoes TEXT
+        );
+        CREATE UNIQUE INDEX idx_despesa_recorrencia
+            ON despesas(recorrente_id, competencia) WHERE recorrente_id IS NOT NULL;
+    ";
+
+    // Accounts with sessions, before sessions carried a CSRF token.
+    const ACCOUNTS_SQL: &str = "
+        CREATE TABLE plantoes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            data TEXT NOT NULL,
+            local TEXT NOT NULL,
+            mfc_pa TEXT NOT NULL,
+            duracao_h REAL NOT NULL,
+            periodo TEXT NOT NULL,
+            valor REAL NOT NULL,
+            previsao_pagamento TEXT,
+            recebido INTEGER NOT NULL DEFAULT 0,
+            mes_ano_pagamento TEXT,
+            observacoes TEXT,
+            user_id INTEGER
+        );
+        CREATE TABLE categorias (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            nome TEXT NOT NULL,
+            padrao INTEGER NOT NULL DEFAULT 0,
+            user_id INTEGER,
+            UNIQUE (user_id, nome)
+        );
+        CREATE TABLE despesas_recorrentes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            descricao TEXT NOT NULL,
+            valor REAL NOT NULL,
+            categoria_id INTEGER,
+            tipo TEXT NOT NULL,
+            periodicidade TEXT NOT NULL,
+            dia_vencimento INTEGER NOT NULL,
+            mes_vencimento INTEGER,
+            data_inicio TEXT NOT NULL,
+            data_fim TEXT,
+            ativo INTEGER NOT NULL DEFAULT 1,
+            user_id INTEGER
+        );
+        CREATE TABLE despesas (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            valor REAL NOT NULL,
+            data TEXT NOT NULL,
+            descricao TEXT,
+            categoria_id INTEGER,
+            tipo TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pago',
+            recorrente_id INTEGER,
+            competencia TEXT,
+            observacoes TEXT,
+            user_id INTEGER
+        );
+        CREATE UNIQUE INDEX idx_despesa_recorrencia
+            ON despesas(recorrente_id, competencia) WHERE recorrente_id IS NOT NULL;
+        CREATE TABLE usuarios (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            nome TEXT NOT NULL UNIQUE,
+            senha_hash TEXT NOT NULL,
+            admin INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE sessoes (
+            token_hash TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            criada_em INTEGER NOT NULL,
+            expira_em INTEGER NOT NULL
+        );
+    ";
+
+    const TABLES: [&str; 6] = ["plantoes", "categorias", "despesas_recorrentes", "despesas", "usuarios", "sessoes"];
+
+    fn database(sql: &str) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(sql).unwrap();
+        conn
+    }
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.pragma_query_value(None, "user_version", |r| r.get(0)).unwrap()
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table)).unwrap();
+        let mut names: Vec<String> = stmt.query_map([], |r| r.get(1)).unwrap().map(|n| n.unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    // An upgraded database must end up with the same tables and columns as
+    // one created from scratch.
+    fn assert_current_schema(conn: &Connection) {
+        let mut fresh = database("");
+        migrate(&mut fresh).unwrap();
+        assert_eq!(user_version(conn), MIGRATIONS.len() as i64);
+        for table in TABLES {
+            assert_eq!(columns(conn, table), columns(&fresh, table), "columns of {}", table);
+        }
+    }
+
+    fn insert_old_plantao(conn: &Connection) {
+        conn.execute(
+            "INSERT INTO plantoes (data, local, mfc_pa, duracao_h, periodo, valor) VALUES ('2024-03-01', 'UPA', 'PA', 12, 'Diurno', 1200)",
+            [],
+        ).unwrap();
+    }
+
+    fn owner_of_plantao(conn: &Connection) -> Option<i64> {
+        conn.query_row("SELECT user_id FROM plantoes", [], |r| r.get(0)).unwrap()
+    }
+
+    #[test]
+    fn migrates_empty_database() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        assert_current_schema(&conn);
+    }
+
+    #[test]
+    fn migrates_plantoes_only_database() {
+        let mut conn = database(PLANTOES_ONLY_SQL);
+        insert_old_plantao(&conn);
+        migrate(&mut conn).unwrap();
+        assert_current_schema(&conn);
+
+        ensure_first_usuario(&conn, "senha").unwrap();
+        assert_eq!(owner_of_plantao(&conn), Some(1));
+    }
+
+    #[test]
+    fn migrates_database_without_accounts() {
+        let mut conn = database(&format!("{}{}", PLANTOES_ONLY_SQL, DESPESAS_SQL));
+        insert_old_plantao(&conn);
+        conn.execute("INSERT INTO categorias (nome, padrao) VALUES ('Moradia', 1)", []).unwrap();
+        conn.execute(
+            "INSERT INTO despesas (valor, data, descricao, categoria_id, tipo) VALUES (80, '2024-03-05', 'luz', 1, 'fixa')",
+            [],
+        ).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_current_schema(&conn);
+
+        ensure_first_usuario(&conn, "senha").unwrap();
+        assert_eq!(owner_of_plantao(&conn), Some(1));
+        let (categoria, dono): (i64, i64) = conn
+            .query_row("SELECT categoria_id, user_id FROM despesas", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap();
+        assert_eq!((categoria, dono), (1, 1));
+        let moradias: i64 = conn
+            .query_row("SELECT COUNT(*) FROM categorias WHERE nome = 'Moradia' AND user_id = 1", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(moradias, 1);
+
+        // Category names are now unique per account, not globally.
+        let other = insert_usuario(&conn, "outra", "senha", false).unwrap();
+        seed_categorias(&conn, other).unwrap();
+    }
+
+    #[test]
+    fn migrates_database_with_sessions_without_csrf() {
+        let mut conn = database(ACCOUNTS_SQL);
+        conn.execute("INSERT INTO usuarios (nome, senha_hash, admin) VALUES ('lucasodon', 'x', 1)", []).unwrap();
+        conn.execute("INSERT INTO sessoes VALUES ('abc', 1, 0, 9999999999)", []).unwrap();
+        insert_old_plantao(&conn);
+        conn.execute("UPDATE plantoes SET user_id = 1", []).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_current_schema(&conn);
+
+        let sessoes: i64 = conn.query_row("SELECT COUNT(*) FROM sessoes", [], |r| r.get(0)).unwrap();
+        assert_eq!(sessoes, 0);
+        assert_eq!(owner_of_plantao(&conn), Some(1));
+    }
+
+    #[test]
+    fn converts_real_values_to_centavos() {
+        let mut conn = database(&format!("{}{}", PLANTOES_ONLY_SQL, DESPESAS_SQL));
+        for valor in ["0.1", "0.2", "19.99", "1200"] {
+            conn.execute(
+                &format!("INSERT INTO despesas (valor, data, tipo) VALUES ({}, '2024-03-05', 'pessoal')", valor),
+                [],
+            ).unwrap();
+        }
+        migrate(&mut conn).unwrap();
+        assert_current_schema(&conn);
+
+        let (total, tipos): (i64, String) = conn
+            .query_row("SELECT SUM(valor), GROUP_CONCAT(DISTINCT typeof(valor)) FROM despesas", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap();
+        assert_eq!(total, 10 + 20 + 1999 + 120000);
+        assert_eq!(tipos, "integer");
+    }
+
+    #[test]
+    fn dinheiro_is_exact_in_json() {
+        let lido: Vec<Dinheiro> = serde_json::from_str(r#"[0.1, 0.2, 19.99, 1200, "7,5", "-3.05"]"#).unwrap();
+        assert_eq!(lido, vec![Dinheiro(10), Dinheiro(20), Dinheiro(1999), Dinheiro(120000), Dinheiro(750), Dinheiro(-305)]);
+
+        let soma = Dinheiro(lido[0].0 + lido[1].0);
+        assert_eq!(serde_json::to_string(&[soma, lido[2], lido[3]]).unwrap(), "[0.3,19.99,1200]");
+        assert_eq!(Dinheiro::parse("1.234"), None);
+        assert_eq!(Dinheiro::parse("abc"), None);
+    }
+
+    #[test]
+    fn resumo_groups_by_month_of_payment() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        let moradia = {
+            conn.execute("INSERT INTO categorias (nome, user_id) VALUES ('Moradia', ?1)", params![user]).unwrap();
+            conn.last_insert_rowid()
+        };
+        for (data, previsao, valor, recebido) in [
+            ("2025-01-10", None, 100_010, 1),
+            ("2025-01-20", Some("2025-02-05"), 50_020, 0),
+            ("2025-02-01", Some(""), 30_030, 0),
+        ] {
+            conn.execute(
+                "INSERT INTO plantoes (data, local, mfc_pa, duracao_h, periodo, valor, previsao_pagamento, recebido, user_id)
+                 VALUES (?1, 'UPA', 'PA', 12, 'Diurno', ?2, ?3, ?4, ?5)",
+                params![data, valor, previsao, recebido, user],
+            ).unwrap();
+        }
+        for (valor, categoria, tipo, status) in [
+            (10, Some(moradia), "pessoal", "pago"),
+            (20, None, "profissional", "previsto"),
+            (40, Some(moradia), "pessoal", "cancelado"),
+        ] {
+            conn.execute(
+                "INSERT INTO despesas (valor, data, categoria_id, tipo, status, user_id) VALUES (?1, '2025-02-10', ?2, ?3, ?4, ?5)",
+                params![valor, categoria, tipo, status, user],
+            ).unwrap();
+        }
+
+        let r = resumo(&conn, user, Some("2025-01-01"), Some("2025-02-28")).unwrap();
+        let meses: Vec<(&str, i64, i64)> = r.receitas_por_mes.iter().map(|m| (m.mes.as_str(), m.total.0, m.plantoes)).collect();
+        assert_eq!(meses, vec![("2025-01", 100_010, 1), ("2025-02", 80_050, 2)]);
+        assert_eq!((r.receitas.recebido, r.receitas.pendente), (Dinheiro(100_010), Dinheiro(80_050)));
+        assert_eq!((r.despesas.total, r.despesas.pago, r.despesas.previsto), (Dinheiro(30), Dinheiro(10), Dinheiro(20)));
+        assert_eq!(r.despesas_por_categoria[0].nome, "Sem categoria");
+        assert_eq!(r.saldo, Dinheiro(180_060 - 30));
+        assert_eq!(r.saldo_realizado, Dinheiro(100_010 - 10));
+
+        let janeiro = resumo(&conn, user, Some("2025-01-01"), Some("2025-01-31")).unwrap();
+        assert_eq!(janeiro.receitas.total, Dinheiro(100_010));
+    }
+
+    #[test]
+    fn projecao_flags_months_in_deficit() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        conn.execute(
+            "INSERT INTO plantoes (data, local, mfc_pa, duracao_h, periodo, valor, previsao_pagamento, recebido, user_id)
+             VALUES ('2025-12-20', 'UPA', 'PA', 12, 'Diurno', 150000, '2026-01-10', 0, ?1)",
+            params![user],
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO despesas_recorrentes (descricao, valor, tipo, periodicidade, dia_vencimento, data_inicio, user_id)
+             VALUES ('Aluguel', 100000, 'pessoal', 'mensal', 5, '2025-01-01', ?1)",
+            params![user],
+        ).unwrap();
+
+        let p = projecao(&conn, user, (2025, 12), 3, Dinheiro(5000)).unwrap();
+        let meses: Vec<(&str, i64, bool)> = p.meses.iter().map(|m| (m.mes.as_str(), m.saldo_previsto.0, m.deficit)).collect();
+        assert_eq!(meses, vec![("2025-12", -95_000, true), ("2026-01", -45_000, false), ("2026-02", -145_000, true)]);
+        assert_eq!(p.meses_em_deficit, 2);
+    }
+
+    #[test]
+    fn parses_bank_statements() {
+        let csv = "Data;Histórico;Valor\n05/03/2025;\"PADARIA; CENTRO\";-1.234,56\n06/03/2025;PIX RECEBIDO;500,00\nsaldo;;\n2025-1é-01;X;1,00\n1234567é;Y;1,00\n";
+        let (linhas, ignoradas) = parse_csv_extrato(csv);
+        assert_eq!(ignoradas, 3);
+        assert_eq!(linhas[0].1, ("2025-03-05".to_string(), "PADARIA; CENTRO".to_string(), Dinheiro(-123_456)));
+        assert_eq!(linhas[1].1.2, Dinheiro(50_000));
+
+        let ofx = "<OFX><BANKTRANLIST><STMTTRN><TRNTYPE>DEBIT<DTPOSTED>20250307120000[-3:BRT]\n<TRNAMT>-89.90<FITID>1<MEMO>NETFLIX</STMTTRN></BANKTRANLIST></OFX>";
+        let (linhas, _) = parse_ofx_extrato(ofx);
+        assert_eq!(linhas[0].1, ("2025-03-07".to_string(), "NETFLIX".to_string(), Dinheiro(-8_990)));
+    }
+
+    #[test]
+    fn preview_flags_duplicates_and_recurring_occurrences() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        conn.execute(
+            "INSERT INTO despesas (valor, data, descricao, tipo, status, user_id)
+             VALUES (2500, '2025-03-01', 'Padaria Pao Quente 123', 'profissional', 'pago', ?1)",
+            params![user],
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO despesas_recorrentes (descricao, valor, tipo, periodicidade, dia_vencimento, data_inicio, user_id)
+             VALUES ('Aluguel', 100000, 'pessoal', 'mensal', 5, '2025-01-01', ?1)",
+            params![user],
+        ).unwrap();
+        materialize_recorrentes(&conn, user, "2025-03-01", "2025-03-31").unwrap();
+
+        let conteudo = "data,descricao,valor\n2025-03-01,PADARIA PAO QUENTE,-25.00\n2025-03-07,TED ALUGUEL,-1000.00\n2025-03-09,PADARIA PAO QUENTE 456,-12.00\n".to_string();
+        let p = preview_extrato(&conn, user, &ImportarPreview { formato: None, conteudo }).unwrap();
+        let acoes: Vec<&str> = p.linhas.iter().map(|l| l.acao.as_str()).collect();
+        assert_eq!(acoes, vec!["duplicada", "pagar_previsto", "importar"]);
+        assert_eq!(p.linhas[2].tipo, "profissional");
+
+        let linhas = p.linhas.iter().filter(|l| l.acao != "duplicada").map(|l| LinhaConfirmada {
+            data: l.data.clone(), descricao: l.descricao.clone(), valor: l.valor,
+            categoria_id: l.categoria_id, tipo: l.tipo.clone(), acao: l.acao.clone(), alvo_id: l.alvo_id,
+        }).collect();
+        let r = confirmar_extrato(&conn, user, &ImportarConfirmar { linhas }).unwrap();
+        assert_eq!((r.importadas, r.pagas), (1, 1));
+        let previstas: i64 = conn.query_row("SELECT COUNT(*) FROM despesas WHERE status = 'previsto'", [], |r| r.get(0)).unwrap();
+        assert_eq!(previstas, 0);
+    }
+
+    #[test]
+    fn materializes_no_further_than_the_projection_horizon() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        let (ano, mes, _) = hoje();
+        let (y, m) = add_months(ano, mes, -1);
+        conn.execute(
+            "INSERT INTO despesas_recorrentes (descricao, valor, tipo, periodicidade, dia_vencimento, data_inicio, user_id)
+             VALUES ('Aluguel', 100000, 'pessoal', 'mensal', 5, ?1, ?2)",
+            params![format!("{:04}-{:02}-01", y, m), user],
+        ).unwrap();
+        let previstos = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM despesas", [], |r| r.get(0)).unwrap()
+        };
+
+        materialize_recorrentes(&conn, user, "9000-01-01", "9999-12-31").unwrap();
+        assert_eq!(previstos(&conn), 0);
+        materialize_recorrentes(&conn, user, "0001-01-01", "9999-12-31").unwrap();
+        assert_eq!(previstos(&conn), PROJECAO_MESES_MAX as i64 + 2);
+    }
+
+    #[test]
+    fn exports_plantoes_with_totals() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        for (data, valor, recebido) in [("2025-02-10", 120_000, 1), ("2025-03-10", 150_050, 0), ("2026-01-10", 90_000, 0)] {
+            conn.execute(
+                "INSERT INTO plantoes (data, local, mfc_pa, duracao_h, periodo, valor, recebido, observacoes, user_id)
+                 VALUES (?1, 'UPA', 'PA', 12, 'Diurno', ?2, ?3, 'troca; \"urgente\"', ?4)",
+                params![data, valor, recebido, user],
+            ).unwrap();
+        }
+
+        let p = planilha_plantoes(&conn, user, Some("2025-01-01"), Some("2025-12-31")).unwrap();
+        let csv = planilha_csv(&p);
+        let linhas: Vec<&str> = csv.trim_start_matches('\u{feff}').lines().collect();
+        assert_eq!(linhas[2], "2;10/03/2025;UPA;PA;12;Diurno;1500,50;125,04;;Não;;\"troca; \"\"urgente\"\"\"");
+        assert_eq!(&linhas[4..], [";Total;;;24;;2700,50;112,52", ";Recebido;;;;;1200,00;", ";A receber;;;;;1500,50;"]);
+        assert!(planilha_xlsx(&p).unwrap().starts_with(b"PK"));
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_and_never_formulas() {
+        let texto = |t: &str| Celula::Texto(t.to_string());
+        let p = Planilha {
+            nome: "Teste",
+            cabecalho: &["a", "b", "c", "d"],
+            linhas: vec![
+                vec![texto("=HYPERLINK(\"x\")"), texto("linha\rquebrada"), texto("@SUM(A1)"), Celula::Valor(Dinheiro(-150))],
+                vec![texto("\t=1+1"), texto("\r=2"), texto("ok"), Celula::Valor(Dinheiro(100))],
+            ],
+            totais: Vec::new(),
+        };
+        let csv = planilha_csv(&p);
+        let linhas: Vec<&str> = csv.trim_start_matches('\u{feff}').split("\r\n").collect();
+        assert_eq!(linhas[1], "\"'=HYPERLINK(\"\"x\"\")\";\"linha\rquebrada\";'@SUM(A1);-1,50");
+        assert_eq!(linhas[2], "'\t=1+1;\"'\r=2\";ok;1,00");
+    }
+
+    #[test]
+    fn validates_export_dates() {
+        assert!(data_valida("2024-02-29"));
+        for d in ["2025-02-29", "2025-13-01", "2025-1-01", "2025-01-01\"", "2025/01/01", "abcd-01-01"] {
+            assert!(!data_valida(d), "{}", d);
+        }
+    }
+
+    #[test]
+    fn restore_rejects_unusable_databases() {
+        let pool = Pool::new(SqliteConnectionManager::memory()).unwrap();
+        let arquivo = std::env::temp_dir().join(format!("lucasodon-restore-{}.db", std::process::id()));
+        let motivo = |prepara: &dyn Fn(&mut Connection)| {
+            let _ = fs::remove_file(&arquivo);
+            prepara(&mut Connection::open(&arquivo).unwrap());
+            match restore_from(&pool, &arquivo) {
+                Err(DbError::Invalido(m)) => m,
+                other => panic!("restore accepted: {:?}", other),
+            }
+        };
+        assert_eq!(motivo(&|c| c.execute_batch("CREATE TABLE x (a); PRAGMA user_version = 99;").unwrap()), "backup de uma versao mais nova");
+        assert_eq!(motivo(&|c| c.execute_batch("CREATE TABLE x (a);").unwrap()), "arquivo nao e um backup do lucasodon");
+        assert_eq!(motivo(&|c| c.execute_batch("CREATE TABLE x (a); PRAGMA user_version = 1;").unwrap()), "esquema do backup incompativel");
+        assert_eq!(motivo(&|c| migrate(c).unwrap()), "backup sem administrador");
+        let _ = fs::remove_file(&arquivo);
+    }
+
+    #[test]
+    fn audit_log_records_changes_and_restores_versions() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        let outro = insert_usuario(&conn, "bia", "senha", false).unwrap();
+        let plantao = |valor: i64| PlantaoInput {
+            data: "2025-03-01".to_string(), local: "UPA".to_string(), mfc_pa: "PA".to_string(),
+            duracao_h: 12.0, periodo: "Diurno".to_string(), valor: Dinheiro(valor),
+            previsao_pagamento: None, recebido: false, mes_ano_pagamento: None, observacoes: None,
+        };
+        insert_plantao(&conn, user, &plantao(100_000)).unwrap();
+        let id = conn.last_insert_rowid();
+        update_plantao(&conn, user, &PlantaoUpdate { id, fields: plantao(120_000) }).unwrap();
+        update_plantao(&conn, outro, &PlantaoUpdate { id, fields: plantao(1) }).unwrap();
+        delete_plantao(&conn, user, id).unwrap();
+
+        let q = AuditoriaQuery { tabela: Some("plantoes".to_string()), registro_id: Some(id), antes_de: None, limite: None };
+        let log = list_auditoria(&conn, user, &q).unwrap();
+        let acoes: Vec<&str> = log.iter().map(|e| e.acao.as_str()).collect();
+        assert_eq!(acoes, vec!["excluir", "alterar", "criar"]);
+        assert_eq!(log[1].antes.as_ref().unwrap()["valor"], 100_000);
+        assert_eq!(log[1].depois.as_ref().unwrap()["valor"], 120_000);
+        assert!(list_auditoria(&conn, outro, &q).unwrap().is_empty());
+
+        // Undoing the update brings the deleted row back at its first value.
+        assert!(!restaurar_versao(&conn, outro, &AuditoriaRestaurar { id: log[1].id, versao: None }).unwrap());
+        assert!(restaurar_versao(&conn, user, &AuditoriaRestaurar { id: log[1].id, versao: None }).unwrap());
+        let valor: i64 = conn.query_row("SELECT valor FROM plantoes WHERE id = ?1 AND user_id = ?2", params![id, user], |r| r.get(0)).unwrap();
+        assert_eq!(valor, 100_000);
+        assert_eq!(list_auditoria(&conn, user, &q).unwrap()[0].acao, "restaurar");
+        assert!(!restaurar_versao(&conn, user, &AuditoriaRestaurar { id: log[2].id, versao: None }).unwrap());
+    }
+
+    #[test]
+    fn trash_restores_and_purges() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        conn.execute(
+            "INSERT INTO despesas_recorrentes (descricao, valor, tipo, periodicidade, dia_vencimento, data_inicio, user_id)
+             VALUES ('Aluguel', 100000, 'pessoal', 'mensal', 5, '2025-01-01', ?1)",
+            params![user],
+        ).unwrap();
+        let regra = conn.last_insert_rowid();
+        let ativas = |conn: &Connection| list_despesas(conn, user, Some("2025-03-01"), Some("2025-04-30"), None, None).unwrap().len();
+        assert_eq!(ativas(&conn), 2);
+
+        delete_recorrente(&conn, user, regra).unwrap();
+        assert_eq!(ativas(&conn), 0);
+        let lixo = list_lixeira(&conn, user).unwrap();
+        assert_eq!(lixo.iter().map(|i| i.tabela.as_str()).collect::<Vec<_>>(), vec!["despesas_recorrentes"]);
+
+        restaurar_lixeira(&conn, user, &ItemRef { tabela: "despesas_recorrentes".to_string(), id: regra }).unwrap();
+        assert_eq!(ativas(&conn), 2);
+        assert!(list_lixeira(&conn, user).unwrap().is_empty());
+
+        // A purged occurrence stays a tombstone, so listing doesn't bring it back.
+        let ocorrencia = list_despesas(&conn, user, Some("2025-03-01"), Some("2025-03-31"), None, None).unwrap()[0].id;
+        delete_despesa(&conn, user, ocorrencia).unwrap();
+        assert_eq!(purge_lixeira(&conn, now_secs() + 1).unwrap(), 1);
+        assert_eq!(ativas(&conn), 1);
+        let status: String = conn.query_row("SELECT status FROM despesas WHERE id = ?1", params![ocorrencia], |r| r.get(0)).unwrap();
+        assert_eq!(status, "cancelado");
+    }
+
+    #[test]
+    fn trashed_rows_stay_as_they_were_trashed() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        let categoria = |nome: &str| -> i64 {
+            insert_categoria(&conn, user, &CategoriaInput { nome: nome.to_string() }).unwrap();
+            conn.query_row("SELECT id FROM categorias WHERE nome = ?1 AND user_id = ?2", params![nome, user], |r| r.get(0)).unwrap()
+        };
+        let renomear = |id: i64, nome: &str| update_categoria(&conn, user, &CategoriaUpdate { id, nome: nome.to_string() });
+        let (velha, nova) = (categoria("Velha"), categoria("Nova"));
+
+        delete_categoria(&conn, user, velha).unwrap();
+        assert_eq!(renomear(velha, "Editada").unwrap(), 0);
+        assert_eq!(renomear(nova, "Velha").unwrap(), 1);
+        assert!(list_lixeira(&conn, user).unwrap().is_empty());
+        let outra = categoria("Outra");
+        assert!(nome_em_uso(&DbError::Sql(renomear(outra, "Velha").unwrap_err())));
+
+        conn.execute(
+            "INSERT INTO despesas (valor, data, descricao, tipo, status, user_id)
+             VALUES (2500, '2025-03-01', 'Padaria', 'pessoal', 'pago', ?1)",
+            params![user],
+        ).unwrap();
+        let despesa = conn.last_insert_rowid();
+        delete_despesa(&conn, user, despesa).unwrap();
+        let edicao: DespesaUpdate = serde_json::from_value(serde_json::json!({
+            "id": despesa, "valor": "10,00", "data": "2025-03-02", "descricao": "Editada", "tipo": "pessoal",
+        })).unwrap();
+        assert_eq!(update_despesa(&conn, user, &edicao).unwrap(), 0);
+        let descricao: String = conn.query_row("SELECT descricao FROM despesas WHERE id = ?1", params![despesa], |r| r.get(0)).unwrap();
+        assert_eq!(descricao, "Padaria");
+
+        conn.execute(
+            "INSERT INTO despesas (valor, data, descricao, tipo, status, user_id)
+             VALUES (2500, '2025-03-10', 'Aluguel', 'pessoal', 'previsto', ?1)",
+            params![user],
+        ).unwrap();
+        let previsto = conn.last_insert_rowid();
+        delete_despesa(&conn, user, previsto).unwrap();
+        let pagar: ImportarConfirmar = serde_json::from_value(serde_json::json!({"linhas": [{
+            "data": "2025-03-11", "descricao": "ALUGUEL", "valor": "25,00", "categoria_id": null,
+            "tipo": "pessoal", "acao": "pagar_previsto", "alvo_id": previsto,
+        }]})).unwrap();
+        assert_eq!(confirmar_extrato(&conn, user, &pagar).unwrap().pagas, 0);
+        let status: String = conn.query_row("SELECT status FROM despesas WHERE id = ?1", params![previsto], |r| r.get(0)).unwrap();
+        assert_eq!(status, "previsto");
+
+        let (outros, mercado) = (categoria("Outros"), categoria("Mercado"));
+        let despesa_em = |descricao: &str| -> i64 {
+            conn.execute(
+                "INSERT INTO despesas (valor, data, descricao, categoria_id, tipo, status, user_id)
+                 VALUES (1000, '2025-03-05', ?1, ?2, 'pessoal', 'pago', ?3)",
+                params![descricao, mercado, user],
+            ).unwrap();
+            conn.last_insert_rowid()
+        };
+        let (viva, lixo) = (despesa_em("Feira"), despesa_em("Feira velha"));
+        delete_despesa(&conn, user, lixo).unwrap();
+        let categoria_de = |id: i64| -> Option<i64> {
+            conn.query_row("SELECT categoria_id FROM despesas WHERE id = ?1", params![id], |r| r.get(0)).unwrap()
+        };
+        delete_categoria(&conn, user, mercado).unwrap();
+        assert_eq!((categoria_de(viva), categoria_de(lixo)), (Some(outros), Some(mercado)));
+        restaurar_lixeira(&conn, user, &ItemRef { tabela: "categorias".into(), id: mercado }).unwrap();
+        assert_eq!((categoria_de(viva), categoria_de(lixo)), (Some(mercado), Some(mercado)));
+    }
+
+    #[test]
+    fn carne_leao_carries_excess_deductions_and_follows_the_table() {
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        conn.execute_batch(&format!("
+            INSERT INTO plantoes (data, local, mfc_pa, duracao_h, periodo, valor, recebido, mes_ano_pagamento, user_id) VALUES
+                ('2024-12-20', 'UPA', 'PA', 12, 'Diurno', 100000, 1, '2025-01-10', {u}),
+                ('2025-02-01', 'UPA', 'PA', 12, 'Diurno', 1000000, 1, NULL, {u}),
+                ('2025-03-01', 'UPA', 'PA', 12, 'Diurno', 500000, 0, NULL, {u}),
+                ('2025-06-01', 'UPA', 'PA', 12, 'Diurno', 250000, 1, '2025-06-30', {u});
+            INSERT INTO despesas (valor, data, tipo, status, user_id) VALUES
+                (150000, '2025-01-05', 'profissional', 'pago', {u}),
+                (90000, '2025-02-05', 'pessoal', 'pago', {u}),
+                (90000, '2025-02-06', 'profissional', 'previsto', {u});", u = user)).unwrap();
+
+        let c = carne_leao(&conn, user, 2025).unwrap();
+        let jan = &c.meses[0];
+        assert_eq!((jan.rendimentos, jan.base_calculo, jan.imposto), (Dinheiro(100_000), Dinheiro(0), Dinheiro(0)));
+        // January's unused R$ 500 lowers February's base; the 2024 table applies.
+        let fev = &c.meses[1];
+        assert_eq!((fev.excesso_anterior, fev.base_calculo), (Dinheiro(50_000), Dinheiro(950_000)));
+        assert_eq!((fev.aliquota, fev.imposto), (27.5, Dinheiro(171_650)));
+        assert_eq!(c.meses[2].rendimentos, Dinheiro(0));
+        // From May 2025 on, 2500 falls in the 7.5% bracket with the new deduction.
+        assert_eq!((c.meses[5].aliquota, c.meses[5].imposto), (7.5, Dinheiro(534)));
+        assert_eq!(c.anual.imposto, Dinheiro(172_184));
+        assert_eq!(c.anual.excesso_nao_usado, Dinheiro(0));
+
+        let nova = TabelaIrpf { vigencia: "2026-01".to_string(), faixas: vec![
+            FaixaIrpf { limite: Some(Dinheiro(500_000)), aliquota: 0.0, deducao: Dinheiro(0) },
+            FaixaIrpf { limite: None, aliquota: 27.5, deducao: Dinheiro(137_500) },
+        ] };
+        assert!(tabela_irpf_valida(&nova));
+        save_tabela_irpf(&conn, &nova).unwrap();
+        assert_eq!(list_tabelas_irpf(&conn).unwrap().len(), 3);
+        let aberta = TabelaIrpf { vigencia: "2026-01".to_string(), faixas: vec![
+            FaixaIrpf { limite: Some(Dinheiro(500_000)), aliquota: 0.0, deducao: Dinheiro(0) },
+        ] };
+        assert!(!tabela_irpf_valida(&aberta));
+    }
+
+    #[test]
+    fn recurrences_follow_period_installments_and_business_days() {
+        let regra = |periodicidade: &str, parcelas: Option<i64>| RegraRecorrente {
+            id: 1, valor: Dinheiro(10_000), categoria_id: None, tipo: "pessoal".to_string(),
+            periodicidade: periodicidade.to_string(), dia_vencimento: 15, mes_vencimento: None,
+            data_inicio: "2025-01-06".to_string(), data_fim: None, parcelas, dia_util: "nenhum".to_string(),
+        };
+        let datas = |r: &RegraRecorrente| vencimentos(r, (2025, 1), (2025, 12)).into_iter().map(|(_, _, d)| fmt_dias(d)).collect::<Vec<_>>();
+        assert_eq!(&datas(&regra("quinzenal", None))[..3], ["2025-01-06", "2025-01-20", "2025-02-03"]);
+        assert_eq!(datas(&regra("trimestral", Some(3))), ["2025-01-15", "2025-04-15", "2025-07-15"]);
+        assert_eq!(datas(&regra("semestral", None)), ["2025-01-15", "2025-07-15"]);
+        assert!(datas(&regra("anual", None)).is_empty());
+
+        // Rules started long ago still reach the window, numbered from their start.
+        let antiga = |periodicidade: &str, inicio: &str, parcelas: Option<i64>| RegraRecorrente {
+            data_inicio: inicio.to_string(), ..regra(periodicidade, parcelas)
+        };
+        let marco = |r: &RegraRecorrente| vencimentos(r, (2025, 3), (2025, 3));
+        let semanas: Vec<_> = marco(&antiga("semanal", "1990-01-01", None)).into_iter().map(|(_, _, d)| fmt_dias(d)).collect();
+        assert_eq!(semanas, ["2025-03-03", "2025-03-10", "2025-03-17", "2025-03-24", "2025-03-31"]);
+        assert_eq!(marco(&antiga("mensal", "1950-01-01", None)).len(), 1);
+        let parcela = marco(&antiga("mensal", "2020-01-01", Some(100)));
+        assert_eq!((parcela[0].0, parcela[0].1.as_str()), (62, "2025-03"));
+        assert!(marco(&antiga("mensal", "2020-01-01", Some(62))).is_empty());
+
+        let feriados: HashSet<String> = ["2025-03-03", "2025-03-04", "12-25"].map(String::from).into();
+        let ajuste = |data: &str, modo: &str| {
+            let (y, m, d) = parse_ymd(data);
+            fmt_dias(ajustar_dia_util(days_from_civil(y, m, d), modo, &feriados))
+        };
+        assert_eq!(ajuste("2025-03-01", "proximo"), "2025-03-05");
+        assert_eq!(ajuste("2025-03-01", "anterior"), "2025-02-28");
+        assert_eq!(ajuste("2026-12-25", "anterior"), "2026-12-24");
+        assert_eq!(ajuste("2025-03-01", "nenhum"), "2025-03-01");
+
+        let mut conn = database("");
+        migrate(&mut conn).unwrap();
+        let user = insert_usuario(&conn, "ana", "senha", false).unwrap();
+        let curso = RecorrenteInput {
+            descricao: "Curso".to_string(), valor: Dinheiro(50_000), categoria_id: None, tipo: "profissional".to_string(),
+            periodicidade: "trimestral".to_string(), dia_vencimento: 10, mes_vencimento: None,
+            data_inicio: "2025-02-01".to_string(), data_fim: None, ativo: None, parcelas: Some(3), dia_util: None,
+        };
+        assert!(recorrente_valida(&curso));
+        insert_recorrente(&conn, user, &curso).unwrap();
+        let ano = list_despesas(&conn, user, Some("2025-01-01"), Some("2026-12-31"), None, None).unwrap();
+        let vistas: Vec<_> = ano.iter().map(|d| (d.data.as_str(), d.descricao.as_deref().unwrap_or(""))).collect();
+        assert_eq!(vistas, [("2025-02-10", "Curso (1/3)"), ("2025-05-10", "Curso (2/3)"), ("2025-08-10", "Curso (3/3)")]);
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut conn = database(PLANTOES_ONLY_SQL);
+        insert_old_plantao(&conn);
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_current_schema(&conn);
+        let plantoes: i64 = conn.query_row("SELECT COUNT(*) FROM plantoes", [], |r| r.get(0)).unwrap();
+        assert_eq!(plantoes, 1);
+    }
+}
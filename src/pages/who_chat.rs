@@ -1,311 +1,2322 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, self};
-use std::io::{Write, Read};
-use std::path::Path;
-use actix_web::{get, post, web, HttpResponse, Responder, Result};
-use serde::Deserialize;
-use age::secrecy::Secret;
+use std::io::{self, Write, Read};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Result};
+use actix_web::web::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::{ExposeSecret, Secret};
+use age::x25519;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use futures_util::StreamExt;
+use tokio::sync::broadcast;
 use rand::{distributions::Alphanumeric, Rng};
 use crate::components::navbar::navbar;
-
+use crate::middleware::rate_limit::{self, RateLimit, RateLimiter};
+
+// Tests keep their rooms and secret under target/ instead of the live bucket.
+#[cfg(not(test))]
+const CHATS_DIR: &str = "bucket/chats";
+#[cfg(not(test))]
+const SECRET_PATH: &str = "bucket/who_chat_secret.txt";
+#[cfg(test)]
+const CHATS_DIR: &str = "target/who_chat_test/chats";
+#[cfg(test)]
+const SECRET_PATH: &str = "target/who_chat_test/secret.txt";
+const ADMIN_PASSWORD_PATH: &str = "bucket/who_chat_admin.txt";
+const SECRET_SIZE: usize = 64;
 const SALT_SIZE: usize = 32;
 const CONTENT_SIZE_LIMIT: usize = 100_000;
+const ROOM_FORMAT: u32 = 2;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+const STREAM_HEARTBEAT: Duration = Duration::from_secs(20);
+const EVENT_BUFFER: usize = 32;
+const E2E_FORMAT: u32 = 1;
+const E2E_SIZE_LIMIT: usize = 140_000;
+const E2E_SALT_LIMIT: usize = 64;
+const ATTACHMENT_LIMIT_VAR: &str = "WHO_CHAT_ATTACHMENT_LIMIT";
+const ATTACHMENT_LIMIT_DEFAULT: usize = 2_000_000;
+const ROOM_QUOTA_VAR: &str = "WHO_CHAT_ROOM_QUOTA";
+const ROOM_QUOTA_DEFAULT: usize = 10_000_000;
+const ATTACHMENT_ID_SIZE: usize = 16;
+// Framing of the age payload: a nonce, then chunks of up to 64 KiB of
+// plaintext, each followed by its tag.
+const AGE_NONCE_SIZE: usize = 16;
+const AGE_CHUNK_SIZE: usize = 64 * 1024;
+const AGE_TAG_SIZE: usize = 16;
+const ARCHIVE_FORMAT: u32 = 1;
+const MAX_ROOMS_VAR: &str = "WHO_CHAT_MAX_ROOMS";
+const MAX_ROOMS_DEFAULT: usize = 10_000;
+const MAX_BYTES_VAR: &str = "WHO_CHAT_MAX_BYTES";
+const MAX_BYTES_DEFAULT: usize = 1_000_000_000;
+// Eviction goes down to this share of the limits, so a full bucket is not
+// rescanned for every new room.
+const STORAGE_LOW_WATER_PERCENT: usize = 90;
+const CREATION_LIMIT: u32 = 20;
+const CREATION_WINDOW: Duration = Duration::from_secs(60 * 60);
+const FILENAME_LIMIT: usize = 100;
+// Longest message lifetime or idle period a room may ask for, ten years.
+const RETENTION_HOURS_LIMIT: u64 = 10 * 365 * 24;
+// Attachments are served with their type only when it is one of these.
+const ATTACHMENT_TYPES: [&str; 6] = ["image/png", "image/jpeg", "image/gif", "image/webp", "application/pdf", "text/plain"];
+
+
+#[derive(Deserialize)]
+struct ChatAccess {
+    name: String,
+    password: String,
+}
+
+
+#[derive(Deserialize)]
+struct ChatPost {
+    name: String,
+    password: String,
+    content: String,
+}
+
+
+#[derive(Deserialize)]
+struct ChatCreate {
+    name: String,
+    read_password: String,
+    post_password: String,
+    owner_password: String,
+    #[serde(flatten)]
+    retention: Retention,
+}
+
+
+#[derive(Deserialize)]
+struct ChatRotate {
+    name: String,
+    password: String,
+    new_password: String,
+    new_read_password: Option<String>,
+    new_post_password: Option<String>,
+}
+
+
+#[derive(Deserialize)]
+struct ChatAttach {
+    name: String,
+    password: String,
+    filename: String,
+    #[serde(default)]
+    content_type: String,
+    data: String,
+}
+
+
+#[derive(Deserialize)]
+struct ChatAttachment {
+    name: String,
+    password: String,
+    id: String,
+}
+
+
+#[derive(Deserialize)]
+struct ChatImport {
+    name: String,
+    password: String,
+    archive: RoomArchive,
+}
+
+
+#[derive(Deserialize)]
+struct AdminAccess {
+    password: String,
+}
+
+
+#[derive(Deserialize)]
+struct E2eName {
+    name: String,
+}
+
+
+#[derive(Deserialize)]
+struct E2eAccess {
+    name: String,
+    token: String,
+}
+
+
+#[derive(Deserialize)]
+struct E2eCreate {
+    name: String,
+    token: String,
+    format: u32,
+    salt: String,
+}
+
+
+#[derive(Deserialize)]
+struct E2ePost {
+    name: String,
+    token: String,
+    format: u32,
+    segment: String,
+}
+
+
+trait Credentials {
+    fn name(&self) -> &String;
+    fn password(&self) -> &String;
+}
+
+
+impl Credentials for ChatAccess {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.password
+    }
+}
+
+
+impl Credentials for ChatPost {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.password
+    }
+}
+
+
+impl Credentials for ChatRotate {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.password
+    }
+}
+
+
+impl Credentials for ChatAttach {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.password
+    }
+}
+
+
+impl Credentials for ChatAttachment {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.password
+    }
+}
+
+
+impl Credentials for ChatImport {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.password
+    }
+}
+
+
+impl Credentials for E2eAccess {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.token
+    }
+}
+
+
+impl Credentials for E2eCreate {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.token
+    }
+}
+
+
+impl Credentials for E2ePost {
+    fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn password(&self) -> &String {
+        &self.token
+    }
+}
+
+
+#[derive(Debug)]
+enum WhoChatError {
+    WrongPassword,
+    ChatNotFound,
+    ChatAlreadyExists,
+    InternalServerError,
+    DataCorruptionError,
+    InvalidName,
+    EmptyPassword,
+    EmptyContent,
+    InvalidContent,
+    ContentTooLarge,
+    UnsupportedFormat,
+    AttachmentNotFound,
+    InvalidRetention,
+}
+
+
+impl std::fmt::Display for WhoChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WhoChatError::WrongPassword => write!(f, "wrong password"),
+            WhoChatError::ChatNotFound => write!(f, "chat not found"),
+            WhoChatError::ChatAlreadyExists => write!(f, "chat already exists"),
+            WhoChatError::InternalServerError => write!(f, "internal server error"),
+            WhoChatError::DataCorruptionError => write!(f, "data corruption"),
+            WhoChatError::InvalidName => write!(f, "invalid name"),
+            WhoChatError::EmptyPassword => write!(f, "empty password"),
+            WhoChatError::EmptyContent => write!(f, "empty content"),
+            WhoChatError::InvalidContent => write!(f, "invalid content"),
+            WhoChatError::ContentTooLarge => write!(f, "content too large"),
+            WhoChatError::UnsupportedFormat => write!(f, "unsupported format"),
+            WhoChatError::AttachmentNotFound => write!(f, "attachment not found"),
+            WhoChatError::InvalidRetention => write!(f, "retention hours must be between 1 and {}", RETENTION_HOURS_LIMIT),
+        }
+    }
+}
+
+
+impl std::convert::From<age::EncryptError> for WhoChatError {
+    fn from(_v: age::EncryptError) -> Self {
+        WhoChatError::InternalServerError
+    }
+}
+
+
+impl std::convert::From<age::DecryptError> for WhoChatError {
+    fn from(_v: age::DecryptError) -> Self {
+        WhoChatError::WrongPassword
+    }
+}
+
+
+impl std::convert::From<std::io::Error> for WhoChatError {
+    fn from(_v: std::io::Error) -> Self {
+        WhoChatError::InternalServerError
+    }
+}
+
+
+impl std::convert::From<std::string::FromUtf8Error> for WhoChatError {
+    fn from(_v: std::string::FromUtf8Error) -> Self {
+        WhoChatError::DataCorruptionError
+    }
+}
+
+
+impl std::convert::From<serde_json::Error> for WhoChatError {
+    fn from(_v: serde_json::Error) -> Self {
+        WhoChatError::DataCorruptionError
+    }
+}
+
+
+fn error_response(err: WhoChatError) -> HttpResponse {
+    let mut response = match err {
+        WhoChatError::WrongPassword => HttpResponse::Forbidden(),
+        WhoChatError::ChatNotFound | WhoChatError::AttachmentNotFound => HttpResponse::NotFound(),
+        WhoChatError::ChatAlreadyExists => HttpResponse::Conflict(),
+        WhoChatError::InvalidName
+        | WhoChatError::EmptyPassword
+        | WhoChatError::EmptyContent
+        | WhoChatError::InvalidContent
+        | WhoChatError::UnsupportedFormat
+        | WhoChatError::InvalidRetention => HttpResponse::BadRequest(),
+        WhoChatError::ContentTooLarge => HttpResponse::PayloadTooLarge(),
+        WhoChatError::InternalServerError | WhoChatError::DataCorruptionError => HttpResponse::InternalServerError(),
+    };
+    response.content_type("text/plain").body(format!("Error: {}", err))
+}
+
+
+// ----------------------------- Rooms -----------------------------
+
+// What a password may do in a room. Owner implies read and post.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Capability {
+    Read,
+    Post,
+    Owner,
+}
+
+
+// Every room has its own x25519 key pair and each message is encrypted to its
+// public half. A grant is one passphrase-encrypted copy of that key: grants
+// that can read hold the identity, post-only grants hold just the recipient,
+// so a poster can write to the room without being able to read it.
+#[derive(Serialize, Deserialize)]
+struct Grant {
+    capabilities: Vec<Capability>,
+    key: String,
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct Segment {
+    size: usize,
+    #[serde(default)]
+    posted_at: u64,
+    ciphertext: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attachment: Option<Attachment>,
+}
+
+
+// A file posted to a room. Its segment holds the reference shown in the chat,
+// `[attachment {id}: {filename}, {size} bytes]`, and the file itself is kept
+// next to the room, encrypted to the room key, at `{room}.{id}.att`.
+#[derive(Serialize, Deserialize, Clone)]
+struct Attachment {
+    id: String,
+    size: usize,
+}
+
+
+// Per-room retention, chosen at creation. Messages older than
+// message_ttl_hours are hidden and dropped on the next post, a room untouched
+// for inactivity_hours is deleted, and a read_once room burns after its first
+// successful get.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+struct Retention {
+    message_ttl_hours: Option<u64>,
+    inactivity_hours: Option<u64>,
+    read_once: bool,
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct Room {
+    format: u32,
+    salt: String,
+    grants: Vec<Grant>,
+    segments: Vec<Segment>,
+    #[serde(default)]
+    retention: Retention,
+    #[serde(default)]
+    version: u64,
+}
+
+
+// The key a password unlocked.
+enum RoomKey {
+    Identity(x25519::Identity),
+    Recipient(x25519::Recipient),
+}
+
+
+impl RoomKey {
+    fn recipient(&self) -> x25519::Recipient {
+        match self {
+            RoomKey::Identity(identity) => identity.to_public(),
+            RoomKey::Recipient(recipient) => recipient.clone(),
+        }
+    }
+}
+
+
+fn generate_salt(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+
+// Key for naming room files, kept next to the chats and created on first run.
+// Losing it orphans every room, just like losing the chats themselves.
+static SECRET: OnceLock<String> = OnceLock::new();
+
+
+// Called once at startup: a secret that could not be saved would change on
+// the next restart and orphan every room created until then.
+pub fn init_secret() -> io::Result<()> {
+    if SECRET.get().is_none() {
+        let _ = SECRET.set(load_secret()?);
+    }
+    Ok(())
+}
+
+
+fn load_secret() -> io::Result<String> {
+    match fs::read_to_string(SECRET_PATH) {
+        Ok(s) if !s.trim().is_empty() => {
+            // Secrets saved by older versions were world-readable.
+            #[cfg(unix)]
+            fs::set_permissions(SECRET_PATH, fs::Permissions::from_mode(0o600))?;
+            return Ok(s.trim().to_string())
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    if let Some(dir) = Path::new(SECRET_PATH).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let secret = generate_salt(SECRET_SIZE);
+    let mut options = File::options();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(SECRET_PATH)?;
+    file.write_all(secret.as_bytes())?;
+    file.sync_all()?;
+    Ok(secret)
+}
+
+
+fn server_secret() -> &'static str {
+    SECRET.get_or_init(|| load_secret().expect("failed to load the Who Chat secret"))
+}
+
+
+fn keyed_mac(value: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(server_secret().as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(value.as_bytes());
+    mac
+}
+
+
+fn keyed_hash(value: &str) -> String {
+    keyed_mac(value).finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+
+// Rooms are stored under an HMAC of their name so a directory listing does
+// not reveal what the chats are called.
+fn chat_file_id(name: &str) -> String {
+    keyed_hash(name)
+}
+
+
+fn chat_path(name: &str) -> String {
+    format!("{}/{}.room", CHATS_DIR, chat_file_id(name))
+}
+
+
+// Chats saved before names were hashed live at `{name}.txt`; locked_room
+// moves them to their hashed file the first time they are looked up.
+fn legacy_path(name: &str) -> String {
+    format!("{}/{}.txt", CHATS_DIR, name)
+}
+
+
+// One lock per room file, held around every read-modify-write of it. Without
+// it a post that loaded the room before a rotation would rename the old key
+// pair, salt and grants back over the rotated file.
+fn room_lock(file_id: &str) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> = OnceLock::new();
+    let mut locks = LOCKS.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+    locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    locks.entry(file_id.to_string()).or_default().clone()
+}
+
+
+// Like locked, and first moves a chat still under its plain name to its
+// hashed file, so the move never races a reader or writer of the room.
+fn locked_room<T>(name: &str, operation: impl FnOnce() -> Result<T, WhoChatError>) -> Result<T, WhoChatError> {
+    locked(&chat_file_id(name), || {
+        let path = chat_path(name);
+        let plain_path = legacy_path(name);
+        if !Path::new(&path).exists() && Path::new(&plain_path).exists() {
+            fs::rename(&plain_path, &path)?;
+        }
+        operation()
+    })
+}
+
+
+fn locked<T>(file_id: &str, operation: impl FnOnce() -> Result<T, WhoChatError>) -> Result<T, WhoChatError> {
+    let lock = room_lock(file_id);
+    let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+    operation()
+}
+
+
+fn env_limit(var: &str, default: usize) -> usize {
+    std::env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+
+fn attachment_limit() -> usize {
+    static LIMIT: OnceLock<usize> = OnceLock::new();
+    *LIMIT.get_or_init(|| env_limit(ATTACHMENT_LIMIT_VAR, ATTACHMENT_LIMIT_DEFAULT))
+}
+
+
+// Bytes a room may hold, messages and attachments together.
+fn room_quota() -> usize {
+    static QUOTA: OnceLock<usize> = OnceLock::new();
+    *QUOTA.get_or_init(|| env_limit(ROOM_QUOTA_VAR, ROOM_QUOTA_DEFAULT))
+}
+
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+fn encrypt_with_passphrase(content: &[u8], password: &str, salt: &str) -> Result<String, WhoChatError> {
+    let key = format!("{}{}", password, salt);
+    let encryptor = age::Encryptor::with_user_passphrase(Secret::new(key));
+    let mut encrypted = vec![];
+    let armor = ArmoredWriter::wrap_output(&mut encrypted, Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armor)?;
+    writer.write_all(content)?;
+    writer.finish()?.finish()?;
+
+    Ok(String::from_utf8(encrypted)?)
+}
+
+
+// Reads both armored grants and the raw binary files of legacy chats.
+fn decrypt_with_passphrase(data: &[u8], password: &str, salt: &str) -> Result<Vec<u8>, WhoChatError> {
+    let key = format!("{}{}", password, salt);
+    let decryptor = match age::Decryptor::new(ArmoredReader::new(data))? {
+        age::Decryptor::Passphrase(d) => d,
+        _ => return Err(WhoChatError::DataCorruptionError),
+    };
+
+    let mut decrypted = vec![];
+    let mut reader = decryptor.decrypt(&Secret::new(key), None)?;
+    reader.read_to_end(&mut decrypted)?;
+
+    Ok(decrypted)
+}
+
+
+// Attachments are stored unarmored, they can be large.
+fn encrypt_bytes_to_recipient(content: &[u8], recipient: x25519::Recipient) -> Result<Vec<u8>, WhoChatError> {
+    let encryptor = match age::Encryptor::with_recipients(vec![Box::new(recipient)]) {
+        Some(e) => e,
+        None => return Err(WhoChatError::InternalServerError),
+    };
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(&mut encrypted)?;
+    writer.write_all(content)?;
+    writer.finish()?;
+
+    Ok(encrypted)
+}
+
+
+fn encrypt_to_recipient(content: &str, recipient: x25519::Recipient) -> Result<String, WhoChatError> {
+    let encryptor = match age::Encryptor::with_recipients(vec![Box::new(recipient)]) {
+        Some(e) => e,
+        None => return Err(WhoChatError::InternalServerError),
+    };
+    let mut encrypted = vec![];
+    let armor = ArmoredWriter::wrap_output(&mut encrypted, Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armor)?;
+    writer.write_all(content.as_bytes())?;
+    writer.finish()?.finish()?;
+
+    Ok(String::from_utf8(encrypted)?)
+}
+
+
+// Reads both armored segments and binary attachments.
+fn decrypt_bytes_with_identity(data: &[u8], identity: &x25519::Identity) -> Result<Vec<u8>, WhoChatError> {
+    let decryptor = match age::Decryptor::new(ArmoredReader::new(data))? {
+        age::Decryptor::Recipients(d) => d,
+        _ => return Err(WhoChatError::DataCorruptionError),
+    };
+
+    let mut decrypted = vec![];
+    let mut reader = decryptor.decrypt(std::iter::once(identity as &dyn age::Identity))?;
+    reader.read_to_end(&mut decrypted)?;
+
+    Ok(decrypted)
+}
+
+
+fn decrypt_with_identity(ciphertext: &str, identity: &x25519::Identity) -> Result<String, WhoChatError> {
+    Ok(String::from_utf8(decrypt_bytes_with_identity(ciphertext.as_bytes(), identity)?)?)
+}
+
+
+// How many bytes an age file, armored or not, decrypts to, read from its
+// framing without any key. The header ends with the line starting "--- ".
+fn age_plaintext_size(ciphertext: &[u8]) -> Option<usize> {
+    let mut data = Vec::new();
+    ArmoredReader::new(ciphertext).read_to_end(&mut data).ok()?;
+    let mac_line = data.windows(5).position(|w| w == b"\n--- ")? + 1;
+    let header_end = mac_line + data[mac_line..].iter().position(|&b| b == b'\n')? + 1;
+    let payload = data.len().checked_sub(header_end + AGE_NONCE_SIZE)?;
+    let chunks = payload.div_ceil(AGE_CHUNK_SIZE + AGE_TAG_SIZE).max(1);
+    payload.checked_sub(chunks * AGE_TAG_SIZE)
+}
+
+
+// Builds an empty room and returns it with its key. Identical passwords share
+// a single grant, so a classic single-password chat costs one passphrase check
+// per request.
+fn new_room(read_password: &str, post_password: &str, owner_password: &str) -> Result<(Room, RoomKey), WhoChatError> {
+    let mut by_password: Vec<(&str, Vec<Capability>)> = Vec::new();
+    let roles = [
+        (owner_password, Capability::Owner),
+        (read_password, Capability::Read),
+        (post_password, Capability::Post),
+    ];
+    for (password, capability) in roles {
+        let implied = match capability {
+            Capability::Owner => vec![Capability::Owner, Capability::Read, Capability::Post],
+            other => vec![other],
+        };
+        match by_password.iter_mut().find(|(p, _)| *p == password) {
+            Some((_, caps)) => {
+                for c in implied {
+                    if !caps.contains(&c) {
+                        caps.push(c);
+                    }
+                }
+            },
+            None => by_password.push((password, implied)),
+        }
+    }
+
+    let identity = x25519::Identity::generate();
+    let salt = generate_salt(SALT_SIZE);
+    let mut grants = Vec::new();
+    for (password, capabilities) in by_password {
+        let payload = if capabilities.contains(&Capability::Read) {
+            identity.to_string().expose_secret().clone()
+        } else {
+            identity.to_public().to_string()
+        };
+        grants.push(Grant {
+            capabilities,
+            key: encrypt_with_passphrase(payload.as_bytes(), password, &salt)?,
+        });
+    }
+
+    let room = Room {
+        format: ROOM_FORMAT,
+        salt,
+        grants,
+        segments: Vec::new(),
+        retention: Retention::default(),
+        version: 0,
+    };
+    Ok((room, RoomKey::Identity(identity)))
+}
+
+
+// Tries every grant carrying the capability; the first that opens with the
+// password wins.
+fn unlock(room: &Room, password: &str, capability: Capability) -> Result<RoomKey, WhoChatError> {
+    for grant in room.grants.iter().filter(|g| g.capabilities.contains(&capability)) {
+        let payload = match decrypt_with_passphrase(grant.key.as_bytes(), password, &room.salt) {
+            Ok(v) => String::from_utf8(v)?,
+            Err(WhoChatError::WrongPassword) => continue,
+            Err(err) => return Err(err),
+        };
+
+        let key = if grant.capabilities.contains(&Capability::Read) {
+            payload.parse::<x25519::Identity>().map(RoomKey::Identity)
+        } else {
+            payload.parse::<x25519::Recipient>().map(RoomKey::Recipient)
+        };
+        return key.map_err(|_e| WhoChatError::DataCorruptionError);
+    }
+
+    Err(WhoChatError::WrongPassword)
+}
+
+
+fn read_contents(room: &Room, key: &RoomKey) -> Result<String, WhoChatError> {
+    let identity = match key {
+        RoomKey::Identity(i) => i,
+        RoomKey::Recipient(_) => return Err(WhoChatError::WrongPassword),
+    };
+
+    let mut contents = String::new();
+    for segment in room.segments.iter().filter(|s| !is_expired(&room.retention, s)) {
+        contents.push_str(&decrypt_with_identity(&segment.ciphertext, identity)?);
+    }
+    Ok(contents)
+}
+
+
+fn truncate_start_string(content: &str) -> String {
+    let mut start_idx = content.chars().count();
+    let mut curr_len: usize= 0;
+    for c in content.chars().rev() {
+        curr_len += c.len_utf8();
+        start_idx -= 1;
+
+        if curr_len > CONTENT_SIZE_LIMIT {
+            start_idx += 1;
+            break;
+        }
+    }
+
+    content.chars().skip(start_idx).collect()
+}
+
+
+fn validate_retention(retention: &Retention) -> Result<(), WhoChatError> {
+    let hours = [retention.message_ttl_hours, retention.inactivity_hours];
+    if hours.iter().flatten().any(|h| !(1..=RETENTION_HOURS_LIMIT).contains(h)) {
+        return Err(WhoChatError::InvalidRetention)
+    }
+    Ok(())
+}
+
+
+fn is_expired(retention: &Retention, segment: &Segment) -> bool {
+    match retention.message_ttl_hours {
+        Some(hours) => segment.posted_at.saturating_add(hours.saturating_mul(3600)) < now_secs(),
+        None => false,
+    }
+}
+
+
+fn segment_usage(segment: &Segment) -> usize {
+    segment.size + segment.attachment.as_ref().map_or(0, |a| a.size)
+}
+
+
+// Posters may not be able to read the room, so instead of re-encrypting the
+// whole chat each message is stored as its own segment. Expired segments are
+// dropped, then the oldest ones until the text fits in CONTENT_SIZE_LIMIT and
+// text plus attachments fit in the room quota. Files of dropped attachments
+// are left to prune_attachments.
+fn append_segment(room: &mut Room, key: &RoomKey, content: &str, attachment: Option<Attachment>) -> Result<(), WhoChatError> {
+    room.segments.retain(|s| !is_expired(&room.retention, s));
+
+    let content_truncated = truncate_start_string(content);
+    room.segments.push(Segment {
+        size: content_truncated.len(),
+        posted_at: now_secs(),
+        ciphertext: encrypt_to_recipient(&content_truncated, key.recipient())?,
+        attachment,
+    });
+
+    let mut text: usize = room.segments.iter().map(|s| s.size).sum();
+    let mut total: usize = room.segments.iter().map(segment_usage).sum();
+    while (text > CONTENT_SIZE_LIMIT || total > room_quota()) && room.segments.len() > 1 {
+        let dropped = room.segments.remove(0);
+        text -= dropped.size;
+        total -= segment_usage(&dropped);
+    }
+    Ok(())
+}
+
+
+fn read_data(name: &str) -> Result<Vec<u8>, WhoChatError> {
+    let mut file = match File::open(chat_path(name)) {
+        Ok(v) => v,
+        Err(_e) => return Err(WhoChatError::ChatNotFound),
+    };
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+
+// Rooms are written to a temporary file and renamed over the old one, so a
+// reader never sees a half-written room and a crash keeps the previous copy.
+// Every write bumps the room version that live streams report.
+fn write_room(name: &str, room: &mut Room) -> Result<(), WhoChatError> {
+    room.version += 1;
+    write_atomically(&chat_path(name), &serde_json::to_vec(room)?)
+}
+
+
+fn write_atomically(path: &str, data: &[u8]) -> Result<(), WhoChatError> {
+    let tmp_path = format!("{}.{}.tmp", path, generate_salt(8));
+    let mut file: File = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+
+// Chats written before rooms existed are a salt followed by a single age
+// file encrypted with password + salt.
+fn is_legacy(data: &[u8]) -> bool {
+    data.first() != Some(&b'{')
+}
+
+
+fn get_salt_from_data(data: &[u8]) -> Result<String, WhoChatError> {
+    if data.len() < SALT_SIZE {
+        return Err(WhoChatError::DataCorruptionError)
+    }
+
+    Ok(String::from_utf8(data[0..SALT_SIZE].to_vec())?)
+}
+
+
+fn get_contents_from_data(data: &[u8], password: &str) -> Result<String, WhoChatError> {
+    if data.len() <= SALT_SIZE {
+        return Err(WhoChatError::DataCorruptionError)
+    }
+
+    let salt = get_salt_from_data(data)?;
+    let encrypted_data = &data[SALT_SIZE..];
+    Ok(String::from_utf8(decrypt_with_passphrase(encrypted_data, password, &salt)?)?)
+}
+
+
+// A legacy chat is converted into a room on the first access with its
+// password, which then becomes the read, post and owner key.
+fn migrate_legacy(name: &str, data: &[u8], password: &str) -> Result<Room, WhoChatError> {
+    let content = get_contents_from_data(data, password)?;
+    let (mut room, key) = new_room(password, password, password)?;
+    if !content.is_empty() {
+        append_segment(&mut room, &key, &content, None)?;
+    }
+    write_room(name, &mut room)?;
+    Ok(room)
+}
+
+
+// The file's modification time doubles as the room's last activity: every
+// write updates it and successful gets touch it.
+fn touch_room(name: &str) -> Result<(), WhoChatError> {
+    let file = File::options().write(true).open(chat_path(name))?;
+    file.set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+
+fn is_inactive(room: &Room, path: &Path) -> bool {
+    let hours = match room.retention.inactivity_hours {
+        Some(h) => h,
+        None => return false,
+    };
+    let idle = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok())
+        .unwrap_or_default();
+    idle > Duration::from_secs(hours.saturating_mul(3600))
+}
+
+
+fn load_room(name: &str, password: &str) -> Result<Room, WhoChatError> {
+    let data = read_data(name)?;
+    if is_legacy(&data) {
+        return migrate_legacy(name, &data, password);
+    }
+
+    let room: Room = serde_json::from_slice(&data)?;
+    if is_inactive(&room, Path::new(&chat_path(name))) {
+        fs::remove_file(chat_path(name))?;
+        prune_attachments(&chat_file_id(name), None)?;
+        return Err(WhoChatError::ChatNotFound);
+    }
+    Ok(room)
+}
+
+
+fn get_contents(chat_access: &ChatAccess, events: &ChatEvents) -> Result<String, WhoChatError> {
+    locked_room(&chat_access.name, || {
+        let room = load_room(&chat_access.name, &chat_access.password)?;
+        let key = unlock(&room, &chat_access.password, Capability::Read)?;
+        let contents = read_contents(&room, &key)?;
+
+        if room.retention.read_once {
+            fs::remove_file(chat_path(&chat_access.name))?;
+            prune_attachments(&chat_file_id(&chat_access.name), None)?;
+            events.publish(&chat_file_id(&chat_access.name), ChatEvent::Deleted);
+        } else {
+            touch_room(&chat_access.name)?;
+        }
+        Ok(contents)
+    })
+}
+
+
+fn post_content(chat_post: &ChatPost) -> Result<ChatEvent, WhoChatError> {
+    locked_room(&chat_post.name, || {
+        if Path::new(&e2e_path(&chat_post.name)).exists() {
+            return Err(WhoChatError::ChatAlreadyExists)
+        }
+
+        let (mut room, key) = if Path::new(&chat_path(&chat_post.name)).exists() {
+            let room = load_room(&chat_post.name, &chat_post.password)?;
+            let key = unlock(&room, &chat_post.password, Capability::Post)?;
+            (room, key)
+        } else {
+            new_room(&chat_post.password, &chat_post.password, &chat_post.password)?
+        };
+
+        append_segment(&mut room, &key, &chat_post.content, None)?;
+        write_room(&chat_post.name, &mut room)?;
+
+        let segment = room.segments.last().map(|s| s.ciphertext.clone()).unwrap_or_default();
+        Ok(ChatEvent::Update { version: room.version, segment })
+    })
+}
+
+
+fn delete_data(chat_access: &ChatAccess) -> Result<(), WhoChatError> {
+    locked_room(&chat_access.name, || {
+        let room = load_room(&chat_access.name, &chat_access.password)?;
+        unlock(&room, &chat_access.password, Capability::Owner)?;
+        fs::remove_file(chat_path(&chat_access.name))?;
+        prune_attachments(&chat_file_id(&chat_access.name), None)?;
+        Ok(())
+    })
+}
+
+
+// Rotation replaces the room key pair, the salt and every grant, then
+// re-encrypts each segment under the new key, so the old passwords open
+// nothing in the new file. Attachments are re-encrypted under fresh ids, so
+// the old files stay valid until the new room is written.
+fn rotate_room(chat_rotate: &ChatRotate) -> Result<(), WhoChatError> {
+    locked_room(&chat_rotate.name, || {
+        let old_room = load_room(&chat_rotate.name, &chat_rotate.password)?;
+        let old_key = unlock(&old_room, &chat_rotate.password, Capability::Owner)?;
+        let identity = match &old_key {
+            RoomKey::Identity(i) => i,
+            RoomKey::Recipient(_) => return Err(WhoChatError::WrongPassword),
+        };
+
+        let owner_password = &chat_rotate.new_password;
+        let read_password = chat_rotate.new_read_password.as_ref().filter(|p| !p.is_empty()).unwrap_or(owner_password);
+        let post_password = chat_rotate.new_post_password.as_ref().filter(|p| !p.is_empty()).unwrap_or(owner_password);
+        let (mut room, key) = new_room(read_password, post_password, owner_password)?;
+
+        for segment in &old_room.segments {
+            let mut content = decrypt_with_identity(&segment.ciphertext, identity)?;
+            let attachment = match &segment.attachment {
+                Some(old) => {
+                    let new = Attachment { id: generate_salt(ATTACHMENT_ID_SIZE), size: old.size };
+                    let data = decrypt_bytes_with_identity(&fs::read(attachment_path(&chat_rotate.name, &old.id))?, identity)?;
+                    write_atomically(&attachment_path(&chat_rotate.name, &new.id), &encrypt_bytes_to_recipient(&data, key.recipient())?)?;
+                    content = content.replacen(&format!("[attachment {}", old.id), &format!("[attachment {}", new.id), 1);
+                    Some(new)
+                },
+                None => None,
+            };
+            room.segments.push(Segment {
+                size: content.len(),
+                posted_at: segment.posted_at,
+                ciphertext: encrypt_to_recipient(&content, key.recipient())?,
+                attachment,
+            });
+        }
+        room.retention = old_room.retention.clone();
+        room.version = old_room.version;
+
+        write_room(&chat_rotate.name, &mut room)?;
+        prune_attachments(&chat_file_id(&chat_rotate.name), Some(&room))
+    })
+}
+
+
+fn create_room(chat_create: &ChatCreate) -> Result<(), WhoChatError> {
+    locked_room(&chat_create.name, || {
+        if Path::new(&chat_path(&chat_create.name)).exists() || Path::new(&e2e_path(&chat_create.name)).exists() {
+            return Err(WhoChatError::ChatAlreadyExists)
+        }
+
+        let (mut room, _key) = new_room(&chat_create.read_password, &chat_create.post_password, &chat_create.owner_password)?;
+        room.retention = chat_create.retention.clone();
+        write_room(&chat_create.name, &mut room)
+    })
+}
+
+
+// ----------------------------- Attachments -----------------------------
+
+fn attachment_path(name: &str, id: &str) -> String {
+    format!("{}/{}.{}.att", CHATS_DIR, chat_file_id(name), id)
+}
+
+
+// Removes the attachment files of a room that its segments no longer
+// reference, or all of them when the room is gone.
+fn prune_attachments(file_id: &str, room: Option<&Room>) -> Result<(), WhoChatError> {
+    let prefix = format!("{}.", file_id);
+    for entry in fs::read_dir(CHATS_DIR)? {
+        let path = entry?.path();
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let id = match file_name.strip_prefix(&prefix).and_then(|n| n.strip_suffix(".att")) {
+            Some(id) => id,
+            None => continue,
+        };
+        let referenced = room.is_some_and(|r| {
+            r.segments.iter().any(|s| s.attachment.as_ref().is_some_and(|a| a.id == id))
+        });
+        if !referenced {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+
+// Names end up in the chat text and in Content-Disposition, so brackets,
+// quotes and control characters are dropped.
+fn clean_filename(filename: &str) -> String {
+    let cleaned: String = filename
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '"' | '\\' | '[' | ']' | '/'))
+        .take(FILENAME_LIMIT)
+        .collect();
+    if cleaned.trim().is_empty() {
+        String::from("attachment")
+    } else {
+        cleaned
+    }
+}
+
+
+fn clean_content_type(content_type: &str) -> &'static str {
+    ATTACHMENT_TYPES
+        .iter()
+        .find(|t| t.eq_ignore_ascii_case(content_type))
+        .copied()
+        .unwrap_or("application/octet-stream")
+}
+
+
+// The file is stored as a JSON header with its name and type, a newline, then
+// the bytes, all encrypted to the room key. Posting an attachment needs the
+// post capability and an existing room.
+fn attach_file(chat_attach: &ChatAttach) -> Result<ChatEvent, WhoChatError> {
+    locked_room(&chat_attach.name, || {
+        let data = BASE64.decode(&chat_attach.data).map_err(|_e| WhoChatError::InvalidContent)?;
+        if data.is_empty() {
+            return Err(WhoChatError::EmptyContent)
+        }
+        if data.len() > attachment_limit() || data.len() > room_quota() {
+            return Err(WhoChatError::ContentTooLarge)
+        }
+
+        let mut room = load_room(&chat_attach.name, &chat_attach.password)?;
+        let key = unlock(&room, &chat_attach.password, Capability::Post)?;
+
+        let filename = clean_filename(&chat_attach.filename);
+        let header = serde_json::json!({
+            "filename": filename,
+            "content_type": clean_content_type(&chat_attach.content_type),
+        });
+        let mut plaintext = header.to_string().into_bytes();
+        plaintext.push(b'\n');
+        plaintext.extend_from_slice(&data);
+
+        let attachment = Attachment { id: generate_salt(ATTACHMENT_ID_SIZE), size: data.len() };
+        let encrypted = encrypt_bytes_to_recipient(&plaintext, key.recipient())?;
+        write_atomically(&attachment_path(&chat_attach.name, &attachment.id), &encrypted)?;
+
+        let reference = format!("[attachment {}: {}, {} bytes]\n", attachment.id, filename, data.len());
+        append_segment(&mut room, &key, &reference, Some(attachment))?;
+        write_room(&chat_attach.name, &mut room)?;
+        prune_attachments(&chat_file_id(&chat_attach.name), Some(&room))?;
+
+        let segment = room.segments.last().map(|s| s.ciphertext.clone()).unwrap_or_default();
+        Ok(ChatEvent::Update { version: room.version, segment })
+    })
+}
+
+
+// Returns the file name, content type and bytes of an attachment still
+// referenced by the room.
+fn read_attachment(chat_attachment: &ChatAttachment) -> Result<(String, String, Vec<u8>), WhoChatError> {
+    locked_room(&chat_attachment.name, || {
+        let room = load_room(&chat_attachment.name, &chat_attachment.password)?;
+        let identity = match unlock(&room, &chat_attachment.password, Capability::Read)? {
+            RoomKey::Identity(i) => i,
+            RoomKey::Recipient(_) => return Err(WhoChatError::WrongPassword),
+        };
+
+        let referenced = room.segments.iter()
+            .filter(|s| !is_expired(&room.retention, s))
+            .any(|s| s.attachment.as_ref().is_some_and(|a| a.id == chat_attachment.id));
+        if !referenced {
+            return Err(WhoChatError::AttachmentNotFound)
+        }
+
+        let encrypted = fs::read(attachment_path(&chat_attachment.name, &chat_attachment.id))?;
+        let plaintext = decrypt_bytes_with_identity(&encrypted, &identity)?;
+        let split = plaintext.iter().position(|b| *b == b'\n').ok_or(WhoChatError::DataCorruptionError)?;
+        let header: serde_json::Value = serde_json::from_slice(&plaintext[..split])?;
+
+        let filename = header["filename"].as_str().unwrap_or("attachment").to_string();
+        let content_type = clean_content_type(header["content_type"].as_str().unwrap_or("")).to_string();
+        touch_room(&chat_attachment.name)?;
+        Ok((filename, content_type, plaintext[split + 1..].to_vec()))
+    })
+}
+
+
+// Attachments do not fit the default JSON limit, so their body is read here,
+// up to what the largest allowed file takes in base64.
+async fn read_json_body<T: DeserializeOwned>(mut payload: web::Payload, limit: usize) -> Result<T, WhoChatError> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_e| WhoChatError::InvalidContent)?;
+        if body.len() + chunk.len() > limit {
+            return Err(WhoChatError::ContentTooLarge)
+        }
+        body.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&body).map_err(|_e| WhoChatError::InvalidContent)
+}
+
+
+// The read capability is enough to follow a room; unlike a get it does not
+// touch the room or burn a read-once one.
+fn open_stream(chat_access: &ChatAccess) -> Result<u64, WhoChatError> {
+    locked_room(&chat_access.name, || {
+        let room = load_room(&chat_access.name, &chat_access.password)?;
+        unlock(&room, &chat_access.password, Capability::Read)?;
+        Ok(room.version)
+    })
+}
+
+
+// Deletes rooms past their inactivity limit and temporary files left behind
+// by interrupted writes, and moves chats still stored under their plain name
+// to their hashed file. Legacy chats have no retention and are kept.
+fn sweep_expired_rooms(events: &ChatEvents) -> Result<(), WhoChatError> {
+    for entry in fs::read_dir(CHATS_DIR)? {
+        let mut path = entry?.path();
+        let mut extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_string();
+
+        if extension == "txt" {
+            let name = path.file_stem().and_then(|n| n.to_str()).unwrap_or("").to_string();
+            if validate_name(&name).is_err() {
+                continue;
+            }
+            locked_room(&name, || Ok(()))?;
+            path = Path::new(&chat_path(&name)).to_path_buf();
+            extension = String::from("room");
+        }
+
+        if extension == "tmp" {
+            let stale = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .is_some_and(|age| age > STALE_TMP_AGE);
+            if stale {
+                fs::remove_file(&path)?;
+            }
+            continue;
+        }
+
+        if extension == "att" {
+            let file_id = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.split('.').next()).unwrap_or("");
+            if !path.with_file_name(format!("{}.room", file_id)).exists() {
+                fs::remove_file(&path)?;
+            }
+            continue;
+        }
+
+        if extension != "room" {
+            continue;
+        }
+
+        let file_id = path.file_stem().and_then(|n| n.to_str()).unwrap_or("").to_string();
+        let deleted = locked(&file_id, || {
+            let data = match fs::read(&path) {
+                Ok(data) if !is_legacy(&data) => data,
+                _ => return Ok(false),
+            };
+            match serde_json::from_slice::<Room>(&data) {
+                Ok(room) if is_inactive(&room, &path) => {
+                    fs::remove_file(&path)?;
+                    prune_attachments(&file_id, None)?;
+                    Ok(true)
+                },
+                _ => Ok(false),
+            }
+        })?;
+        if deleted {
+            events.publish(&file_id, ChatEvent::Deleted);
+        }
+    }
+    enforce_storage_limits(events, None)
+}
+
+
+pub async fn run_sweeper(events: web::Data<ChatEvents>) {
+    let mut interval = actix_web::rt::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let events = events.clone();
+        if let Ok(Err(err)) = web::block(move || sweep_expired_rooms(&events)).await {
+            eprintln!("who_chat sweeper: {}", err);
+        }
+    }
+}
+
+
+// ------------------------- End-to-end rooms -------------------------
+
+// In end-to-end rooms the browser does all the cryptography and the server
+// never sees the password or the messages. Format 1 clients run PBKDF2-SHA256
+// over the password and the room salt to get 64 bytes: the first half is an
+// AES-256-GCM key that never leaves the browser, the second half is a token
+// proving the password. Segments are base64 of IV followed by ciphertext.
+// The server keeps only a keyed hash of the token, so its files do not hold
+// anything a password guess can be checked against offline.
+#[derive(Serialize, Deserialize)]
+struct E2eRoom {
+    format: u32,
+    salt: String,
+    verifier: String,
+    segments: Vec<String>,
+    #[serde(default)]
+    version: u64,
+}
+
+
+fn e2e_path(name: &str) -> String {
+    format!("{}/{}.e2e", CHATS_DIR, chat_file_id(name))
+}
+
+
+// Names are alphanumeric, so the prefix keeps token hashes apart from them.
+fn token_verifier(token: &str) -> String {
+    keyed_hash(&format!("e2e-token:{}", token))
+}
+
+
+fn is_base64(text: &str) -> bool {
+    !text.is_empty()
+        && text.len().is_multiple_of(4)
+        && text.trim_end_matches('=').chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+}
+
+
+fn load_e2e_room(name: &str) -> Result<E2eRoom, WhoChatError> {
+    let data = match fs::read(e2e_path(name)) {
+        Ok(v) => v,
+        Err(_e) => return Err(WhoChatError::ChatNotFound),
+    };
+    Ok(serde_json::from_slice(&data)?)
+}
+
+
+// Both sides are HMAC outputs, so comparing them leaks nothing about the token.
+fn unlock_e2e_room(name: &str, token: &str) -> Result<E2eRoom, WhoChatError> {
+    let room = load_e2e_room(name)?;
+    if room.verifier != token_verifier(token) {
+        return Err(WhoChatError::WrongPassword)
+    }
+    Ok(room)
+}
+
+
+fn write_e2e_room(name: &str, room: &mut E2eRoom) -> Result<(), WhoChatError> {
+    room.version += 1;
+    write_atomically(&e2e_path(name), &serde_json::to_vec(room)?)
+}
+
+
+fn create_e2e_room(e2e_create: &E2eCreate) -> Result<(), WhoChatError> {
+    locked_room(&e2e_create.name, || {
+        if e2e_create.format != E2E_FORMAT {
+            return Err(WhoChatError::UnsupportedFormat)
+        }
+        if e2e_create.salt.len() > E2E_SALT_LIMIT || !is_base64(&e2e_create.salt) {
+            return Err(WhoChatError::InvalidContent)
+        }
+        if Path::new(&chat_path(&e2e_create.name)).exists() || Path::new(&e2e_path(&e2e_create.name)).exists() {
+            return Err(WhoChatError::ChatAlreadyExists)
+        }
+
+        let mut room = E2eRoom {
+            format: e2e_create.format,
+            salt: e2e_create.salt.clone(),
+            verifier: token_verifier(&e2e_create.token),
+            segments: Vec::new(),
+            version: 0,
+        };
+        write_e2e_room(&e2e_create.name, &mut room)
+    })
+}
+
+
+// The server can not look inside a segment, so limits apply to the stored
+// ciphertext: one segment may not exceed E2E_SIZE_LIMIT and the oldest ones
+// are dropped once the room does.
+fn post_e2e_segment(e2e_post: &E2ePost) -> Result<ChatEvent, WhoChatError> {
+    locked_room(&e2e_post.name, || {
+        let mut room = unlock_e2e_room(&e2e_post.name, &e2e_post.token)?;
+        if e2e_post.format != room.format {
+            return Err(WhoChatError::UnsupportedFormat)
+        }
+        if !is_base64(&e2e_post.segment) {
+            return Err(WhoChatError::InvalidContent)
+        }
+        if e2e_post.segment.len() > E2E_SIZE_LIMIT {
+            return Err(WhoChatError::ContentTooLarge)
+        }
+
+        room.segments.push(e2e_post.segment.clone());
+        let mut total: usize = room.segments.iter().map(|s| s.len()).sum();
+        while total > E2E_SIZE_LIMIT {
+            total -= room.segments.remove(0).len();
+        }
+        write_e2e_room(&e2e_post.name, &mut room)?;
+
+        Ok(ChatEvent::Update { version: room.version, segment: e2e_post.segment.clone() })
+    })
+}
+
+
+fn delete_e2e_room(e2e_access: &E2eAccess) -> Result<(), WhoChatError> {
+    locked_room(&e2e_access.name, || {
+        unlock_e2e_room(&e2e_access.name, &e2e_access.token)?;
+        fs::remove_file(e2e_path(&e2e_access.name))?;
+        Ok(())
+    })
+}
+
+
+// ----------------------------- Archives -----------------------------
+
+// A room as stored on disk, still encrypted and without its name, which only
+// picks the file and is chosen again on import. The server never needs to
+// decrypt anything to export or import one.
+#[derive(Serialize, Deserialize)]
+struct RoomArchive {
+    archive_format: u32,
+    exported_at: u64,
+    room: ArchivedRoom,
+}
+
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum ArchivedRoom {
+    // Attachment files by id, base64 of their age ciphertext.
+    Server { room: Room, attachments: HashMap<String, String> },
+    // The token verifier is keyed to this server and is left out.
+    Browser { room: E2eRoom },
+}
+
+
+fn export_room(chat_access: &ChatAccess) -> Result<RoomArchive, WhoChatError> {
+    locked_room(&chat_access.name, || {
+        let room = load_room(&chat_access.name, &chat_access.password)?;
+        unlock(&room, &chat_access.password, Capability::Owner)?;
+
+        let mut attachments = HashMap::new();
+        for attachment in room.segments.iter().filter_map(|s| s.attachment.as_ref()) {
+            let data = fs::read(attachment_path(&chat_access.name, &attachment.id))?;
+            attachments.insert(attachment.id.clone(), BASE64.encode(data));
+        }
+        Ok(RoomArchive {
+            archive_format: ARCHIVE_FORMAT,
+            exported_at: now_secs(),
+            room: ArchivedRoom::Server { room, attachments },
+        })
+    })
+}
+
+
+fn export_e2e_room(e2e_access: &E2eAccess) -> Result<RoomArchive, WhoChatError> {
+    let mut room = unlock_e2e_room(&e2e_access.name, &e2e_access.token)?;
+    room.verifier = String::new();
+    Ok(RoomArchive {
+        archive_format: ARCHIVE_FORMAT,
+        exported_at: now_secs(),
+        room: ArchivedRoom::Browser { room },
+    })
+}
+
+
+// Imported rooms go through the same limits as rooms built here. A server
+// room must open with the given password as owner; a browser room gets the
+// given token as its new credential, since its old verifier is not portable.
+// Sizes in the archive are not trusted: they are measured again from the
+// ciphertexts, and an attachment's size then includes its metadata line.
+// Returns the bytes of attachment files written.
+fn import_room(chat_import: ChatImport) -> Result<u64, WhoChatError> {
+    let name = chat_import.name.clone();
+    locked_room(&name, move || {
+        if chat_import.archive.archive_format != ARCHIVE_FORMAT {
+            return Err(WhoChatError::UnsupportedFormat)
+        }
+        let name = &chat_import.name;
+        if Path::new(&chat_path(name)).exists() || Path::new(&e2e_path(name)).exists() {
+            return Err(WhoChatError::ChatAlreadyExists)
+        }
+
+        match chat_import.archive.room {
+            ArchivedRoom::Server { mut room, attachments } => {
+                if room.format != ROOM_FORMAT {
+                    return Err(WhoChatError::UnsupportedFormat)
+                }
+                validate_retention(&room.retention)?;
+                unlock(&room, &chat_import.password, Capability::Owner)?;
+
+                for segment in room.segments.iter_mut() {
+                    segment.size = age_plaintext_size(segment.ciphertext.as_bytes()).ok_or(WhoChatError::InvalidContent)?;
+                }
+
+                let mut ids = HashSet::new();
+                let mut files = Vec::new();
+                for attachment in room.segments.iter_mut().filter_map(|s| s.attachment.as_mut()) {
+                    let valid_id = attachment.id.len() == ATTACHMENT_ID_SIZE
+                        && attachment.id.chars().all(|c| c.is_ascii_alphanumeric())
+                        && ids.insert(attachment.id.clone());
+                    let data = match attachments.get(&attachment.id) {
+                        Some(data) if valid_id => BASE64.decode(data).map_err(|_e| WhoChatError::InvalidContent)?,
+                        _ => return Err(WhoChatError::InvalidContent),
+                    };
+                    attachment.size = age_plaintext_size(&data).ok_or(WhoChatError::InvalidContent)?;
+                    if attachment.size > attachment_limit() {
+                        return Err(WhoChatError::ContentTooLarge)
+                    }
+                    files.push((attachment_path(name, &attachment.id), data));
+                }
+
+                let text: usize = room.segments.iter().map(|s| s.size).sum();
+                let total: usize = room.segments.iter().map(segment_usage).sum();
+                if text > CONTENT_SIZE_LIMIT || total > room_quota() {
+                    return Err(WhoChatError::ContentTooLarge)
+                }
+                let mut attached = 0;
+                for (path, data) in files {
+                    write_atomically(&path, &data)?;
+                    attached += data.len() as u64;
+                }
+                write_room(name, &mut room)?;
+                Ok(attached)
+            },
+            ArchivedRoom::Browser { mut room } => {
+                if room.format != E2E_FORMAT {
+                    return Err(WhoChatError::UnsupportedFormat)
+                }
+                if room.salt.len() > E2E_SALT_LIMIT || !is_base64(&room.salt) || !room.segments.iter().all(|s| is_base64(s)) {
+                    return Err(WhoChatError::InvalidContent)
+                }
+                if room.segments.iter().map(|s| s.len()).sum::<usize>() > E2E_SIZE_LIMIT {
+                    return Err(WhoChatError::ContentTooLarge)
+                }
+                room.verifier = token_verifier(&chat_import.password);
+                write_e2e_room(name, &mut room)?;
+                Ok(0)
+            },
+        }
+    })
+}
+
+
+fn archive_response(archive: RoomArchive) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Content-Disposition", "attachment; filename=\"who_chat_archive.json\""))
+        .json(archive)
+}
+
+
+// --------------------------- Storage limits ---------------------------
+
+static EVICTED_ROOMS: AtomicU64 = AtomicU64::new(0);
+
+// Rooms and bytes in bucket/chats as of the last scan, plus what new rooms
+// and attachments added since. Deletions only show up at the next scan, so
+// the total errs high and at worst brings a scan forward.
+static USAGE: Mutex<Option<(usize, u64)>> = Mutex::new(None);
+
+
+fn max_rooms() -> usize {
+    static MAX: OnceLock<usize> = OnceLock::new();
+    *MAX.get_or_init(|| env_limit(MAX_ROOMS_VAR, MAX_ROOMS_DEFAULT))
+}
+
+
+fn max_bytes() -> usize {
+    static MAX: OnceLock<usize> = OnceLock::new();
+    *MAX.get_or_init(|| env_limit(MAX_BYTES_VAR, MAX_BYTES_DEFAULT))
+}
+
+
+// Everything one room keeps on disk. Attachment files whose room is gone
+// form a group with no kind, which the sweeper removes anyway.
+struct StoredRoom {
+    file_id: String,
+    kind: Option<&'static str>,
+    attachments: usize,
+    bytes: u64,
+    last_used: SystemTime,
+    paths: Vec<PathBuf>,
+}
+
+
+#[derive(Serialize, Default)]
+struct StorageStats {
+    rooms: usize,
+    server_rooms: usize,
+    browser_rooms: usize,
+    legacy_rooms: usize,
+    attachments: usize,
+    bytes: u64,
+    max_rooms: usize,
+    max_bytes: usize,
+    evicted_rooms: u64,
+}
+
+
+// Groups the files in bucket/chats by room. Legacy chats are keyed by the
+// hash of their name, like the events of the room they will become.
+fn scan_storage() -> Result<Vec<StoredRoom>, WhoChatError> {
+    let mut rooms: HashMap<String, StoredRoom> = HashMap::new();
+    for entry in fs::read_dir(CHATS_DIR)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let stem = file_name.split('.').next().unwrap_or("");
+
+        let (file_id, kind) = match extension {
+            "room" => (stem.to_string(), Some("server")),
+            "e2e" => (stem.to_string(), Some("browser")),
+            "txt" => (chat_file_id(stem), Some("legacy")),
+            "att" => (stem.to_string(), None),
+            _ => continue,
+        };
+        let metadata = entry.metadata()?;
+
+        let room = rooms.entry(file_id.clone()).or_insert(StoredRoom {
+            file_id,
+            kind: None,
+            attachments: 0,
+            bytes: 0,
+            last_used: UNIX_EPOCH,
+            paths: Vec::new(),
+        });
+        room.bytes += metadata.len();
+        room.paths.push(path);
+        match kind {
+            Some(kind) => {
+                room.kind = Some(kind);
+                room.last_used = metadata.modified()?;
+            },
+            None => room.attachments += 1,
+        }
+    }
+    Ok(rooms.into_values().collect())
+}
+
+
+fn storage_stats() -> Result<StorageStats, WhoChatError> {
+    let mut stats = StorageStats {
+        max_rooms: max_rooms(),
+        max_bytes: max_bytes(),
+        evicted_rooms: EVICTED_ROOMS.load(Ordering::Relaxed),
+        ..StorageStats::default()
+    };
+    for room in scan_storage()? {
+        match room.kind {
+            Some("server") => stats.server_rooms += 1,
+            Some("browser") => stats.browser_rooms += 1,
+            Some(_) => stats.legacy_rooms += 1,
+            None => (),
+        }
+        stats.attachments += room.attachments;
+        stats.bytes += room.bytes;
+    }
+    stats.rooms = stats.server_rooms + stats.browser_rooms + stats.legacy_rooms;
+    Ok(stats)
+}
+
+
+// Picks the least recently used rooms, as told by their file times, to
+// delete until there are at most `max_rooms` of them in at most `max_bytes`.
+// `keep`, the room just written, is never picked.
+fn pick_evictions(mut rooms: Vec<StoredRoom>, keep: Option<&str>, max_rooms: usize, max_bytes: u64) -> Vec<StoredRoom> {
+    let mut count = rooms.iter().filter(|r| r.kind.is_some()).count();
+    let mut total: u64 = rooms.iter().map(|r| r.bytes).sum();
+
+    rooms.sort_by_key(|r| r.last_used);
+    let mut evicted = Vec::new();
+    for room in rooms {
+        if count <= max_rooms && total <= max_bytes {
+            break;
+        }
+        if keep == Some(room.file_id.as_str()) {
+            continue;
+        }
+        total -= room.bytes;
+        if room.kind.is_some() {
+            count -= 1;
+        }
+        evicted.push(room);
+    }
+    evicted
+}
+
+
+// Scans bucket/chats and, when it holds more than max_rooms() rooms or
+// max_bytes() bytes, evicts rooms down to the low-water mark. Runs on every
+// sweep and whenever the running total goes over a limit.
+fn enforce_storage_limits(events: &ChatEvents, keep: Option<&str>) -> Result<(), WhoChatError> {
+    let rooms = scan_storage()?;
+    let mut count = rooms.iter().filter(|r| r.kind.is_some()).count();
+    let mut total: u64 = rooms.iter().map(|r| r.bytes).sum();
+
+    if count > max_rooms() || total > max_bytes() as u64 {
+        let low_rooms = max_rooms() * STORAGE_LOW_WATER_PERCENT / 100;
+        let low_bytes = (max_bytes() * STORAGE_LOW_WATER_PERCENT / 100) as u64;
+        for room in pick_evictions(rooms, keep, low_rooms, low_bytes) {
+            locked(&room.file_id, || {
+                for path in &room.paths {
+                    match fs::remove_file(path) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => (),
+                    }
+                }
+                Ok(())
+            })?;
+            total -= room.bytes;
+            if room.kind.is_some() {
+                count -= 1;
+                EVICTED_ROOMS.fetch_add(1, Ordering::Relaxed);
+                events.publish(&room.file_id, ChatEvent::Deleted);
+            }
+        }
+    }
+
+    *USAGE.lock().unwrap_or_else(|e| e.into_inner()) = Some((count, total));
+    Ok(())
+}
+
 
+// Adds a new room or attachment to the running total, scanning only when
+// there is no total yet or it went over a limit.
+fn track_storage(events: &ChatEvents, keep: &str, rooms: usize, bytes: u64) -> Result<(), WhoChatError> {
+    let over = match USAGE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        Some((count, total)) => {
+            *count += rooms;
+            *total += bytes;
+            *count > max_rooms() || *total > max_bytes() as u64
+        },
+        None => true,
+    };
+    if over {
+        return enforce_storage_limits(events, Some(keep))
+    }
+    Ok(())
+}
 
-#[derive(Deserialize)]
-struct ChatAccess {
-    name: String,
-    password: String,
+
+// Each client IP may create CREATION_LIMIT rooms per CREATION_WINDOW,
+// counted whether or not the creation goes through.
+fn check_creation(limiter: &RateLimiter, req: &HttpRequest) -> Result<(), Box<HttpResponse>> {
+    let key = format!("who_chat:create:ip:{}", rate_limit::client_ip(req));
+    limiter.take(&key, CREATION_LIMIT, CREATION_WINDOW)
+        .map_err(|retry_after| Box::new(rate_limit::too_many_requests(retry_after)))
 }
 
 
-#[derive(Deserialize)]
-struct ChatPost {
-    name: String,
-    password: String,
-    content: String,
+async fn record_creation(events: &web::Data<ChatEvents>, name: &str) {
+    check_storage(events, name, true, 0).await;
 }
 
 
-trait Credentials {
-    fn name(&self) -> &String;
-    fn password(&self) -> &String;
+// Counts what a request added against the storage limits: the files of a
+// room it `created`, plus `attached` bytes of attachment files. For a new
+// attachment the length of the base64 upload stands in for the slightly
+// smaller file; an import passes the sizes of the files it wrote.
+async fn check_storage(events: &web::Data<ChatEvents>, name: &str, created: bool, attached: u64) {
+    let events = events.clone();
+    let name = name.to_string();
+    let result = blocking(move || {
+        let mut bytes = attached;
+        if created {
+            bytes += [chat_path(&name), e2e_path(&name)].iter()
+                .filter_map(|path| fs::metadata(path).ok())
+                .map(|m| m.len())
+                .sum::<u64>();
+        }
+        track_storage(&events, &chat_file_id(&name), usize::from(created), bytes)
+    }).await;
+    if let Err(err) = result {
+        eprintln!("who_chat storage limits: {}", err);
+    }
 }
 
 
-impl Credentials for ChatAccess {
-    fn name(&self) -> &String {
-        &self.name
+// The admin password is read once at first use and only its keyed hash is
+// kept, which verify_slice compares in constant time. No admin password
+// file, no admin access; changing it takes a restart.
+fn is_admin(password: &str) -> bool {
+    static ADMIN: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+    let admin = ADMIN.get_or_init(|| {
+        let password = fs::read_to_string(ADMIN_PASSWORD_PATH).ok()?;
+        let password = password.trim();
+        (!password.is_empty()).then(|| keyed_mac(password).finalize().into_bytes().to_vec())
+    });
+    match admin {
+        Some(tag) => keyed_mac(password).verify_slice(tag).is_ok(),
+        None => false,
     }
+}
 
-    fn password(&self) -> &String {
-        &self.password
+
+// ----------------------------- Live updates -----------------------------
+
+#[derive(Clone)]
+pub enum ChatEvent {
+    // A new message: the room version and its still-encrypted segment.
+    Update { version: u64, segment: String },
+    Rotated,
+    Deleted,
+}
+
+
+impl ChatEvent {
+    fn to_sse(&self) -> String {
+        match self {
+            ChatEvent::Update { version, segment } => format!(
+                "event: update\ndata: {}\n\n",
+                serde_json::json!({ "version": version, "segment": segment }),
+            ),
+            ChatEvent::Rotated => String::from("event: rotated\ndata: {}\n\n"),
+            ChatEvent::Deleted => String::from("event: deleted\ndata: {}\n\n"),
+        }
     }
 }
 
 
-impl Credentials for ChatPost {
-    fn name(&self) -> &String {
-        &self.name
+// One broadcast channel per room file with open streams, created by the first
+// subscriber and dropped once nobody listens.
+#[derive(Default)]
+pub struct ChatEvents {
+    channels: Mutex<HashMap<String, broadcast::Sender<ChatEvent>>>,
+}
+
+
+impl ChatEvents {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn password(&self) -> &String {
-        &self.password
+    fn subscribe(&self, file_id: &str) -> broadcast::Receiver<ChatEvent> {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels
+            .entry(file_id.to_string())
+            .or_insert_with(|| broadcast::channel(EVENT_BUFFER).0)
+            .subscribe()
+    }
+
+    fn publish(&self, file_id: &str, event: ChatEvent) {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+        let delivered = match channels.get(file_id) {
+            Some(sender) => sender.send(event).is_ok(),
+            None => return,
+        };
+        if !delivered {
+            channels.remove(file_id);
+        }
     }
 }
 
 
-enum WhoChatError {
-    WrongPassword,
-    ChatNotFound,
-    InternalServerError,
-    DataCorruptionError,
-    InvalidName,
-    EmptyPassword,
-    EmptyContent,
+// Server-sent events for one room: a `ready` event with the current version,
+// then `update`, `rotated` or `deleted` as they happen, with comment lines as
+// heartbeats. The stream ends after rotation or deletion since the client's
+// key no longer opens the room.
+fn event_stream(receiver: broadcast::Receiver<ChatEvent>, version: u64) -> impl futures_util::Stream<Item = Result<Bytes, actix_web::Error>> {
+    let ready = format!("event: ready\ndata: {}\n\n", serde_json::json!({ "version": version }));
+    let first = futures_util::stream::once(async move { Ok(Bytes::from(ready)) });
+
+    // The receiver is the stream state; it is dropped once the room is gone.
+    let rest = futures_util::stream::unfold(Some(receiver), |receiver| async move {
+        let mut receiver = receiver?;
+        let event = match actix_web::rt::time::timeout(STREAM_HEARTBEAT, receiver.recv()).await {
+            Err(_elapsed) => return Some((Ok(Bytes::from_static(b": heartbeat\n\n")), Some(receiver))),
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => return Some((Ok(Bytes::from_static(b"event: resync\ndata: {}\n\n")), Some(receiver))),
+            Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+            Ok(Ok(event)) => event,
+        };
+        let receiver = matches!(event, ChatEvent::Update { .. }).then_some(receiver);
+        Some((Ok(Bytes::from(event.to_sse())), receiver))
+    });
+
+    futures_util::StreamExt::chain(first, rest)
 }
 
 
-impl std::fmt::Display for WhoChatError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            WhoChatError::WrongPassword => write!(f, "wrong password"),
-            WhoChatError::ChatNotFound => write!(f, "chat not found"),
-            WhoChatError::InternalServerError => write!(f, "internal server error"),
-            WhoChatError::DataCorruptionError => write!(f, "data corruption"),
-            WhoChatError::InvalidName => write!(f, "invalid name"),
-            WhoChatError::EmptyPassword => write!(f, "empty password"),
-            WhoChatError::EmptyContent => write!(f, "empty content"),
-        }
+// Passphrase checks run scrypt and everything touches the disk, so room
+// operations go to the blocking pool instead of holding up a worker.
+async fn blocking<T: Send + 'static>(operation: impl FnOnce() -> Result<T, WhoChatError> + Send + 'static) -> Result<T, WhoChatError> {
+    web::block(operation).await.unwrap_or(Err(WhoChatError::InternalServerError))
+}
+
+
+// Runs a password-checked operation under an attempt limit per room and
+// client IP. Keying on the room alone would let anyone lock its members out
+// for fifteen minutes with a handful of wrong guesses; the price is that guesses
+// spread over many IPs are only slowed by the per-IP limit of the middleware
+// and the cost of each scrypt unlock. The response is boxed, it is several
+// times larger than any Ok value.
+async fn guarded<T: Send + 'static>(
+    limiter: &RateLimiter,
+    req: &HttpRequest,
+    name: &str,
+    operation: impl FnOnce() -> Result<T, WhoChatError> + Send + 'static,
+) -> Result<T, Box<HttpResponse>> {
+    let key = format!("who_chat:room:{}:ip:{}", chat_file_id(name), rate_limit::client_ip(req));
+    if let Err(retry_after) = limiter.check(&key) {
+        return Err(Box::new(rate_limit::too_many_requests(retry_after)));
+    }
+
+    match blocking(operation).await {
+        Ok(v) => {
+            limiter.record_success(&key);
+            Ok(v)
+        },
+        Err(WhoChatError::WrongPassword) => {
+            limiter.record_failure(&key);
+            Err(Box::new(error_response(WhoChatError::WrongPassword)))
+        },
+        Err(err) => Err(Box::new(error_response(err))),
     }
 }
 
 
-impl std::convert::From<age::EncryptError> for WhoChatError {
-    fn from(_v: age::EncryptError) -> Self {
-        WhoChatError::InternalServerError
+fn validate_name(name: &str) -> Result<(), WhoChatError> {
+    if !name.chars().all(char::is_alphanumeric) || name.is_empty() {
+        return Err(WhoChatError::InvalidName)
     }
+
+    Ok(())
 }
 
 
-impl std::convert::From<age::DecryptError> for WhoChatError {
-    fn from(_v: age::DecryptError) -> Self {
-        WhoChatError::WrongPassword
+fn validate_credentials<T: Credentials>(chat_credentials: &T) -> Result<(), WhoChatError> {
+    validate_name(chat_credentials.name())?;
+
+    if chat_credentials.password().is_empty() {
+        return Err(WhoChatError::EmptyPassword)
     }
+
+    Ok(())
 }
 
 
-impl std::convert::From<std::io::Error> for WhoChatError {
-    fn from(_v: std::io::Error) -> Self {
-        WhoChatError::InternalServerError
+#[post("/who_chat/get", wrap = "RateLimit::new(\"who_chat\")")]
+async fn get_chat(req: HttpRequest, chat_access: web::Json<ChatAccess>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let chat_access = chat_access.into_inner();
+    if let Err(err) = validate_credentials(&chat_access) {
+        return error_response(err);
+    }
+
+    let name = chat_access.name.clone();
+    match guarded(&limiter, &req, &name, move || get_contents(&chat_access, &events)).await {
+        Ok(contents) => HttpResponse::Ok().content_type("text/plain").body(contents),
+        Err(response) => *response,
     }
 }
 
 
-impl std::convert::From<std::string::FromUtf8Error> for WhoChatError {
-    fn from(_v: std::string::FromUtf8Error) -> Self {
-        WhoChatError::DataCorruptionError
+#[post("/who_chat/post", wrap = "RateLimit::new(\"who_chat\")")]
+async fn post_chat(req: HttpRequest, chat_post: web::Json<ChatPost>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let chat_post = chat_post.into_inner();
+    if let Err(err) = validate_credentials(&chat_post) {
+        return error_response(err);
+    }
+
+    if chat_post.content.is_empty() {
+        return error_response(WhoChatError::EmptyContent);
+    }
+
+    let name = chat_post.name.clone();
+    let lookup = name.clone();
+    let creating = match blocking(move || Ok(!Path::new(&chat_path(&lookup)).exists() && !Path::new(&legacy_path(&lookup)).exists())).await {
+        Ok(v) => v,
+        Err(err) => return error_response(err),
+    };
+    if creating {
+        if let Err(response) = check_creation(&limiter, &req) {
+            return *response;
+        }
+    }
+
+    match guarded(&limiter, &req, &name, move || post_content(&chat_post)).await {
+        Ok(event) => {
+            if creating {
+                record_creation(&events, &name).await;
+            }
+            events.publish(&chat_file_id(&name), event);
+            HttpResponse::Ok().content_type("text/plain").body("Posted!")
+        },
+        Err(response) => *response,
     }
 }
 
 
-fn generate_salt(length: usize) -> String {
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(length)
-        .map(char::from)
-        .collect()
+#[post("/who_chat/delete", wrap = "RateLimit::new(\"who_chat\")")]
+async fn delete_chat(req: HttpRequest, chat_access: web::Json<ChatAccess>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let chat_access = chat_access.into_inner();
+    if let Err(err) = validate_credentials(&chat_access) {
+        return error_response(err);
+    }
+
+    let name = chat_access.name.clone();
+    match guarded(&limiter, &req, &name, move || delete_data(&chat_access)).await {
+        Ok(_val) => {
+            events.publish(&chat_file_id(&name), ChatEvent::Deleted);
+            HttpResponse::Ok().content_type("text/plain").body("Deleted!")
+        },
+        Err(response) => *response,
+    }
 }
 
 
-fn encrypt(content: &String, password: &String, salt: &String) -> Result<Vec<u8>, WhoChatError> {
-    let key = format!("{}{}",&password,&salt);
-    let encryptor = age::Encryptor::with_user_passphrase(Secret::new(key.to_owned()));
-    let mut encrypted = vec![];
-    let mut writer = encryptor.wrap_output(&mut encrypted)?;
-    writer.write_all(content.as_bytes())?;
-    writer.finish()?;
+#[post("/who_chat/create")]
+async fn create_chat(req: HttpRequest, chat_create: web::Json<ChatCreate>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let chat_create = chat_create.into_inner();
+    if let Err(err) = validate_name(&chat_create.name) {
+        return error_response(err);
+    }
 
-    Ok(encrypted)
+    let passwords = [&chat_create.read_password, &chat_create.post_password, &chat_create.owner_password];
+    if passwords.iter().any(|p| p.is_empty()) {
+        return error_response(WhoChatError::EmptyPassword);
+    }
+
+    if let Err(err) = validate_retention(&chat_create.retention) {
+        return error_response(err);
+    }
+
+    if let Err(response) = check_creation(&limiter, &req) {
+        return *response;
+    }
+
+    let name = chat_create.name.clone();
+    match blocking(move || create_room(&chat_create)).await {
+        Ok(_val) => {
+            record_creation(&events, &name).await;
+            HttpResponse::Ok().content_type("text/plain").body("Created!")
+        },
+        Err(err) => error_response(err),
+    }
 }
 
 
-fn decrypt(data: &Vec<u8>, password: &String, salt: &String) -> Result<String, WhoChatError> {
-    let key = format!("{}{}",&password,&salt);
-    let decryptor = match age::Decryptor::new(&data[..])? {
-        age::Decryptor::Passphrase(d) => d,
-        _ => unreachable!(),
-    };
+#[post("/who_chat/rotate", wrap = "RateLimit::new(\"who_chat\")")]
+async fn rotate_chat(req: HttpRequest, chat_rotate: web::Json<ChatRotate>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let chat_rotate = chat_rotate.into_inner();
+    if let Err(err) = validate_credentials(&chat_rotate) {
+        return error_response(err);
+    }
 
-    let mut decrypted = vec![];
-    let mut reader: age::stream::StreamReader<&[u8]> = decryptor.decrypt(&Secret::new(key.to_owned()), None)?;
-    reader.read_to_end(&mut decrypted)?;
+    if chat_rotate.new_password.is_empty() {
+        return error_response(WhoChatError::EmptyPassword);
+    }
 
-    Ok(String::from_utf8(decrypted)?)
+    let name = chat_rotate.name.clone();
+    match guarded(&limiter, &req, &name, move || rotate_room(&chat_rotate)).await {
+        Ok(_val) => {
+            events.publish(&chat_file_id(&name), ChatEvent::Rotated);
+            HttpResponse::Ok().content_type("text/plain").body("Rotated!")
+        },
+        Err(response) => *response,
+    }
 }
 
 
-fn read_data(name: &String) -> Result<Vec<u8>, WhoChatError> {
-    let path = format!("bucket/chats/{}.txt", &name);
-    let mut file = match File::open(path) {
+#[post("/who_chat/attach", wrap = "RateLimit::new(\"who_chat\")")]
+async fn attach_chat(req: HttpRequest, payload: web::Payload, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let chat_attach: ChatAttach = match read_json_body(payload, attachment_limit() / 3 * 4 + 4096).await {
         Ok(v) => v,
-        Err(_e) => return Err(WhoChatError::ChatNotFound),
+        Err(err) => return error_response(err),
     };
-    let mut data = Vec::new();
-    file.read_to_end(&mut data)?;
-    Ok(data)
+    if let Err(err) = validate_credentials(&chat_attach) {
+        return error_response(err);
+    }
+
+    let name = chat_attach.name.clone();
+    let attached = chat_attach.data.len() as u64;
+    match guarded(&limiter, &req, &name, move || attach_file(&chat_attach)).await {
+        Ok(event) => {
+            check_storage(&events, &name, false, attached).await;
+            events.publish(&chat_file_id(&name), event);
+            HttpResponse::Ok().content_type("text/plain").body("Attached!")
+        },
+        Err(response) => *response,
+    }
 }
 
 
-fn get_salt_from_data(data: &Vec<u8>) -> Result<String, WhoChatError> {
-    if data.len() < SALT_SIZE {
-        return Err(WhoChatError::DataCorruptionError)
+#[post("/who_chat/attachment", wrap = "RateLimit::new(\"who_chat\")")]
+async fn get_attachment(req: HttpRequest, chat_attachment: web::Json<ChatAttachment>, limiter: web::Data<RateLimiter>) -> impl Responder {
+    let chat_attachment = chat_attachment.into_inner();
+    if let Err(err) = validate_credentials(&chat_attachment) {
+        return error_response(err);
+    }
+    if !chat_attachment.id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return error_response(WhoChatError::AttachmentNotFound);
     }
 
-    Ok(String::from_utf8(data[0..SALT_SIZE].to_vec())?)
+    let name = chat_attachment.name.clone();
+    match guarded(&limiter, &req, &name, move || read_attachment(&chat_attachment)).await {
+        Ok((filename, content_type, data)) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename.replace(|c: char| !c.is_ascii(), "_"))))
+            .insert_header(("X-Content-Type-Options", "nosniff"))
+            .body(data),
+        Err(response) => *response,
+    }
 }
 
 
-fn get_contents_from_data(data: &Vec<u8>, password: &String) -> Result<String, WhoChatError> {
-    if data.len() <= SALT_SIZE {
-        return Err(WhoChatError::DataCorruptionError)
+#[post("/who_chat/export", wrap = "RateLimit::new(\"who_chat\")")]
+async fn export_chat(req: HttpRequest, chat_access: web::Json<ChatAccess>, limiter: web::Data<RateLimiter>) -> impl Responder {
+    let chat_access = chat_access.into_inner();
+    if let Err(err) = validate_credentials(&chat_access) {
+        return error_response(err);
     }
 
-    let salt = get_salt_from_data(data)?;
-    let encrypted_data = &data[SALT_SIZE..];
-    decrypt(&encrypted_data.to_vec(), &password, &salt)
+    let name = chat_access.name.clone();
+    match guarded(&limiter, &req, &name, move || export_room(&chat_access)).await {
+        Ok(archive) => archive_response(archive),
+        Err(response) => *response,
+    }
 }
 
 
-fn delete_data(chat_access: &ChatAccess) -> Result<(), WhoChatError> {
-    let data = read_data(&chat_access.name)?;
+// Takes archives of both kinds; for browser rooms the password is the token.
+#[post("/who_chat/import", wrap = "RateLimit::new(\"who_chat\")")]
+async fn import_chat(req: HttpRequest, payload: web::Payload, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let chat_import: ChatImport = match read_json_body(payload, room_quota() / 3 * 4 + 64_000).await {
+        Ok(v) => v,
+        Err(err) => return error_response(err),
+    };
+    if let Err(err) = validate_credentials(&chat_import) {
+        return error_response(err);
+    }
+
+    if let Err(response) = check_creation(&limiter, &req) {
+        return *response;
+    }
 
-    match get_contents_from_data(&data, &chat_access.password) {
-        Ok(_contents) => {
-            fs::remove_file(format!("bucket/chats/{}.txt", &chat_access.name))?;
-            Ok(())
+    let name = chat_import.name.clone();
+    match blocking(move || import_room(chat_import)).await {
+        Ok(attached) => {
+            check_storage(&events, &name, true, attached).await;
+            HttpResponse::Ok().content_type("text/plain").body("Imported!")
         },
-        Err(err) => Err(err),
+        Err(err) => error_response(err),
     }
 }
 
 
-fn truncate_start_string(content: &String) -> String {
-    let mut start_idx = content.chars().count();
-    let mut curr_len: usize= 0;
-    for c in content.chars().rev() {
-        curr_len += c.len_utf8();
-        start_idx -= 1;
-
-        if curr_len > CONTENT_SIZE_LIMIT {
-            start_idx += 1;
-            break;
-        }
+#[post("/who_chat/stream", wrap = "RateLimit::new(\"who_chat\")")]
+async fn stream_chat(req: HttpRequest, chat_access: web::Json<ChatAccess>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let chat_access = chat_access.into_inner();
+    if let Err(err) = validate_credentials(&chat_access) {
+        return error_response(err);
     }
 
-    return content.chars().skip(start_idx).collect()
+    // Subscribe before checking the password so no post slips in between.
+    let name = chat_access.name.clone();
+    let receiver = events.subscribe(&chat_file_id(&name));
+    match guarded(&limiter, &req, &name, move || open_stream(&chat_access)).await {
+        Ok(version) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .insert_header(("X-Accel-Buffering", "no"))
+            .streaming(event_stream(receiver, version)),
+        Err(response) => *response,
+    }
 }
 
 
-fn write_content(chat_name: &String, password: &String, content: &String, salt: &String) -> Result<(), WhoChatError> {
-    let content_truncated = truncate_start_string(&content);
+// The salt and format are public so the browser can derive the room key.
+#[post("/who_chat/e2e/salt")]
+async fn salt_e2e_chat(e2e_name: web::Json<E2eName>) -> impl Responder {
+    let e2e_name = e2e_name.into_inner();
+    if let Err(err) = validate_name(&e2e_name.name) {
+        return error_response(err);
+    }
 
-    let path = format!("bucket/chats/{}.txt", &chat_name);
-    let mut file: File = File::create(path)?;
-    file.write_all(&salt.as_bytes())?;
-    match encrypt(&content_truncated, &password, salt) {
-        Ok(data) => {
-            file.write(&data)?;
-            Ok(())
-        },
-        Err(e) => Err(e),
+    match blocking(move || load_e2e_room(&e2e_name.name)).await {
+        Ok(room) => HttpResponse::Ok().json(serde_json::json!({ "format": room.format, "salt": room.salt })),
+        Err(err) => error_response(err),
     }
 }
 
 
-fn write_first_content(chat_post: &ChatPost) -> Result<(), WhoChatError> {
-    write_content(&chat_post.name, &chat_post.password, &chat_post.content, &generate_salt(SALT_SIZE))
-}
+#[post("/who_chat/e2e/create")]
+async fn create_e2e_chat(req: HttpRequest, e2e_create: web::Json<E2eCreate>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let e2e_create = e2e_create.into_inner();
+    if let Err(err) = validate_credentials(&e2e_create) {
+        return error_response(err);
+    }
 
+    if let Err(response) = check_creation(&limiter, &req) {
+        return *response;
+    }
 
-fn append_content(chat_post: &ChatPost) -> Result<(), WhoChatError> {
-    let data = read_data(&chat_post.name)?;
-    let salt = get_salt_from_data(&data)?;
-    let content = get_contents_from_data(&data, &chat_post.password)?;
-    let new_content = format!("{}{}", &content, &chat_post.content);
-    write_content(&chat_post.name, &chat_post.password, &new_content, &salt)
+    let name = e2e_create.name.clone();
+    match blocking(move || create_e2e_room(&e2e_create)).await {
+        Ok(_val) => {
+            record_creation(&events, &name).await;
+            HttpResponse::Ok().content_type("text/plain").body("Created!")
+        },
+        Err(err) => error_response(err),
+    }
 }
 
 
-fn validate_credentials<T: Credentials>(chat_credentials: &T) -> Result<(), WhoChatError> {
-    if !chat_credentials.name().chars().all(char::is_alphanumeric) || chat_credentials.name().len() == 0 {
-        return Err(WhoChatError::InvalidName)
+#[post("/who_chat/e2e/get", wrap = "RateLimit::new(\"who_chat\")")]
+async fn get_e2e_chat(req: HttpRequest, e2e_access: web::Json<E2eAccess>, limiter: web::Data<RateLimiter>) -> impl Responder {
+    let e2e_access = e2e_access.into_inner();
+    if let Err(err) = validate_credentials(&e2e_access) {
+        return error_response(err);
     }
 
-    if chat_credentials.password().len() == 0 {
-        return Err(WhoChatError::EmptyPassword)
+    let name = e2e_access.name.clone();
+    match guarded(&limiter, &req, &name, move || unlock_e2e_room(&e2e_access.name, &e2e_access.token)).await {
+        Ok(room) => HttpResponse::Ok().json(serde_json::json!({
+            "format": room.format,
+            "version": room.version,
+            "segments": room.segments,
+        })),
+        Err(response) => *response,
     }
-
-    Ok(())
 }
 
 
-#[post("/who_chat/get")]
-async fn get_chat(chat_access: web::Json<ChatAccess>) -> impl Responder {
-    match validate_credentials(&chat_access.0) {
-        Ok(_v) => (),
-        Err(err) => return HttpResponse::BadRequest().content_type("text/plain").body(format!("Error: {}", err)),
-    };
+#[post("/who_chat/e2e/post", wrap = "RateLimit::new(\"who_chat\")")]
+async fn post_e2e_chat(req: HttpRequest, e2e_post: web::Json<E2ePost>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let e2e_post = e2e_post.into_inner();
+    if let Err(err) = validate_credentials(&e2e_post) {
+        return error_response(err);
+    }
 
-    let data = match read_data(&chat_access.name) {
-        Ok(v) => v,
-        Err(err) => return HttpResponse::NotFound().content_type("text/plain").body(format!("Error: {}", err)),
-    };
+    let name = e2e_post.name.clone();
+    match guarded(&limiter, &req, &name, move || post_e2e_segment(&e2e_post)).await {
+        Ok(event) => {
+            events.publish(&chat_file_id(&name), event);
+            HttpResponse::Ok().content_type("text/plain").body("Posted!")
+        },
+        Err(response) => *response,
+    }
+}
 
-    match get_contents_from_data(&data, &chat_access.password) {
-        Ok(contents) => HttpResponse::Ok().content_type("text/plain").body(contents),
-        Err(err) => HttpResponse::InternalServerError().content_type("text/plain").body(format!("Error: {}", err)),
+
+#[post("/who_chat/e2e/delete", wrap = "RateLimit::new(\"who_chat\")")]
+async fn delete_e2e_chat(req: HttpRequest, e2e_access: web::Json<E2eAccess>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let e2e_access = e2e_access.into_inner();
+    if let Err(err) = validate_credentials(&e2e_access) {
+        return error_response(err);
+    }
+
+    let name = e2e_access.name.clone();
+    match guarded(&limiter, &req, &name, move || delete_e2e_room(&e2e_access)).await {
+        Ok(_val) => {
+            events.publish(&chat_file_id(&name), ChatEvent::Deleted);
+            HttpResponse::Ok().content_type("text/plain").body("Deleted!")
+        },
+        Err(response) => *response,
     }
 }
 
 
-#[post("/who_chat/post")]
-async fn post_chat(chat_post: web::Json<ChatPost>) -> impl Responder {
-    match validate_credentials(&chat_post.0) {
-        Ok(_v) => (),
-        Err(err) => return HttpResponse::BadRequest().content_type("text/plain").body(format!("Error: {}", err)),
-    };
+#[post("/who_chat/e2e/export", wrap = "RateLimit::new(\"who_chat\")")]
+async fn export_e2e_chat(req: HttpRequest, e2e_access: web::Json<E2eAccess>, limiter: web::Data<RateLimiter>) -> impl Responder {
+    let e2e_access = e2e_access.into_inner();
+    if let Err(err) = validate_credentials(&e2e_access) {
+        return error_response(err);
+    }
 
-    if chat_post.content.len() == 0 {
-        return HttpResponse::BadRequest().content_type("text/plain").body(format!("Error: {}", WhoChatError::EmptyContent));
+    let name = e2e_access.name.clone();
+    match guarded(&limiter, &req, &name, move || export_e2e_room(&e2e_access)).await {
+        Ok(archive) => archive_response(archive),
+        Err(response) => *response,
     }
+}
 
-    let path = format!("bucket/chats/{}.txt", &chat_post.name);
 
-    if Path::new(&path).exists() {
-        match append_content(&chat_post) {
-            Ok(_val) => HttpResponse::Ok().content_type("text/plain").body("Posted!"),
-            Err(err) => HttpResponse::InternalServerError().content_type("text/plain").body(format!("Error: {}", err)),
-        }
+#[post("/who_chat/e2e/stream", wrap = "RateLimit::new(\"who_chat\")")]
+async fn stream_e2e_chat(req: HttpRequest, e2e_access: web::Json<E2eAccess>, limiter: web::Data<RateLimiter>, events: web::Data<ChatEvents>) -> impl Responder {
+    let e2e_access = e2e_access.into_inner();
+    if let Err(err) = validate_credentials(&e2e_access) {
+        return error_response(err);
     }
-    else{
-        match write_first_content(&chat_post) {
-            Ok(_val) => HttpResponse::Ok().content_type("text/plain").body("Posted!"),
-            Err(err) => HttpResponse::InternalServerError().content_type("text/plain").body(format!("Error: {}", err)),
-        }
+
+    let name = e2e_access.name.clone();
+    let receiver = events.subscribe(&chat_file_id(&name));
+    match guarded(&limiter, &req, &name, move || unlock_e2e_room(&e2e_access.name, &e2e_access.token)).await {
+        Ok(room) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .insert_header(("X-Accel-Buffering", "no"))
+            .streaming(event_stream(receiver, room.version)),
+        Err(response) => *response,
     }
 }
 
 
-#[post("/who_chat/delete")]
-async fn delete_chat(chat_access: web::Json<ChatAccess>) -> impl Responder {
-    match validate_credentials(&chat_access.0) {
-        Ok(_v) => (),
-        Err(err) => return HttpResponse::BadRequest().content_type("text/plain").body(format!("Error: {}", err)),
-    };
+#[post("/who_chat/admin/stats", wrap = "RateLimit::new(\"who_chat_admin\")")]
+async fn admin_stats(admin_access: web::Json<AdminAccess>) -> impl Responder {
+    if !is_admin(&admin_access.password) {
+        return error_response(WhoChatError::WrongPassword);
+    }
 
-    match delete_data(&chat_access) {
-        Ok(_val) => HttpResponse::Ok().content_type("text/plain").body("Deleted!"),
-        Err(err) => HttpResponse::InternalServerError().content_type("text/plain").body(format!("Error: {}", err)),
+    match blocking(storage_stats).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(err) => error_response(err),
     }
 }
 
@@ -347,23 +2358,69 @@ pub async fn render() -> Result<HttpResponse> {
 
                         <ul>
                             <li> Each chat has a maximum data capacity of 100KB. When this limit is reached,
-                            the system automatically removes the oldest data, ensuring that only the most
+                            the system automatically removes the oldest messages, ensuring that only the most
                             recent 100KB of text is displayed.</li>
 
-                            <li> Although I can not read the contents inside a chat, I, Francisco Bruno, can
-                            read the chat's name. Don't put sensitive information on the chat's name.</li>
+                            <li> The server holds a limited number of chats. When it is full, the chats that
+                            went longest without being read or written are deleted to make room.</li>
+
+                            <li> Chats are stored under a keyed hash of their names, so a look at the server's
+                            files does not reveal them. Still, the name travels to the server with every
+                            request, so don't put sensitive information on the chat's name.</li>
 
                             <li> If you post to a chat that does not exist, the chat is created then the
                             information is written in the chat using the provided password and a ramdonly
                             generated salt. If the chat already exists, the information is posted only if
                             the password matches the one used during creation.
                             </li>
+
+                            <li> Use <span class=\"who-chat\">Create</span> to give a chat separate keys: the
+                            read key only gets the chat, the post key only posts to it (it can not read what
+                            is there) and the owner key can do everything, including deleting the chat. A
+                            broadcast channel, for instance, shares only its read key.
+                            </li>
+
+                            <li> The owner key can <span class=\"who-chat\">Rotate</span> a chat's keys. The chat
+                            gets new keys and a new salt, and the old keys stop working. New read and post
+                            keys left blank become the new owner key.
+                            </li>
+
+                            <li> When creating a chat you may also make its messages expire after some hours,
+                            have the chat deleted after some hours without use, or make it burn after the first
+                            time it is read.
+                            </li>
+
+                            <li> Tick <span class=\"who-chat\">Encrypt in browser</span> to keep the password
+                            and the messages from ever reaching the server in the clear: your browser derives
+                            the key and encrypts before sending, and the server only stores the ciphertext.
+                            Such chats have a single password and no retention options, and a chat name is
+                            either encrypted in the browser or on the server, never both.
+                            </li>
+
+                            <li> Posts may carry a small file, such as an image or a PDF, instead of text. It is
+                            encrypted with the chat's key, counts toward the chat's space and can only be
+                            downloaded with a key that reads the chat. Chats encrypted in the browser take
+                            text only.
+                            </li>
+
+                            <li> <span class=\"who-chat\">Archive</span> exports a chat, with the owner key, as a
+                            file holding only its encrypted data, and imports such a file as a new chat here or
+                            on another server. Importing asks for the owner key again.
+                            </li>
+
+                            <li> Tick <span class=\"who-chat\">Follow</span> when getting a chat to keep it open:
+                            the page reloads the chat whenever someone posts to it, until you switch modes or the
+                            chat is rotated or deleted.
+                            </li>
                         </ul>
                     </div>
 
                     <div id=\"mode-switch\">
                         <div id=\"get-switch\" class=\"switch\" onClick=\"set_get_chat();\">Get</div>
                         <div id=\"post-switch\" class=\"switch\" onClick=\"set_post_chat();\">Post</div>
+                        <div id=\"create-switch\" class=\"switch\" onClick=\"set_create_chat();\">Create</div>
+                        <div id=\"rotate-switch\" class=\"switch\" onClick=\"set_rotate_chat();\">Rotate</div>
+                        <div id=\"archive-switch\" class=\"switch\" onClick=\"set_archive_chat();\">Archive</div>
                         <div id=\"delete-switch\" class=\"switch\" onClick=\"set_delete_chat();\">Delete</div>
                     </div>
 
@@ -383,5 +2440,337 @@ pub async fn render() -> Result<HttpResponse> {
 
     Ok(HttpResponse::Ok()
         .content_type("text/html")
-        .body(html_content)) 
+        .body(html_content))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_wrong_password<T>(result: Result<T, WhoChatError>) -> bool {
+        matches!(result, Err(WhoChatError::WrongPassword))
+    }
+
+    // Rooms named at random so tests sharing CHATS_DIR never collide.
+    fn test_room(prefix: &str) -> String {
+        fs::create_dir_all(CHATS_DIR).unwrap();
+        format!("{}{}", prefix, generate_salt(12))
+    }
+
+    fn access(name: &str, password: &str) -> ChatAccess {
+        ChatAccess { name: name.to_string(), password: password.to_string() }
+    }
+
+    fn post(name: &str, password: &str, content: &str) -> ChatPost {
+        ChatPost { name: name.to_string(), password: password.to_string(), content: content.to_string() }
+    }
+
+    fn create(name: &str, read: &str, post: &str, owner: &str, retention: Retention) {
+        create_room(&ChatCreate {
+            name: name.to_string(),
+            read_password: read.to_string(),
+            post_password: post.to_string(),
+            owner_password: owner.to_string(),
+            retention,
+        }).unwrap();
+    }
+
+    #[test]
+    fn grants_keep_read_post_and_owner_apart() {
+        let (mut room, key) = new_room("reader", "poster", "owner").unwrap();
+        append_segment(&mut room, &key, "hello\n", None).unwrap();
+
+        let read = unlock(&room, "reader", Capability::Read).unwrap();
+        assert_eq!(read_contents(&room, &read).unwrap(), "hello\n");
+        assert!(is_wrong_password(unlock(&room, "reader", Capability::Post)));
+        assert!(is_wrong_password(unlock(&room, "reader", Capability::Owner)));
+
+        // A post-only grant holds just the public key: it can write, not read.
+        let post = unlock(&room, "poster", Capability::Post).unwrap();
+        assert!(matches!(post, RoomKey::Recipient(_)));
+        assert!(is_wrong_password(read_contents(&room, &post)));
+        assert!(is_wrong_password(unlock(&room, "poster", Capability::Read)));
+        append_segment(&mut room, &post, "from poster\n", None).unwrap();
+
+        let owner = unlock(&room, "owner", Capability::Owner).unwrap();
+        assert_eq!(read_contents(&room, &owner).unwrap(), "hello\nfrom poster\n");
+        assert!(unlock(&room, "owner", Capability::Post).is_ok());
+    }
+
+    #[test]
+    fn identical_passwords_share_one_grant() {
+        let (room, _key) = new_room("same", "same", "same").unwrap();
+        assert_eq!(room.grants.len(), 1);
+        assert!(unlock(&room, "same", Capability::Owner).is_ok());
+        assert!(is_wrong_password(unlock(&room, "other", Capability::Read)));
+    }
+
+    #[test]
+    fn rotation_locks_out_old_passwords_even_with_a_post_in_flight() {
+        let name = test_room("rotate");
+        create(&name, "reader", "poster", "owner", Retention::default());
+        post_content(&post(&name, "poster", "before\n")).unwrap();
+
+        let rotate = ChatRotate {
+            name: name.clone(),
+            password: String::from("owner"),
+            new_password: String::from("owner2"),
+            new_read_password: Some(String::from("reader2")),
+            new_post_password: None,
+        };
+        let during = post(&name, "poster", "during\n");
+        let (rotated, posted) = std::thread::scope(|scope| {
+            let rotation = scope.spawn(|| rotate_room(&rotate));
+            let posting = scope.spawn(|| post_content(&during));
+            (rotation.join().unwrap(), posting.join().unwrap())
+        });
+        rotated.unwrap();
+
+        let events = ChatEvents::new();
+        assert!(is_wrong_password(get_contents(&access(&name, "reader"), &events)));
+        assert!(is_wrong_password(post_content(&post(&name, "poster", "after\n"))));
+        // The post either landed before the rotation and was carried over, or
+        // came after it and was refused; it never brings the old keys back.
+        let contents = get_contents(&access(&name, "reader2"), &events).unwrap();
+        assert!(contents.starts_with("before\n"));
+        assert_eq!(contents.contains("during\n"), posted.is_ok());
+        delete_data(&access(&name, "owner2")).unwrap();
+    }
+
+    #[test]
+    fn retention_hides_old_messages_and_idle_rooms() {
+        let posted = |age: u64| Segment { size: 0, posted_at: now_secs() - age, ciphertext: String::new(), attachment: None };
+        let hours = |ttl: Option<u64>, idle: Option<u64>| Retention { message_ttl_hours: ttl, inactivity_hours: idle, read_once: false };
+
+        assert!(!is_expired(&hours(Some(2), None), &posted(3600)));
+        assert!(is_expired(&hours(Some(2), None), &posted(3 * 3600)));
+        assert!(!is_expired(&hours(None, None), &posted(1_000_000)));
+        // Absurd values saturate instead of wrapping around into the past.
+        assert!(!is_expired(&hours(Some(u64::MAX), None), &posted(3600)));
+
+        let path = PathBuf::from(chat_path(&test_room("idle")));
+        fs::write(&path, "{}").unwrap();
+        File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 3600)).unwrap();
+        let room = |idle: Option<u64>| Room {
+            format: ROOM_FORMAT,
+            salt: String::new(),
+            grants: Vec::new(),
+            segments: Vec::new(),
+            retention: hours(None, idle),
+            version: 0,
+        };
+        assert!(is_inactive(&room(Some(1)), &path));
+        assert!(!is_inactive(&room(Some(3)), &path));
+        assert!(!is_inactive(&room(Some(u64::MAX)), &path));
+        assert!(!is_inactive(&room(None), &path));
+        fs::remove_file(&path).unwrap();
+
+        assert!(validate_retention(&hours(Some(24), Some(RETENTION_HOURS_LIMIT))).is_ok());
+        assert!(validate_retention(&hours(Some(0), None)).is_err());
+        assert!(validate_retention(&hours(None, Some(RETENTION_HOURS_LIMIT + 1))).is_err());
+    }
+
+    #[test]
+    fn legacy_chats_move_under_their_hashed_name_and_migrate() {
+        let name = test_room("legacy");
+        let salt = generate_salt(SALT_SIZE);
+        // Written the way chats were before rooms: the salt, then raw age output.
+        let mut legacy = salt.clone().into_bytes();
+        let encryptor = age::Encryptor::with_user_passphrase(Secret::new(format!("secret{}", salt)));
+        let mut writer = encryptor.wrap_output(&mut legacy).unwrap();
+        writer.write_all(b"old notes\n").unwrap();
+        writer.finish().unwrap();
+        fs::write(legacy_path(&name), legacy).unwrap();
+
+        let path = chat_path(&name);
+        assert_eq!(path, format!("{}/{}.room", CHATS_DIR, chat_file_id(&name)));
+        assert!(!path.contains(&name));
+
+        let events = ChatEvents::new();
+        assert!(is_wrong_password(get_contents(&access(&name, "wrong"), &events)));
+        assert!(!Path::new(&legacy_path(&name)).exists());
+        assert!(is_legacy(&fs::read(&path).unwrap()));
+
+        assert_eq!(get_contents(&access(&name, "secret"), &events).unwrap(), "old notes\n");
+        assert!(!is_legacy(&fs::read(&path).unwrap()));
+        let room = locked_room(&name, || load_room(&name, "secret")).unwrap();
+        for capability in [Capability::Read, Capability::Post, Capability::Owner] {
+            assert!(unlock(&room, "secret", capability).is_ok());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_is_readable_only_by_its_owner() {
+        init_secret().unwrap();
+        let mode = fs::metadata(SECRET_PATH).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_secret().unwrap(), server_secret());
+    }
+
+    #[test]
+    fn oldest_segments_make_way_for_text_and_attachments() {
+        let (mut room, key) = new_room("reader", "poster", "owner").unwrap();
+        let half = "a".repeat(CONTENT_SIZE_LIMIT / 2);
+        append_segment(&mut room, &key, &half, None).unwrap();
+        append_segment(&mut room, &key, &half, None).unwrap();
+        assert_eq!(room.segments.len(), 2);
+        append_segment(&mut room, &key, "b", None).unwrap();
+        assert_eq!(room.segments.len(), 2);
+
+        let file = |id: &str| Some(Attachment { id: id.to_string(), size: room_quota() / 2 - CONTENT_SIZE_LIMIT });
+        append_segment(&mut room, &key, "[first]", file("first")).unwrap();
+        append_segment(&mut room, &key, "[second]", file("second")).unwrap();
+        assert_eq!(room.segments.len(), 4);
+        append_segment(&mut room, &key, "[third]", file("third")).unwrap();
+        let kept: Vec<_> = room.segments.iter().filter_map(|s| s.attachment.as_ref().map(|a| a.id.as_str())).collect();
+        assert_eq!(kept, ["second", "third"]);
+        assert!(room.segments.iter().map(segment_usage).sum::<usize>() <= room_quota());
+        let read = unlock(&room, "reader", Capability::Read).unwrap();
+        assert_eq!(read_contents(&room, &read).unwrap(), "[second][third]");
+
+        let name = test_room("quota");
+        for id in ["first", "second", "third"] {
+            fs::write(attachment_path(&name, id), id).unwrap();
+        }
+        prune_attachments(&chat_file_id(&name), Some(&room)).unwrap();
+        assert!(!Path::new(&attachment_path(&name, "first")).exists());
+        assert!(Path::new(&attachment_path(&name, "second")).exists());
+        prune_attachments(&chat_file_id(&name), None).unwrap();
+        assert!(!Path::new(&attachment_path(&name, "third")).exists());
+    }
+
+    #[test]
+    fn archives_import_back_with_their_messages_and_files() {
+        let name = test_room("export");
+        create(&name, "reader", "poster", "owner", Retention::default());
+        post_content(&post(&name, "poster", "hello\n")).unwrap();
+        attach_file(&ChatAttach {
+            name: name.clone(),
+            password: String::from("poster"),
+            filename: String::from("notes.txt"),
+            content_type: String::from("text/plain"),
+            data: BASE64.encode("file body"),
+        }).unwrap();
+
+        assert!(is_wrong_password(export_room(&access(&name, "reader"))));
+        let archive = serde_json::to_string(&export_room(&access(&name, "owner")).unwrap()).unwrap();
+        delete_data(&access(&name, "owner")).unwrap();
+
+        let copy = test_room("import");
+        let import = |password: &str| import_room(ChatImport {
+            name: copy.clone(),
+            password: password.to_string(),
+            archive: serde_json::from_str(&archive).unwrap(),
+        });
+        assert!(is_wrong_password(import("reader")));
+        let attached = import("owner").unwrap();
+        assert!(matches!(import("owner"), Err(WhoChatError::ChatAlreadyExists)));
+
+        let contents = get_contents(&access(&copy, "reader"), &ChatEvents::new()).unwrap();
+        assert!(contents.starts_with("hello\n[attachment "));
+        let id = contents.split(' ').nth(1).unwrap().trim_end_matches(':').to_string();
+        assert_eq!(attached, fs::metadata(attachment_path(&copy, &id)).unwrap().len());
+        let (filename, content_type, data) = read_attachment(&ChatAttachment {
+            name: copy.clone(),
+            password: String::from("reader"),
+            id,
+        }).unwrap();
+        assert_eq!((filename.as_str(), content_type.as_str(), data.as_slice()), ("notes.txt", "text/plain", &b"file body"[..]));
+        delete_data(&access(&copy, "owner")).unwrap();
+    }
+
+    #[test]
+    fn eviction_picks_the_least_recently_used_rooms_but_not_the_new_one() {
+        let stored = |id: &str, kind: Option<&'static str>, bytes: u64, age: u64| StoredRoom {
+            file_id: id.to_string(),
+            kind,
+            attachments: 0,
+            bytes,
+            last_used: SystemTime::now() - Duration::from_secs(age),
+            paths: Vec::new(),
+        };
+        let rooms = || vec![
+            stored("new", Some("server"), 10, 300),
+            stored("recent", Some("browser"), 10, 10),
+            stored("old", Some("server"), 10, 100),
+            stored("orphan", None, 5, 200),
+        ];
+        let picked = |rooms: Vec<StoredRoom>, max_rooms: usize, max_bytes: u64| -> Vec<String> {
+            pick_evictions(rooms, Some("new"), max_rooms, max_bytes).into_iter().map(|r| r.file_id).collect()
+        };
+
+        assert!(picked(rooms(), 3, 35).is_empty());
+        // Attachments without a room hold bytes but no room slot.
+        assert_eq!(picked(rooms(), 3, 30), ["orphan"]);
+        assert_eq!(picked(rooms(), 2, 35), ["orphan", "old"]);
+        assert_eq!(picked(rooms(), 0, 0), ["orphan", "old", "recent"]);
+    }
+
+    #[test]
+    fn age_sizes_are_read_from_the_framing() {
+        let recipient = x25519::Identity::generate().to_public();
+        for size in [0, 1, AGE_CHUNK_SIZE - 1, AGE_CHUNK_SIZE, 3 * AGE_CHUNK_SIZE + 7] {
+            let raw = encrypt_bytes_to_recipient(&vec![b'x'; size], recipient.clone()).unwrap();
+            assert_eq!(age_plaintext_size(&raw), Some(size));
+            let armored = encrypt_to_recipient(&"x".repeat(size), recipient.clone()).unwrap();
+            assert_eq!(age_plaintext_size(armored.as_bytes()), Some(size));
+        }
+        assert_eq!(age_plaintext_size(b"not age"), None);
+    }
+
+    #[test]
+    fn imports_measure_sizes_instead_of_trusting_the_archive() {
+        let name = test_room("forged");
+        create(&name, "owner", "owner", "owner", Retention::default());
+        post_content(&post(&name, "owner", "hello\n")).unwrap();
+        attach_file(&ChatAttach {
+            name: name.clone(),
+            password: String::from("owner"),
+            filename: String::from("notes.txt"),
+            content_type: String::from("text/plain"),
+            data: BASE64.encode("file body"),
+        }).unwrap();
+        let exported = serde_json::to_value(export_room(&access(&name, "owner")).unwrap()).unwrap();
+        delete_data(&access(&name, "owner")).unwrap();
+
+        let import = |archive: &serde_json::Value| {
+            let copy = test_room("forged");
+            let result = import_room(ChatImport {
+                name: copy.clone(),
+                password: String::from("owner"),
+                archive: serde_json::from_value(archive.clone()).unwrap(),
+            });
+            (copy, result)
+        };
+
+        let mut archive = exported.clone();
+        for segment in archive["room"]["room"]["segments"].as_array_mut().unwrap() {
+            segment["size"] = 0.into();
+            if segment["attachment"].is_object() {
+                segment["attachment"]["size"] = 1.into();
+            }
+        }
+        let (copy, result) = import(&archive);
+        result.unwrap();
+        let room: Room = serde_json::from_slice(&fs::read(chat_path(&copy)).unwrap()).unwrap();
+        assert_eq!(room.segments[0].size, "hello\n".len());
+        assert!(room.segments[1].attachment.as_ref().unwrap().size > "file body".len());
+        delete_data(&access(&copy, "owner")).unwrap();
+
+        let mut archive = exported.clone();
+        let segments = archive["room"]["room"]["segments"].as_array_mut().unwrap();
+        segments.push(segments[1].clone());
+        assert!(matches!(import(&archive).1, Err(WhoChatError::InvalidContent)));
+
+        let mut archive = exported;
+        let id = archive["room"]["room"]["segments"][1]["attachment"]["id"].as_str().unwrap().to_string();
+        let recipient = x25519::Identity::generate().to_public();
+        let oversized = encrypt_bytes_to_recipient(&vec![0; attachment_limit() + 1], recipient).unwrap();
+        archive["room"]["attachments"][id.as_str()] = BASE64.encode(oversized).into();
+        assert!(matches!(import(&archive).1, Err(WhoChatError::ContentTooLarge)));
+    }
 }
@@ -33,6 +33,9 @@ fn validate_wordle_input(guesses: &[GuessData]) -> Result<(), String> {
         return Ok(());
     }
 
+    let word_regex = Regex::new(r"^[a-z]{5}$").unwrap();
+    let feedback_regex = Regex::new(r"^[012]{5}$").unwrap();
+
     for (i, guess_data) in guesses.iter().enumerate() {
         let word = &guess_data.word;
         let feedback = &guess_data.feedback;
@@ -43,7 +46,6 @@ fn validate_wordle_input(guesses: &[GuessData]) -> Result<(), String> {
         }
 
         // Word should only contain letters
-        let word_regex = Regex::new(r"^[a-z]{5}$").unwrap();
         if !word_regex.is_match(word) {
             return Err(format!("Guess {} can only contain letters", i + 1));
         }
@@ -54,7 +56,6 @@ fn validate_wordle_input(guesses: &[GuessData]) -> Result<(), String> {
         }
 
         // Feedback should only contain 0, 1, 2
-        let feedback_regex = Regex::new(r"^[012]{5}$").unwrap();
         if !feedback_regex.is_match(feedback) {
             return Err(format!("Feedback for guess {} can only contain 0 (grey), 1 (yellow), 2 (green)", i + 1));
         }
@@ -240,7 +241,7 @@ pub async fn solve_wordle(data: web::Json<WordleRequest>) -> Result<HttpResponse
                 .take(MAX_SUGGESTIONS) // Limit to top 20 suggestions
                 .map(|line| {
                     // Extract just the word part (skip entropy)
-                    let parts: Vec<&str> = line.trim().split_whitespace().collect();
+                    let parts: Vec<&str> = line.split_whitespace().collect();
                     if parts.len() >= 2 {
                         parts[1].to_uppercase()
                     } else {
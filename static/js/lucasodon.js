@@ -2,11 +2,21 @@ const PERIODOS = ["Diurno", "Noturno", "Cinderela", "24hrs", "24hrs invertido"];
 const TIPOS = ["PA", "MFC"];
 
 let plantoes = [];
+let resumo = null;
 
 function brl(n) {
   return (n || 0).toLocaleString("pt-BR", { style: "currency", currency: "BRL" });
 }
 
+// Sums are kept in integer centavos so they match the server to the cent.
+function cents(valor) {
+  return Math.round((valor || 0) * 100);
+}
+
+function brlCents(c) {
+  return brl(c / 100);
+}
+
 // Returns an ISO yyyy-mm-dd one month after the given ISO date.
 // Built from date parts (not Date parsing) to avoid timezone shifts.
 function addOneMonth(iso) {
@@ -50,8 +60,11 @@ function esc(s) {
   return d.innerHTML;
 }
 
+const CSRF_TOKEN = document.querySelector('meta[name="csrf-token"]').content;
+
 async function api(path, method, body) {
   const opts = { method, headers: {} };
+  if (method !== "GET") opts.headers["X-CSRF-Token"] = CSRF_TOKEN;
   if (body !== undefined) {
     opts.headers["Content-Type"] = "application/json";
     opts.body = JSON.stringify(body);
@@ -67,8 +80,12 @@ async function api(path, method, body) {
 
 async function load() {
   try {
-    const res = await api("/lucasodon/api/list", "GET");
-    plantoes = await res.json();
+    const [lista, res] = await Promise.all([
+      api("/lucasodon/api/list", "GET"),
+      api("/lucasodon/api/resumo", "GET"),
+    ]);
+    plantoes = await lista.json();
+    resumo = await res.json();
     render();
   } catch (e) {
     showMsg("Erro ao carregar: " + e.message, false);
@@ -76,11 +93,7 @@ async function load() {
 }
 
 function renderTotals() {
-  let recebido = 0, pendente = 0;
-  for (const p of plantoes) {
-    if (p.recebido) recebido += p.valor;
-    else pendente += p.valor;
-  }
+  const { recebido, pendente } = resumo.receitas;
   document.getElementById("totais").innerHTML = `
     <div class="card"><div class="lbl">Total recebido</div><div class="val recebido">${brl(recebido)}</div></div>
     <div class="card"><div class="lbl">A receber</div><div class="val pendente">${brl(pendente)}</div></div>
@@ -118,36 +131,15 @@ const MESES = ["Janeiro", "Fevereiro", "Março", "Abril", "Maio", "Junho",
 
 // Bucket a plantao by its payment month. Prefer the expected-payment date
 // (ISO, sortable); fall back to the shift date. Returns {key, label}.
-function paymentMonth(p) {
-  const iso = p.previsao_pagamento || p.data;
-  const parts = (iso || "").split("-");
-  if (parts.length !== 3) return { key: "9999-99", label: "Sem data" };
-  return {
-    key: `${parts[0]}-${parts[1]}`,
-    label: `${MESES[Number(parts[1]) - 1]} ${parts[0]}`,
-  };
-}
-
-function pct(part, total) {
-  return total > 0 ? (part / total) * 100 : 0;
+// "Março 2025" from the yyyy-mm month of payment the server groups by.
+function monthLabel(mes) {
+  const parts = (mes || "").split("-");
+  if (parts.length !== 2) return "Sem data";
+  return `${MESES[Number(parts[1]) - 1]} ${parts[0]}`;
 }
 
 function renderDashboard() {
-  const groups = {};
-  for (const p of plantoes) {
-    const { key, label } = paymentMonth(p);
-    if (!groups[key]) groups[key] = { key, label, total: 0, recebido: 0, pendente: 0, count: 0 };
-    const g = groups[key];
-    g.total += p.valor;
-    g.count += 1;
-    if (p.recebido) g.recebido += p.valor;
-    else g.pendente += p.valor;
-  }
-
-  const months = Object.values(groups).sort((a, b) => a.key.localeCompare(b.key));
-  const grandTotal = months.reduce((s, m) => s + m.total, 0);
-  const grandReceb = months.reduce((s, m) => s + m.recebido, 0);
-  const grandPend = months.reduce((s, m) => s + m.pendente, 0);
+  const months = resumo.receitas_por_mes;
 
   const el = document.getElementById("view-dashboard");
 
@@ -158,20 +150,20 @@ function renderDashboard() {
 
   const summary = `
     <div class="dash-summary">
-      <div class="card"><div class="lbl">Total previsto</div><div class="val">${brl(grandTotal)}</div></div>
-      <div class="card"><div class="lbl">Recebido</div><div class="val recebido">${brl(grandReceb)}</div></div>
-      <div class="card"><div class="lbl">A receber</div><div class="val pendente">${brl(grandPend)}</div></div>
+      <div class="card"><div class="lbl">Total previsto</div><div class="val">${brl(resumo.receitas.total)}</div></div>
+      <div class="card"><div class="lbl">Recebido</div><div class="val recebido">${brl(resumo.receitas.recebido)}</div></div>
+      <div class="card"><div class="lbl">A receber</div><div class="val pendente">${brl(resumo.receitas.pendente)}</div></div>
     </div>`;
 
   const cards = months.map(m => `
     <div class="dash-month">
       <div class="dash-month-head">
-        <span class="mlabel">${m.label}</span>
-        <span class="mcount">${m.count} plantã${m.count === 1 ? "o" : "os"}</span>
+        <span class="mlabel">${monthLabel(m.mes)}</span>
+        <span class="mcount">${m.plantoes} plantã${m.plantoes === 1 ? "o" : "os"}</span>
       </div>
       <div class="dash-bar" title="Recebido ${brl(m.recebido)} de ${brl(m.total)}">
-        <div class="bar-recebido" style="width:${pct(m.recebido, m.total)}%"></div>
-        <div class="bar-pendente" style="width:${pct(m.pendente, m.total)}%"></div>
+        <div class="bar-recebido" style="width:${m.percentual_recebido}%"></div>
+        <div class="bar-pendente" style="width:${m.total > 0 ? 100 - m.percentual_recebido : 0}%"></div>
       </div>
       <div class="dash-figs">
         <div><span class="lbl">Previsto</span><span class="v">${brl(m.total)}</span></div>
@@ -350,7 +342,7 @@ async function remove(id) {
   if (!confirm("Excluir este plantão?")) return;
   try {
     await api("/lucasodon/api/delete", "POST", { id });
-    showMsg("Plantão excluído.", true);
+    showUndo("msg", "Plantão movido para a lixeira.", "plantoes", id);
     await load();
   } catch (e) {
     showMsg("Erro ao excluir: " + e.message, false);
@@ -395,13 +387,16 @@ function showDespMsg(text, ok) {
 }
 
 // ---- main tab switching ----
+const MAIN_TABS = ["plantoes", "despesas", "projecao", "carne"];
+
 function setMainTab(tab) {
-  const isDesp = tab === "despesas";
-  document.getElementById("section-plantoes").style.display = isDesp ? "none" : "";
-  document.getElementById("section-despesas").style.display = isDesp ? "" : "none";
-  document.getElementById("maintab-plantoes").classList.toggle("active", !isDesp);
-  document.getElementById("maintab-despesas").classList.toggle("active", isDesp);
-  if (isDesp && !despesasLoaded) { despesasLoaded = true; initDespesas(); }
+  MAIN_TABS.forEach(t => {
+    document.getElementById("section-" + t).style.display = t === tab ? "" : "none";
+    document.getElementById("maintab-" + t).classList.toggle("active", t === tab);
+  });
+  if (tab === "despesas" && !despesasLoaded) { despesasLoaded = true; initDespesas(); }
+  if (tab === "projecao") loadProjecao();
+  if (tab === "carne") loadCarneLeao();
 }
 
 async function initDespesas() {
@@ -477,6 +472,7 @@ async function excluirCategoria(c) {
   if (!confirm(`Excluir a categoria "${c.nome}"? As despesas dela vão para "Outros".`)) return;
   try {
     await api("/lucasodon/api/categorias/delete", "POST", { id: c.id });
+    showUndo("despesas-msg", `Categoria "${c.nome}" movida para a lixeira.`, "categorias", c.id);
     await loadCategorias();
     await loadDespesas();
   } catch (e) { showDespMsg("Erro: " + e.message, false); }
@@ -511,23 +507,24 @@ function renderDespesas() {
   let total = 0, pessoal = 0, profissional = 0;
   const porCat = {};
   for (const d of despesas) {
-    total += d.valor;
-    if (d.tipo === "profissional") profissional += d.valor; else pessoal += d.valor;
+    const c = cents(d.valor);
+    total += c;
+    if (d.tipo === "profissional") profissional += c; else pessoal += c;
     const key = d.categoria_id || 0;
-    porCat[key] = (porCat[key] || 0) + d.valor;
+    porCat[key] = (porCat[key] || 0) + c;
   }
 
   const cards = `
-    <div class="card"><div class="lbl">Total do período</div><div class="val">${brl(total)}</div></div>
-    <div class="card"><div class="lbl">Pessoal</div><div class="val">${brl(pessoal)}</div></div>
-    <div class="card"><div class="lbl">Profissional</div><div class="val profissional">${brl(profissional)}</div></div>`;
+    <div class="card"><div class="lbl">Total do período</div><div class="val">${brlCents(total)}</div></div>
+    <div class="card"><div class="lbl">Pessoal</div><div class="val">${brlCents(pessoal)}</div></div>
+    <div class="card"><div class="lbl">Profissional</div><div class="val profissional">${brlCents(profissional)}</div></div>`;
 
   const linhas = Object.entries(porCat).sort((a, b) => b[1] - a[1]).map(([id, v]) => {
     const nome = id === "0" ? "Sem categoria" : (catById[id] ? catById[id].nome : "—");
     const p = total > 0 ? (v / total) * 100 : 0;
     return `<div class="cat-row"><span class="cat-nome">${esc(nome)}</span>
       <div class="cat-bar"><div style="width:${p}%"></div></div>
-      <span class="cat-val">${brl(v)}</span></div>`;
+      <span class="cat-val">${brlCents(v)}</span></div>`;
   }).join("");
 
   document.getElementById("despesas-totais").innerHTML = cards +
@@ -582,12 +579,12 @@ async function pagarDespesa(d) {
 
 async function removeDespesa(d) {
   const msg = d.recorrente_id
-    ? "Excluir esta ocorrência da despesa fixa? Ela não será gerada de novo."
+    ? "Excluir esta ocorrência da despesa fixa? Ela vai para a lixeira e não será gerada de novo."
     : "Excluir esta despesa?";
   if (!confirm(msg)) return;
   try {
     await api("/lucasodon/api/despesas/delete", "POST", { id: d.id });
-    showDespMsg("Despesa excluída.", true);
+    showUndo("despesas-msg", "Despesa movida para a lixeira.", "despesas", d.id);
     await loadDespesas();
   } catch (e) { showDespMsg("Erro: " + e.message, false); }
 }
@@ -654,16 +651,17 @@ function recorrenteRow(r) {
   const tr = document.createElement("tr");
   if (!r.ativo) tr.className = "inativo";
   const cat = r.categoria_id && catById[r.categoria_id] ? catById[r.categoria_id].nome : "—";
-  const venc = r.periodicidade === "anual"
+  let venc = r.periodicidade === "anual"
     ? `dia ${r.dia_vencimento} de ${MESES[(r.mes_vencimento || 1) - 1]}`
-    : `dia ${r.dia_vencimento}`;
+    : SEMANAIS.includes(r.periodicidade) ? `a partir de ${fmtDate(r.data_inicio)}` : `dia ${r.dia_vencimento}`;
+  if (r.dia_util !== "nenhum") venc += r.dia_util === "proximo" ? " (próx. dia útil)" : " (dia útil anterior)";
   const vig = `${fmtDate(r.data_inicio)} – ${r.data_fim ? fmtDate(r.data_fim) : "..."}`;
   tr.innerHTML = `
     <td>${esc(r.descricao)}</td>
     <td>${esc(cat)}</td>
     <td>${r.tipo === "profissional" ? "Profissional" : "Pessoal"}</td>
     <td>${brl(r.valor)}</td>
-    <td>${r.periodicidade === "anual" ? "Anual" : "Mensal"}</td>
+    <td>${PERIODICIDADES[r.periodicidade] || r.periodicidade}${r.parcelas ? ` · ${r.parcelas}x` : ""}</td>
     <td>${venc}</td>
     <td>${vig}</td>
     <td>${r.ativo ? "Sim" : "Não"}</td>`;
@@ -678,9 +676,17 @@ function recorrenteRow(r) {
   return tr;
 }
 
+const PERIODICIDADES = {
+  semanal: "Semanal", quinzenal: "Quinzenal", mensal: "Mensal",
+  trimestral: "Trimestral", semestral: "Semestral", anual: "Anual",
+};
+// Counted from the start date, so they have no day of the month.
+const SEMANAIS = ["semanal", "quinzenal"];
+
 function toggleMesField() {
-  document.getElementById("rx-mes-field").style.display =
-    document.getElementById("rx-periodicidade").value === "anual" ? "" : "none";
+  const per = document.getElementById("rx-periodicidade").value;
+  document.getElementById("rx-mes-field").style.display = per === "anual" ? "" : "none";
+  document.getElementById("rx-dia-field").style.display = SEMANAIS.includes(per) ? "none" : "";
 }
 
 function openRecorrente(r) {
@@ -696,6 +702,8 @@ function openRecorrente(r) {
   document.getElementById("rx-inicio").value = r ? r.data_inicio : "";
   document.getElementById("rx-fim").value = r && r.data_fim ? r.data_fim : "";
   document.getElementById("rx-ativo").checked = r ? r.ativo : true;
+  document.getElementById("rx-parcelas").value = r && r.parcelas ? r.parcelas : "";
+  document.getElementById("rx-dia-util").value = r ? r.dia_util : "nenhum";
   toggleMesField();
   document.getElementById("recorrente-modal").classList.add("open");
 }
@@ -705,13 +713,17 @@ function closeRecorrente() { document.getElementById("recorrente-modal").classLi
 async function saveRecorrente() {
   const descricao = document.getElementById("rx-descricao").value.trim();
   const valor = parseFloat(document.getElementById("rx-valor").value) || 0;
-  const dia = parseInt(document.getElementById("rx-dia").value, 10) || 0;
   const inicio = document.getElementById("rx-inicio").value;
   const periodicidade = document.getElementById("rx-periodicidade").value;
+  const semanal = SEMANAIS.includes(periodicidade);
+  const dia = semanal ? Number(inicio.slice(8, 10)) : parseInt(document.getElementById("rx-dia").value, 10) || 0;
+  const parcelasVal = document.getElementById("rx-parcelas").value;
+  const parcelas = parcelasVal ? parseInt(parcelasVal, 10) : null;
   if (!descricao) { showDespMsg("Informe a descrição.", false); return; }
   if (valor <= 0) { showDespMsg("Informe um valor.", false); return; }
-  if (dia < 1 || dia > 31) { showDespMsg("Dia de vencimento inválido (1–31).", false); return; }
   if (!inicio) { showDespMsg("Informe a data de início.", false); return; }
+  if (dia < 1 || dia > 31) { showDespMsg("Dia de vencimento inválido (1–31).", false); return; }
+  if (parcelas !== null && !(parcelas >= 1 && parcelas <= 600)) { showDespMsg("Número de parcelas inválido (1–600).", false); return; }
   const catVal = document.getElementById("rx-categoria").value;
   const body = {
     descricao, valor,
@@ -723,6 +735,8 @@ async function saveRecorrente() {
     data_inicio: inicio,
     data_fim: document.getElementById("rx-fim").value || null,
     ativo: document.getElementById("rx-ativo").checked,
+    parcelas,
+    dia_util: document.getElementById("rx-dia-util").value,
   };
   try {
     if (editingRecorrenteId) await api("/lucasodon/api/recorrentes/update", "POST", Object.assign({ id: editingRecorrenteId }, body));
@@ -735,15 +749,460 @@ async function saveRecorrente() {
 }
 
 async function removeRecorrente(r) {
-  if (!confirm("Excluir esta despesa fixa? Ocorrências futuras ainda não pagas serão removidas.")) return;
+  if (!confirm("Excluir esta despesa fixa? Ocorrências futuras ainda não pagas vão com ela para a lixeira.")) return;
   try {
     await api("/lucasodon/api/recorrentes/delete", "POST", { id: r.id });
-    showDespMsg("Despesa fixa excluída.", true);
+    showUndo("despesas-msg", "Despesa fixa movida para a lixeira.", "despesas_recorrentes", r.id);
     await loadRecorrentes();
     await loadDespesas();
   } catch (e) { showDespMsg("Erro: " + e.message, false); }
 }
 
+// ---- projeção ----
+async function loadProjecao() {
+  const qs = new URLSearchParams({ meses: document.getElementById("p-meses").value });
+  const inicio = document.getElementById("p-inicio").value;
+  const saldo = document.getElementById("p-saldo").value;
+  if (inicio) qs.set("inicio", inicio);
+  if (saldo) qs.set("saldo_inicial", saldo);
+  try {
+    const res = await api(`/lucasodon/api/projecao?${qs.toString()}`, "GET");
+    renderProjecao(await res.json());
+  } catch (e) {
+    const el = document.getElementById("projecao-msg");
+    el.textContent = "Erro ao carregar: " + e.message;
+    el.className = "msg err";
+  }
+}
+
+function renderProjecao(p) {
+  const ultimo = p.meses.length ? p.meses[p.meses.length - 1].saldo_previsto : p.saldo_inicial;
+  document.getElementById("projecao-totais").innerHTML = `
+    <div class="card"><div class="lbl">Saldo ao fim do período</div><div class="val ${ultimo < 0 ? "pendente" : "recebido"}">${brl(ultimo)}</div></div>
+    <div class="card"><div class="lbl">Meses no vermelho</div><div class="val ${p.meses_em_deficit ? "pendente" : ""}">${p.meses_em_deficit}</div></div>
+    <div class="card"><div class="lbl">A receber em atraso</div><div class="val">${brl(p.atrasado)}</div></div>`;
+
+  const body = document.getElementById("projecao-body");
+  body.innerHTML = "";
+  p.meses.forEach(m => {
+    const tr = document.createElement("tr");
+    if (m.deficit) tr.className = "deficit";
+    const [y, mm] = m.mes.split("-");
+    tr.innerHTML = `
+      <td>${MESES[Number(mm) - 1]} ${y}</td>
+      <td>${brl(m.receitas)}</td>
+      <td>${brl(m.despesas)}</td>
+      <td>${brl(m.saldo_mes)}</td>
+      <td>${brl(m.saldo_previsto)}</td>`;
+    body.appendChild(tr);
+  });
+}
+
+// ---- carnê-leão ----
+async function loadCarneLeao() {
+  const ano = document.getElementById("c-ano");
+  if (!ano.value) ano.value = new Date().getFullYear();
+  try {
+    const res = await api(`/lucasodon/api/carne_leao?ano=${encodeURIComponent(ano.value)}`, "GET");
+    renderCarneLeao(await res.json());
+  } catch (e) {
+    const el = document.getElementById("carne-msg");
+    el.textContent = "Erro ao carregar: " + e.message;
+    el.className = "msg err";
+  }
+}
+
+function fmtAliquota(a) {
+  return a ? a.toLocaleString("pt-BR") + "%" : "Isento";
+}
+
+function renderCarneLeao(c) {
+  const a = c.anual;
+  document.getElementById("carne-totais").innerHTML = `
+    <div class="card"><div class="lbl">Rendimentos em ${c.ano}</div><div class="val recebido">${brl(a.rendimentos)}</div></div>
+    <div class="card"><div class="lbl">Despesas dedutíveis</div><div class="val">${brl(a.despesas_profissionais)}</div></div>
+    <div class="card"><div class="lbl">Imposto estimado</div><div class="val pendente">${brl(a.imposto)}</div></div>
+    <div class="card"><div class="lbl">Alíquota efetiva</div><div class="val">${a.aliquota_efetiva.toLocaleString("pt-BR", { maximumFractionDigits: 2 })}%</div></div>`;
+
+  const body = document.getElementById("carne-body");
+  body.innerHTML = "";
+  c.meses.forEach(m => {
+    const tr = document.createElement("tr");
+    const [y, mm] = m.mes.split("-");
+    tr.innerHTML = `
+      <td>${MESES[Number(mm) - 1]} ${y}</td>
+      <td>${brl(m.rendimentos)}</td>
+      <td>${brl(m.despesas_profissionais)}</td>
+      <td>${brl(m.excesso_anterior)}</td>
+      <td>${brl(m.base_calculo)}</td>
+      <td>${fmtAliquota(m.aliquota)}</td>
+      <td>${brl(m.parcela_deduzir)}</td>
+      <td>${brl(m.imposto)}</td>`;
+    body.appendChild(tr);
+  });
+  if (a.excesso_nao_usado > 0) {
+    const el = document.getElementById("carne-msg");
+    el.textContent = `Despesas de ${brl(a.excesso_nao_usado)} excederam os rendimentos e não são aproveitadas no ano seguinte.`;
+    el.className = "msg";
+  }
+}
+
+// ---- importação de extrato ----
+let importLinhas = [];
+
+const IMPORT_SITUACAO = {
+  importar: "Nova",
+  duplicada: "Já lançada",
+  pagar_previsto: "Paga a fixa prevista",
+};
+
+function showImportMsg(text, ok) {
+  const el = document.getElementById("importar-msg");
+  el.textContent = text;
+  el.className = "msg " + (ok ? "ok" : "err");
+}
+
+function openImportar() {
+  importLinhas = [];
+  document.getElementById("ix-arquivo").value = "";
+  document.getElementById("importar-preview").style.display = "none";
+  document.getElementById("confirm-importar-btn").style.display = "none";
+  showImportMsg("", true);
+  document.getElementById("importar-modal").classList.add("open");
+}
+
+// Brazilian banks still export Latin-1 files, so fall back to it when the
+// file isn't valid UTF-8.
+async function readExtrato(file) {
+  const buf = await file.arrayBuffer();
+  try {
+    return new TextDecoder("utf-8", { fatal: true }).decode(buf);
+  } catch (_) {
+    return new TextDecoder("windows-1252").decode(buf);
+  }
+}
+
+async function previewImportar() {
+  const file = document.getElementById("ix-arquivo").files[0];
+  if (!file) { showImportMsg("Escolha um arquivo.", false); return; }
+  try {
+    const conteudo = await readExtrato(file);
+    const formato = file.name.toLowerCase().endsWith(".ofx") ? "ofx" : null;
+    const res = await api("/lucasodon/api/importar/preview", "POST", { formato, conteudo });
+    const preview = await res.json();
+    importLinhas = preview.linhas;
+    renderImportar();
+    const ign = preview.ignoradas ? ` (${preview.ignoradas} linha(s) ignorada(s))` : "";
+    showImportMsg(`${importLinhas.length} débito(s) encontrados${ign}.`, true);
+  } catch (e) { showImportMsg("Erro: " + e.message, false); }
+}
+
+function renderImportar() {
+  const opts = `<option value="">—</option>` +
+    categorias.map(c => `<option value="${c.id}">${esc(c.nome)}</option>`).join("");
+  const body = document.getElementById("importar-body");
+  body.innerHTML = "";
+  importLinhas.forEach((l, i) => {
+    const tr = document.createElement("tr");
+    if (l.acao === "duplicada") tr.className = "previsto";
+    tr.innerHTML = `
+      <td><input type="checkbox" data-i="${i}" ${l.acao === "duplicada" ? "" : "checked"}></td>
+      <td>${fmtDate(l.data)}</td>
+      <td>${esc(l.descricao)}</td>
+      <td>${brl(l.valor)}</td>
+      <td><select data-cat="${i}">${opts}</select></td>
+      <td><select data-tipo="${i}"><option value="pessoal">Pessoal</option><option value="profissional">Profissional</option></select></td>
+      <td>${IMPORT_SITUACAO[l.acao] || l.acao}</td>`;
+    tr.querySelector("[data-cat]").value = l.categoria_id ? String(l.categoria_id) : "";
+    tr.querySelector("[data-tipo]").value = l.tipo;
+    body.appendChild(tr);
+  });
+  const has = importLinhas.length > 0;
+  document.getElementById("importar-preview").style.display = has ? "" : "none";
+  document.getElementById("confirm-importar-btn").style.display = has ? "" : "none";
+}
+
+async function confirmImportar() {
+  const body = document.getElementById("importar-body");
+  const linhas = importLinhas.map((l, i) => {
+    const cat = body.querySelector(`[data-cat="${i}"]`).value;
+    return {
+      data: l.data, descricao: l.descricao, valor: l.valor,
+      categoria_id: cat ? Number(cat) : null,
+      tipo: body.querySelector(`[data-tipo="${i}"]`).value,
+      // A duplicate the user ticked anyway is imported as a new row.
+      acao: l.acao === "duplicada" ? "importar" : l.acao,
+      alvo_id: l.alvo_id,
+      marcada: body.querySelector(`[data-i="${i}"]`).checked,
+    };
+  }).filter(l => l.marcada);
+  if (!linhas.length) { showImportMsg("Nenhuma linha selecionada.", false); return; }
+  try {
+    const res = await api("/lucasodon/api/importar/confirmar", "POST", { linhas });
+    const r = await res.json();
+    document.getElementById("importar-modal").classList.remove("open");
+    showDespMsg(`${r.importadas} despesa(s) importada(s), ${r.pagas} fixa(s) marcada(s) como paga(s).`, true);
+    await loadDespesas();
+  } catch (e) { showImportMsg("Erro: " + e.message, false); }
+}
+
+// ---- lixeira ----
+const UNDO_MS = 10000;
+
+async function reloadAll() {
+  await load();
+  if (despesasLoaded) { await loadCategorias(); await loadRecorrentes(); await loadDespesas(); }
+}
+
+async function restaurarItem(tabela, id) {
+  await api("/lucasodon/api/lixeira/restaurar", "POST", { tabela, id });
+  await reloadAll();
+}
+
+// Shows `text` in the message box `elId` with a button that takes the item
+// back out of the trash.
+function showUndo(elId, text, tabela, id) {
+  const el = document.getElementById(elId);
+  el.className = "msg ok";
+  el.textContent = text + " ";
+  const btn = document.createElement("button");
+  btn.className = "edit"; btn.textContent = "Desfazer";
+  btn.onclick = async () => {
+    try {
+      await restaurarItem(tabela, id);
+      el.textContent = "Exclusão desfeita.";
+    } catch (e) { el.textContent = "Erro: " + e.message; el.className = "msg err"; }
+  };
+  el.appendChild(btn);
+  setTimeout(() => { if (el.contains(btn)) { el.textContent = ""; el.className = "msg"; } }, UNDO_MS);
+}
+
+function showLixeiraMsg(text, ok) {
+  const el = document.getElementById("lixeira-msg");
+  el.textContent = text;
+  el.className = "msg " + (ok ? "ok" : "err");
+}
+
+async function openLixeira() {
+  showLixeiraMsg("", true);
+  document.getElementById("lixeira-modal").classList.add("open");
+  await loadLixeira();
+}
+
+async function loadLixeira() {
+  const body = document.getElementById("lixeira-body");
+  try {
+    const res = await api("/lucasodon/api/lixeira", "GET");
+    const itens = await res.json();
+    body.innerHTML = "";
+    if (!itens.length) {
+      body.innerHTML = `<tr><td colspan="6" class="dash-empty">A lixeira está vazia.</td></tr>`;
+      return;
+    }
+    itens.forEach(i => body.appendChild(lixeiraRow(i)));
+  } catch (e) { showLixeiraMsg("Erro ao carregar: " + e.message, false); }
+}
+
+function lixeiraRow(i) {
+  const tr = document.createElement("tr");
+  const dia = (secs) => new Date(secs * 1000).toLocaleDateString("pt-BR");
+  tr.innerHTML = `
+    <td>${esc(HISTORICO_TABELAS[i.tabela] || i.tabela)}</td>
+    <td>${esc(i.descricao)}</td>
+    <td>${fmtDate(i.data)}</td>
+    <td>${i.valor == null ? "" : brl(i.valor)}</td>
+    <td>${dia(i.excluido_em)} (apagado em ${dia(i.expira_em)})</td>`;
+  const acts = document.createElement("td");
+  acts.className = "actions";
+  const btn = document.createElement("button");
+  btn.className = "save"; btn.textContent = "Restaurar";
+  btn.onclick = async () => {
+    try {
+      await restaurarItem(i.tabela, i.id);
+      showLixeiraMsg("Item restaurado.", true);
+      await loadLixeira();
+    } catch (e) { showLixeiraMsg("Erro: " + e.message, false); }
+  };
+  acts.appendChild(btn);
+  tr.appendChild(acts);
+  return tr;
+}
+
+// ---- histórico ----
+const HISTORICO_TABELAS = {
+  plantoes: "Plantão", despesas: "Despesa", despesas_recorrentes: "Recorrente", categorias: "Categoria",
+};
+const HISTORICO_ACOES = {
+  criar: "Criado", alterar: "Alterado", excluir: "Excluído", restaurar: "Restaurado", purgar: "Apagado",
+};
+const HISTORICO_PAGINA = 50;
+let historico = [];
+
+function showHistMsg(text, ok) {
+  const el = document.getElementById("historico-msg");
+  el.textContent = text;
+  el.className = "msg " + (ok ? "ok" : "err");
+}
+
+// Values are logged as stored: money in centavos, booleans as 0/1.
+function fmtCampo(campo, v) {
+  if (v == null || v === "") return "—";
+  if (campo === "valor") return brlCents(v);
+  if (/^\d{4}-\d{2}-\d{2}$/.test(v)) return fmtDate(v);
+  return String(v);
+}
+
+function resumoAlteracao(e) {
+  const a = e.antes || {}, d = e.depois || {};
+  if (!e.antes || !e.depois) {
+    const r = e.depois || e.antes;
+    return [r.descricao, r.local, r.nome, r.data && fmtDate(r.data), r.valor != null && brlCents(r.valor)]
+      .filter(Boolean).join(" · ");
+  }
+  return Object.keys(d)
+    .filter(k => k !== "user_id" && JSON.stringify(a[k]) !== JSON.stringify(d[k]))
+    .map(k => `${k}: ${fmtCampo(k, a[k])} → ${fmtCampo(k, d[k])}`)
+    .join("; ");
+}
+
+async function openHistorico() {
+  historico = [];
+  showHistMsg("", true);
+  document.getElementById("historico-body").innerHTML = "";
+  document.getElementById("historico-modal").classList.add("open");
+  await loadHistorico();
+}
+
+async function loadHistorico() {
+  const qs = new URLSearchParams({ limite: HISTORICO_PAGINA });
+  const tabela = document.getElementById("hx-tabela").value;
+  if (tabela) qs.set("tabela", tabela);
+  if (historico.length) qs.set("antes_de", historico[historico.length - 1].id);
+  try {
+    const res = await api(`/lucasodon/api/auditoria?${qs.toString()}`, "GET");
+    const pagina = await res.json();
+    historico = historico.concat(pagina);
+    const body = document.getElementById("historico-body");
+    pagina.forEach(e => body.appendChild(historicoRow(e)));
+    if (!historico.length) body.innerHTML = `<tr><td colspan="5" class="dash-empty">Nenhuma alteração registrada.</td></tr>`;
+    document.getElementById("mais-historico-btn").style.display = pagina.length === HISTORICO_PAGINA ? "" : "none";
+  } catch (e) { showHistMsg("Erro ao carregar: " + e.message, false); }
+}
+
+function historicoRow(e) {
+  const tr = document.createElement("tr");
+  const quando = new Date(e.criado_em * 1000).toLocaleString("pt-BR");
+  tr.innerHTML = `
+    <td>${esc(quando)}</td>
+    <td>${esc(HISTORICO_TABELAS[e.tabela] || e.tabela)} #${e.registro_id}</td>
+    <td>${esc(HISTORICO_ACOES[e.acao] || e.acao)}</td>
+    <td>${esc(resumoAlteracao(e))}</td>`;
+  const acts = document.createElement("td");
+  acts.className = "actions";
+  if (e.antes) {
+    const undo = document.createElement("button");
+    undo.className = "edit"; undo.textContent = "Desfazer"; undo.onclick = () => desfazerAlteracao(e);
+    acts.appendChild(undo);
+  }
+  tr.appendChild(acts);
+  return tr;
+}
+
+async function desfazerAlteracao(e) {
+  if (!confirm("Voltar este registro para como estava antes desta alteração?")) return;
+  try {
+    await api("/lucasodon/api/auditoria/restaurar", "POST", { id: e.id });
+    await openHistorico();
+    showHistMsg("Registro restaurado.", true);
+    await reloadAll();
+  } catch (err) { showHistMsg("Erro: " + err.message, false); }
+}
+
+// ---- exportação ----
+function openExportar() {
+  const now = new Date();
+  if (!document.getElementById("ex-inicio").value) document.getElementById("ex-inicio").value = `${now.getFullYear()}-01-01`;
+  if (!document.getElementById("ex-fim").value) document.getElementById("ex-fim").value = `${now.getFullYear()}-12-31`;
+  document.getElementById("exportar-modal").classList.add("open");
+}
+
+function downloadExportar() {
+  const tabela = document.getElementById("ex-tabela").value;
+  const qs = new URLSearchParams({ formato: document.getElementById("ex-formato").value });
+  const inicio = document.getElementById("ex-inicio").value;
+  const fim = document.getElementById("ex-fim").value;
+  if (inicio) qs.set("inicio", inicio);
+  if (fim) qs.set("fim", fim);
+  window.location.href = `/lucasodon/api/exportar/${tabela}?${qs.toString()}`;
+  document.getElementById("exportar-modal").classList.remove("open");
+}
+
+// ---- conta ----
+function showContaMsg(text, ok) {
+  const el = document.getElementById("conta-msg");
+  el.textContent = text;
+  el.className = "msg " + (ok ? "ok" : "err");
+}
+
+async function openConta() {
+  showContaMsg("", true);
+  ["cx-atual", "cx-nova", "ux-nome", "ux-senha"].forEach(id => document.getElementById(id).value = "");
+  try {
+    const conta = await (await api("/lucasodon/api/conta", "GET")).json();
+    document.getElementById("conta-title").textContent = "Conta: " + conta.nome;
+    document.getElementById("usuarios-admin").style.display = conta.admin ? "" : "none";
+  } catch (e) { showContaMsg("Erro: " + e.message, false); }
+  document.getElementById("conta-modal").classList.add("open");
+}
+
+async function saveSenha() {
+  const atual = document.getElementById("cx-atual").value;
+  const nova = document.getElementById("cx-nova").value;
+  if (!nova.trim()) { showContaMsg("Informe a nova senha.", false); return; }
+  try {
+    await api("/lucasodon/api/conta/senha", "POST", { atual, nova });
+    document.getElementById("cx-atual").value = "";
+    document.getElementById("cx-nova").value = "";
+    showContaMsg("Senha alterada.", true);
+  } catch (e) { showContaMsg("Erro: " + e.message, false); }
+}
+
+async function saveUsuario() {
+  const nome = document.getElementById("ux-nome").value.trim();
+  const senha = document.getElementById("ux-senha").value;
+  if (!nome || !senha.trim()) { showContaMsg("Informe nome e senha.", false); return; }
+  try {
+    await api("/lucasodon/api/usuarios/create", "POST", { nome, senha });
+    document.getElementById("ux-nome").value = "";
+    document.getElementById("ux-senha").value = "";
+    showContaMsg("Usuário " + nome + " criado.", true);
+  } catch (e) { showContaMsg("Erro: " + e.message, false); }
+}
+
+// The body is the raw database file, so this bypasses api()'s JSON encoding.
+async function restaurarBackup() {
+  const file = document.getElementById("bx-arquivo").files[0];
+  if (!file) { showContaMsg("Escolha o arquivo do backup.", false); return; }
+  if (!confirm("Substituir todos os dados pelo backup? Uma cópia dos dados atuais é guardada antes.")) return;
+  try {
+    const res = await fetch("/lucasodon/api/backup/restaurar", {
+      method: "POST",
+      headers: { "X-CSRF-Token": CSRF_TOKEN, "Content-Type": "application/octet-stream" },
+      body: file,
+    });
+    if (!res.ok) throw new Error(await res.text());
+    window.location.href = "/lucasodon";
+  } catch (e) { showContaMsg("Erro: " + e.message, false); }
+}
+
+async function encerrarSessoes() {
+  if (!confirm("Encerrar todas as sessões, inclusive esta?")) return;
+  try {
+    await api("/lucasodon/api/conta/encerrar_sessoes", "POST");
+    window.location.href = "/lucasodon";
+  } catch (e) { showContaMsg("Erro: " + e.message, false); }
+}
+
 // ---- wiring ----
 function bindModalClose(modalId, ...closers) {
   const modal = document.getElementById(modalId);
@@ -769,3 +1228,35 @@ bindModalClose("recorrente-modal", "close-recorrente-btn", "cancel-recorrente-bt
 document.getElementById("open-categorias-btn").addEventListener("click", () => document.getElementById("categorias-modal").classList.add("open"));
 document.getElementById("cat-add-btn").addEventListener("click", addCategoria);
 bindModalClose("categorias-modal", "close-categorias-btn");
+
+document.getElementById("open-importar-btn").addEventListener("click", openImportar);
+document.getElementById("preview-importar-btn").addEventListener("click", previewImportar);
+document.getElementById("confirm-importar-btn").addEventListener("click", confirmImportar);
+bindModalClose("importar-modal", "close-importar-btn");
+
+document.getElementById("open-conta-btn").addEventListener("click", openConta);
+document.getElementById("save-senha-btn").addEventListener("click", saveSenha);
+document.getElementById("encerrar-sessoes-btn").addEventListener("click", encerrarSessoes);
+document.getElementById("save-usuario-btn").addEventListener("click", saveUsuario);
+document.getElementById("download-backup-btn").addEventListener("click", () => { window.location.href = "/lucasodon/api/backup"; });
+document.getElementById("restore-backup-btn").addEventListener("click", restaurarBackup);
+bindModalClose("conta-modal", "close-conta-btn");
+
+document.getElementById("open-lixeira-btn").addEventListener("click", openLixeira);
+bindModalClose("lixeira-modal", "close-lixeira-btn");
+
+document.getElementById("open-historico-btn").addEventListener("click", openHistorico);
+document.getElementById("mais-historico-btn").addEventListener("click", loadHistorico);
+document.getElementById("hx-tabela").addEventListener("change", openHistorico);
+bindModalClose("historico-modal", "close-historico-btn");
+
+document.getElementById("open-exportar-btn").addEventListener("click", openExportar);
+document.getElementById("download-exportar-btn").addEventListener("click", downloadExportar);
+bindModalClose("exportar-modal", "close-exportar-btn", "cancel-exportar-btn");
+
+document.getElementById("maintab-projecao").addEventListener("click", () => setMainTab("projecao"));
+["p-inicio", "p-meses", "p-saldo"].forEach(id =>
+  document.getElementById(id).addEventListener("change", loadProjecao));
+
+document.getElementById("maintab-carne").addEventListener("click", () => setMainTab("carne"));
+document.getElementById("c-ano").addEventListener("change", loadCarneLeao);
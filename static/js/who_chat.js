@@ -2,13 +2,199 @@
 set_get_chat();
 
 
+// End-to-end chats: format 1 derives 64 bytes with PBKDF2-SHA256 from the
+// password and the room salt. The first half is the AES-GCM key, which never
+// leaves the browser, the second half the token the server checks.
+const E2E_FORMAT = 1;
+const E2E_ITERATIONS = 600000;
+
+
+function to_base64(bytes) {
+    let binary = "";
+    for (const byte of bytes)
+        binary += String.fromCharCode(byte);
+    return btoa(binary);
+}
+
+
+function from_base64(text) {
+    return Uint8Array.from(atob(text), c => c.charCodeAt(0));
+}
+
+
+async function derive_e2e_keys(password, salt) {
+    const material = await crypto.subtle.importKey(
+        "raw", new TextEncoder().encode(password), "PBKDF2", false, ["deriveBits"]
+    );
+    const bits = new Uint8Array(await crypto.subtle.deriveBits(
+        { name: "PBKDF2", hash: "SHA-256", salt: from_base64(salt), iterations: E2E_ITERATIONS },
+        material,
+        512
+    ));
+    const key = await crypto.subtle.importKey("raw", bits.slice(0, 32), "AES-GCM", false, ["encrypt", "decrypt"]);
+    return { key: key, token: to_base64(bits.slice(32)) };
+}
+
+
+async function e2e_encrypt(key, text) {
+    const iv = crypto.getRandomValues(new Uint8Array(12));
+    const ciphertext = new Uint8Array(await crypto.subtle.encrypt(
+        { name: "AES-GCM", iv: iv }, key, new TextEncoder().encode(text)
+    ));
+    const segment = new Uint8Array(iv.length + ciphertext.length);
+    segment.set(iv);
+    segment.set(ciphertext, iv.length);
+    return to_base64(segment);
+}
+
+
+async function e2e_decrypt(key, segment) {
+    const bytes = from_base64(segment);
+    const plaintext = await crypto.subtle.decrypt(
+        { name: "AES-GCM", iv: bytes.slice(0, 12) }, key, bytes.slice(12)
+    );
+    return new TextDecoder().decode(plaintext);
+}
+
+
+async function e2e_request(path, body) {
+    return fetch(path, {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json; charset=UTF-8'
+        },
+        body: JSON.stringify(body)
+    });
+}
+
+
+// Public salt and format of an end-to-end chat, or null if it does not exist.
+async function e2e_room_info(name) {
+    const response = await e2e_request('/who_chat/e2e/salt', { "name": name });
+    if (response.status == 404)
+        return null;
+    if (response.status != 200)
+        throw new Error(await response.text());
+    const info = await response.json();
+    if (info.format != E2E_FORMAT)
+        throw new Error("Error: unsupported format");
+    return info;
+}
+
+
+async function e2e_credentials(name, password) {
+    const info = await e2e_room_info(name);
+    if (info == null)
+        throw new Error("Error: chat not found");
+    const keys = await derive_e2e_keys(password, info.salt);
+    return { "name": name, "token": keys.token, "key": keys.key };
+}
+
+
+function show_response(ok, ok_color, data) {
+    const responseDiv = document.getElementById("response");
+    responseDiv.style.backgroundColor = ok ? ok_color : "rgba(255, 0, 0, 0.4)";
+    const time = new Date();
+    responseDiv.innerText = `Response (${time.toLocaleTimeString()}):\n${data}`;
+}
+
+
+async function get_e2e_chat(name, password) {
+    try {
+        const credentials = await e2e_credentials(name, password);
+        const response = await e2e_request('/who_chat/e2e/get', { "name": name, "token": credentials.token });
+        if (response.status != 200)
+            return show_response(false, "", await response.text());
+
+        const room = await response.json();
+        let contents = "";
+        for (const segment of room.segments)
+            contents += await e2e_decrypt(credentials.key, segment);
+        show_response(true, "rgba(0, 0, 0, 0.5)", contents);
+    } catch (err) {
+        show_response(false, "", err.message);
+    }
+}
+
+
+// Like server-side chats, posting to a missing chat creates it.
+async function post_e2e_chat(name, password, content) {
+    try {
+        let info = await e2e_room_info(name);
+        if (info == null) {
+            info = { "format": E2E_FORMAT, "salt": to_base64(crypto.getRandomValues(new Uint8Array(16))) };
+            const keys = await derive_e2e_keys(password, info.salt);
+            const response = await e2e_request('/who_chat/e2e/create', {
+                "name": name, "token": keys.token, "format": info.format, "salt": info.salt
+            });
+            if (response.status != 200)
+                return show_response(false, "", await response.text());
+        }
+
+        const keys = await derive_e2e_keys(password, info.salt);
+        const response = await e2e_request('/who_chat/e2e/post', {
+            "name": name,
+            "token": keys.token,
+            "format": info.format,
+            "segment": await e2e_encrypt(keys.key, content)
+        });
+        show_response(response.status == 200, "rgba(0, 255, 0, 0.4)", await response.text());
+    } catch (err) {
+        show_response(false, "", err.message);
+    }
+}
+
+
+async function delete_e2e_chat(name, password) {
+    try {
+        const credentials = await e2e_credentials(name, password);
+        const response = await e2e_request('/who_chat/e2e/delete', { "name": name, "token": credentials.token });
+        show_response(response.status == 200, "rgba(0, 255, 0, 0.5)", await response.text());
+    } catch (err) {
+        show_response(false, "", err.message);
+    }
+}
+
+
+// Open live stream of the chat being followed, if any.
+var follow_controller = null;
+
+
+function stop_following() {
+    if (follow_controller) {
+        follow_controller.abort();
+        follow_controller = null;
+    }
+}
+
+
 async function get_chat() {
     const name = document.getElementById("name").value;
     const password = document.getElementById("password").value;
+    const follow = document.getElementById("follow").checked;
+    const e2e = document.getElementById("e2e").checked;
 
+    stop_following();
+    if (e2e) {
+        await get_e2e_chat(name, password);
+        if (follow)
+            e2e_credentials(name, password)
+                .then(c => follow_chat('/who_chat/e2e/stream', { "name": name, "token": c.token }, () => get_e2e_chat(name, password)))
+                .catch(() => {});
+        return;
+    }
+
+    await fetch_chat(name, password);
+    if (follow)
+        follow_chat('/who_chat/stream', { "name": name, "password": password }, () => fetch_chat(name, password));
+}
+
+
+async function fetch_chat(name, password) {
     const responseDiv = document.getElementById("response");
 
-    fetch('/who_chat/get', {
+    return fetch('/who_chat/get', {
         method: 'POST',
         headers: {
             'Accept': 'application/json',
@@ -26,15 +212,138 @@ async function get_chat() {
     .then(data => {
         const time = new Date();
         responseDiv.innerText = `Response (${time.toLocaleTimeString()}):\n${data}`;
+        add_attachment_links(responseDiv, data, name, password);
     });
 }
 
 
+// Adds a download button for every attachment referenced in the chat.
+function add_attachment_links(responseDiv, data, name, password) {
+    for (const match of data.matchAll(/\[attachment ([A-Za-z0-9]+): ([^\]]*), \d+ bytes\]/g)) {
+        const button = document.createElement("div");
+        button.className = "button";
+        button.innerText = `Download ${match[2]}`;
+        button.onclick = () => download_attachment(name, password, match[1], match[2]);
+        responseDiv.appendChild(button);
+    }
+}
+
+
+async function download_attachment(name, password, id, filename) {
+    const response = await fetch('/who_chat/attachment', {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json; charset=UTF-8'
+        },
+        body: JSON.stringify({ "name": name, "password": password, "id": id })
+    });
+    if (response.status != 200)
+        return show_response(false, "", await response.text());
+
+    const url = URL.createObjectURL(await response.blob());
+    const link = document.createElement("a");
+    link.href = url;
+    link.download = filename;
+    link.click();
+    URL.revokeObjectURL(url);
+}
+
+
+async function attach_chat(name, password, file) {
+    const data = await new Promise((resolve, reject) => {
+        const reader = new FileReader();
+        reader.onload = () => resolve(reader.result.slice(reader.result.indexOf(",") + 1));
+        reader.onerror = () => reject(reader.error);
+        reader.readAsDataURL(file);
+    });
+
+    const response = await fetch('/who_chat/attach', {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json; charset=UTF-8'
+        },
+        body: JSON.stringify({
+            "name": name,
+            "password": password,
+            "filename": file.name,
+            "content_type": file.type,
+            "data": data
+        })
+    });
+    show_response(response.status == 200, "rgba(0, 255, 0, 0.4)", await response.text());
+}
+
+
+// Reads the server-sent events of a chat and calls refresh on every update.
+async function follow_chat(path, body, refresh) {
+    const controller = new AbortController();
+    follow_controller = controller;
+
+    let response;
+    try {
+        response = await fetch(path, {
+            method: 'POST',
+            headers: {
+                'Accept': 'text/event-stream',
+                'Content-Type': 'application/json; charset=UTF-8'
+            },
+            body: JSON.stringify(body),
+            signal: controller.signal
+        });
+    } catch (err) {
+        return;
+    }
+    if (response.status != 200)
+        return;
+
+    const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+    let buffer = "";
+    try {
+        while (true) {
+            const { value, done } = await reader.read();
+            if (done)
+                break;
+            buffer += value;
+
+            let end;
+            while ((end = buffer.indexOf("\n\n")) >= 0) {
+                const block = buffer.slice(0, end);
+                buffer = buffer.slice(end + 2);
+
+                const event = block.split("\n")
+                    .find(line => line.startsWith("event: "));
+                if (!event)
+                    continue;
+                const type = event.slice("event: ".length);
+
+                if (type == "update" || type == "resync")
+                    await refresh();
+                else if (type == "rotated" || type == "deleted") {
+                    const responseDiv = document.getElementById("response");
+                    responseDiv.innerText += `\n\n(Chat ${type}, no longer following.)`;
+                }
+            }
+        }
+    } catch (err) {
+        // aborted by stop_following
+    }
+    if (follow_controller == controller)
+        follow_controller = null;
+}
+
+
 async function post_chat() {
     const name = document.getElementById("name").value;
     const password = document.getElementById("password").value;
     const content = document.getElementById("content").value;
 
+    if (document.getElementById("e2e").checked)
+        return post_e2e_chat(name, password, content);
+
+    const file = document.getElementById("attachment").files[0];
+    if (file)
+        return attach_chat(name, password, file);
+
     const responseDiv = document.getElementById("response"); 
 
     fetch('/who_chat/post', {
@@ -59,10 +368,91 @@ async function post_chat() {
 }
 
 
+async function create_chat() {
+    const name = document.getElementById("name").value;
+    const read_password = document.getElementById("read-password").value;
+    const post_password = document.getElementById("post-password").value;
+    const owner_password = document.getElementById("owner-password").value;
+    const message_ttl_hours = parseInt(document.getElementById("message-ttl").value) || null;
+    const inactivity_hours = parseInt(document.getElementById("inactivity").value) || null;
+    const read_once = document.getElementById("read-once").checked;
+
+    const responseDiv = document.getElementById("response");
+
+    fetch('/who_chat/create', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json; charset=UTF-8'
+        },
+        body: JSON.stringify({
+            "name": name,
+            "read_password": read_password,
+            "post_password": post_password,
+            "owner_password": owner_password,
+            "message_ttl_hours": message_ttl_hours,
+            "inactivity_hours": inactivity_hours,
+            "read_once": read_once
+        })
+    })
+    .then((response) => {
+        if (response.status == 200)
+            responseDiv.style.backgroundColor = "rgba(0, 255, 0, 0.4)";
+        else
+            responseDiv.style.backgroundColor = "rgba(255, 0, 0, 0.4)";
+        return response.text();
+    })
+    .then(data => {
+        const time = new Date();
+        responseDiv.innerText = `Response (${time.toLocaleTimeString()}):\n${data}`;
+    });
+}
+
+
+async function rotate_chat() {
+    const name = document.getElementById("name").value;
+    const password = document.getElementById("password").value;
+    const new_password = document.getElementById("new-password").value;
+    const new_read_password = document.getElementById("new-read-password").value;
+    const new_post_password = document.getElementById("new-post-password").value;
+
+    const responseDiv = document.getElementById("response");
+
+    fetch('/who_chat/rotate', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json; charset=UTF-8'
+        },
+        body: JSON.stringify({
+            "name": name,
+            "password": password,
+            "new_password": new_password,
+            "new_read_password": new_read_password,
+            "new_post_password": new_post_password
+        })
+    })
+    .then((response) => {
+        if (response.status == 200)
+            responseDiv.style.backgroundColor = "rgba(0, 255, 0, 0.4)";
+        else
+            responseDiv.style.backgroundColor = "rgba(255, 0, 0, 0.4)";
+        return response.text();
+    })
+    .then(data => {
+        const time = new Date();
+        responseDiv.innerText = `Response (${time.toLocaleTimeString()}):\n${data}`;
+    });
+}
+
+
 async function delete_chat() {
     const name = document.getElementById("name").value;
     const password = document.getElementById("password").value;
 
+    if (document.getElementById("e2e").checked)
+        return delete_e2e_chat(name, password);
+
     const responseDiv = document.getElementById("response");
 
     fetch('/who_chat/delete', {
@@ -87,6 +477,64 @@ async function delete_chat() {
 }
 
 
+// Export saves the archive as a file; import sends a saved one under the
+// name typed in the form.
+async function archive_chat(action) {
+    const name = document.getElementById("name").value;
+    const password = document.getElementById("password").value;
+    const e2e = document.getElementById("e2e").checked;
+
+    try {
+        if (action == "Export")
+            return await export_chat(name, password, e2e);
+
+        const file = document.getElementById("archive").files[0];
+        if (!file)
+            return show_response(false, "", "Error: choose an archive to import");
+        const archive = JSON.parse(await file.text());
+
+        let secret = password;
+        if (archive.room && archive.room.kind == "browser")
+            secret = (await derive_e2e_keys(password, archive.room.room.salt)).token;
+
+        const response = await e2e_request('/who_chat/import', { "name": name, "password": secret, "archive": archive });
+        show_response(response.status == 200, "rgba(0, 255, 0, 0.4)", await response.text());
+    } catch (err) {
+        show_response(false, "", err.message);
+    }
+}
+
+
+async function export_chat(name, password, e2e) {
+    const response = e2e
+        ? await e2e_request('/who_chat/e2e/export', { "name": name, "token": (await e2e_credentials(name, password)).token })
+        : await e2e_request('/who_chat/export', { "name": name, "password": password });
+    if (response.status != 200)
+        return show_response(false, "", await response.text());
+
+    const url = URL.createObjectURL(await response.blob());
+    const link = document.createElement("a");
+    link.href = url;
+    link.download = `${name}.who_chat.json`;
+    link.click();
+    URL.revokeObjectURL(url);
+    show_response(true, "rgba(0, 255, 0, 0.4)", "Exported!");
+}
+
+
+// Highlights the selected mode, delete keeps its crimson warning color, and
+// stops following a chat when leaving the get mode.
+function highlight_switch(active) {
+    stop_following();
+    for (const id of ["get-switch", "post-switch", "create-switch", "rotate-switch", "archive-switch", "delete-switch"]) {
+        const color = id == "delete-switch" ? "crimson" : "#c71c63";
+        document.getElementById(id).style = id == active
+            ? `color: black; background-color: ${color}`
+            : `color: ${color}; background-color: #36384c`;
+    }
+}
+
+
 function set_get_chat() {
     const forms_wrapper = document.getElementById("forms-wrapper");
     forms_wrapper.innerHTML = `
@@ -99,12 +547,18 @@ function set_get_chat() {
                 <label for="password">Password:&nbsp&nbsp</label>
                 <input type="password" id="password" name="password" required>
             </div>
+            <div class=\"item\">
+                <label for="e2e">Encrypt in browser:&nbsp</label>
+                <input type="checkbox" id="e2e" name="e2e">
+            </div>
+            <div class=\"item\">
+                <label for="follow">Follow:&nbsp</label>
+                <input type="checkbox" id="follow" name="follow">
+            </div>
             <input class="button" type="submit" value="Get Chat">
         </form>
     `;
-	document.getElementById("get-switch").style = "color: black; background-color: #c71c63";
-	document.getElementById("post-switch").style = "color: #c71c63; background-color: #36384c";
-	document.getElementById("delete-switch").style = "color: crimson; background-color: #36384c";
+	highlight_switch("get-switch");
 }
 
 
@@ -124,12 +578,116 @@ function set_post_chat() {
                 <label for="content">Content:&nbsp</label>
                 <textarea rows="4" cols="40" form="data-form" id="content">Type content here</textarea required>
             </div>
+            <div class=\"item\">
+                <label for="attachment">Or attach a file:&nbsp</label>
+                <input type="file" id="attachment" name="attachment" accept="image/*,application/pdf,text/plain">
+            </div>
+            <div class=\"item\">
+                <label for="e2e">Encrypt in browser:&nbsp</label>
+                <input type="checkbox" id="e2e" name="e2e">
+            </div>
             <input class="button" type="submit" value="Post">
         </form>
     `;
-	document.getElementById("get-switch").style = "color: #c71c63; background-color: #36384c";
-	document.getElementById("post-switch").style = "color: black; background-color: #c71c63";
-	document.getElementById("delete-switch").style = "color: crimson; background-color: #36384c";
+	highlight_switch("post-switch");
+}
+
+
+function set_create_chat() {
+    const forms_wrapper = document.getElementById("forms-wrapper");
+    forms_wrapper.innerHTML = `
+        <form id="data-form" onsubmit="event.preventDefault(); return create_chat()">
+            <div class=\"item\">
+                <label for="name">Chat name:&nbsp;</label>
+                <input type="text" id="name" name="name" required>
+            </div>
+            <div class=\"item\">
+                <label for="read-password">Read key:&nbsp&nbsp</label>
+                <input type="password" id="read-password" name="read-password" required>
+            </div>
+            <div class=\"item\">
+                <label for="post-password">Post key:&nbsp&nbsp</label>
+                <input type="password" id="post-password" name="post-password" required>
+            </div>
+            <div class=\"item\">
+                <label for="owner-password">Owner key:&nbsp</label>
+                <input type="password" id="owner-password" name="owner-password" required>
+            </div>
+            <div class=\"item\">
+                <label for="message-ttl">Messages expire after (hours):&nbsp</label>
+                <input type="number" min="1" id="message-ttl" name="message-ttl" placeholder="never">
+            </div>
+            <div class=\"item\">
+                <label for="inactivity">Delete after idle (hours):&nbsp</label>
+                <input type="number" min="1" id="inactivity" name="inactivity" placeholder="never">
+            </div>
+            <div class=\"item\">
+                <label for="read-once">Burn after reading:&nbsp</label>
+                <input type="checkbox" id="read-once" name="read-once">
+            </div>
+            <input class="button" type="submit" value="Create Chat">
+        </form>
+    `;
+	highlight_switch("create-switch");
+}
+
+
+function set_rotate_chat() {
+    const forms_wrapper = document.getElementById("forms-wrapper");
+    forms_wrapper.innerHTML = `
+        <form id="data-form" onsubmit="event.preventDefault(); return rotate_chat()">
+            <div class=\"item\">
+                <label for="name">Chat name:&nbsp;</label>
+                <input type="text" id="name" name="name" required>
+            </div>
+            <div class=\"item\">
+                <label for="password">Owner key:&nbsp</label>
+                <input type="password" id="password" name="password" required>
+            </div>
+            <div class=\"item\">
+                <label for="new-password">New owner key:&nbsp</label>
+                <input type="password" id="new-password" name="new-password" required>
+            </div>
+            <div class=\"item\">
+                <label for="new-read-password">New read key:&nbsp&nbsp</label>
+                <input type="password" id="new-read-password" name="new-read-password" placeholder="same as owner">
+            </div>
+            <div class=\"item\">
+                <label for="new-post-password">New post key:&nbsp&nbsp</label>
+                <input type="password" id="new-post-password" name="new-post-password" placeholder="same as owner">
+            </div>
+            <input class="button" type="submit" value="Rotate Keys">
+        </form>
+    `;
+	highlight_switch("rotate-switch");
+}
+
+
+function set_archive_chat() {
+    const forms_wrapper = document.getElementById("forms-wrapper");
+    forms_wrapper.innerHTML = `
+        <form id="data-form" onsubmit="event.preventDefault(); return archive_chat(event.submitter.value)">
+            <div class=\"item\">
+                <label for="name">Chat name:&nbsp;</label>
+                <input type="text" id="name" name="name" required>
+            </div>
+            <div class=\"item\">
+                <label for="password">Owner key:&nbsp</label>
+                <input type="password" id="password" name="password" required>
+            </div>
+            <div class=\"item\">
+                <label for="e2e">Encrypt in browser:&nbsp</label>
+                <input type="checkbox" id="e2e" name="e2e">
+            </div>
+            <div class=\"item\">
+                <label for="archive">Archive to import:&nbsp</label>
+                <input type="file" id="archive" name="archive" accept=".json,application/json">
+            </div>
+            <input class="button" type="submit" value="Export">
+            <input class="button" type="submit" value="Import">
+        </form>
+    `;
+	highlight_switch("archive-switch");
 }
 
 
@@ -145,10 +703,12 @@ function set_delete_chat() {
                 <label for="password">Password:&nbsp&nbsp</label>
                 <input type="password" id="password" name="password" required>
             </div>
+            <div class=\"item\">
+                <label for="e2e">Encrypt in browser:&nbsp</label>
+                <input type="checkbox" id="e2e" name="e2e">
+            </div>
             <input class="button" type="submit" value="Delete Chat">
         </form>
     `;
-	document.getElementById("get-switch").style = "color: #c71c63; background-color: #36384c";
-	document.getElementById("post-switch").style = "color: #c71c63; background-color: #36384c";
-	document.getElementById("delete-switch").style = "color: black; background-color: crimson";
+	highlight_switch("delete-switch");
 }